@@ -0,0 +1,448 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use axum::{
+    extract::{Path, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Json, Response},
+    routing::{delete, get, patch, post},
+    Router,
+};
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Deserializer, Serialize};
+use serde_json::json;
+use thiserror::Error;
+use tracing::{error, info};
+
+use crate::approval::{Application, ApprovalError, ApprovalQueue, Claim};
+use crate::storage::StorageError;
+use crate::tokens::InviteToken;
+use crate::{account_outcome, AppState, Outcome, RegistrationState, Signup};
+
+const GENERATED_TOKEN_LEN: usize = 24;
+
+/// Routes under `/admin`, all guarded by the `ADMIN_SECRET` bearer token.
+pub fn router(state: AppState) -> Router<AppState> {
+    let mut router = Router::new()
+        .route("/admin/attempts", get(list_attempts).delete(clear_attempts))
+        .route("/admin/attempts/:ip", delete(clear_attempt));
+    // Synapse's tokens are managed with its own admin API; ours would never
+    // be consulted.
+    if state.synapse_tokens.is_none() {
+        router = router
+            .route("/admin/tokens", get(list_tokens).post(create_token))
+            .route(
+                "/admin/tokens/:token",
+                patch(update_token).get(get_token).delete(revoke_token),
+            );
+    }
+    if state.approvals.is_some() {
+        router = router
+            .route("/admin/approvals", get(list_applications))
+            .route("/admin/approvals/:id", get(get_application))
+            .route("/admin/approvals/:id/approve", post(approve_application))
+            .route("/admin/approvals/:id/reject", post(reject_application));
+    }
+    router.route_layer(middleware::from_fn_with_state(state, require_admin))
+}
+
+async fn require_admin(
+    State(state): State<AppState>,
+    headers: HeaderMap,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(secret) = state.config.admin_secret.as_deref() else {
+        return AdminError::Unauthorized.into_response();
+    };
+    let presented = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "));
+
+    match presented {
+        Some(presented) if constant_time_eq(presented.as_bytes(), secret.as_bytes()) => {
+            next.run(request).await
+        }
+        _ => AdminError::Unauthorized.into_response(),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+#[derive(Debug, Error)]
+enum AdminError {
+    #[error("missing or wrong admin bearer token")]
+    Unauthorized,
+    #[error("not found")]
+    NotFound,
+    #[error("{0}")]
+    Conflict(&'static str),
+    #[error("{0}")]
+    BadRequest(&'static str),
+    #[error("storage error: {0}")]
+    Storage(#[from] StorageError),
+    #[error("{0}")]
+    Approval(#[from] ApprovalError),
+    #[error("the homeserver failed to create the account; the application is still pending")]
+    Upstream,
+}
+
+impl IntoResponse for AdminError {
+    fn into_response(self) -> Response {
+        let status = match self {
+            AdminError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AdminError::NotFound => StatusCode::NOT_FOUND,
+            AdminError::Conflict(_) => StatusCode::CONFLICT,
+            AdminError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AdminError::Storage(ref err) => {
+                error!("admin request failed: {err}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            AdminError::Approval(ref err) => {
+                error!("admin request failed: {err}");
+                StatusCode::INTERNAL_SERVER_ERROR
+            }
+            AdminError::Upstream => StatusCode::BAD_GATEWAY,
+        };
+        (status, Json(json!({ "error": self.to_string() }))).into_response()
+    }
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct TokenView {
+    token: String,
+    max_uses: Option<u32>,
+    uses: u32,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+impl TokenView {
+    fn new(token: String, invite: InviteToken) -> Self {
+        Self {
+            token,
+            max_uses: invite.max_uses,
+            uses: invite.uses,
+            expires_at: invite.expires_at,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CreateTokenRequest {
+    /// Generated when omitted.
+    token: Option<String>,
+    max_uses: Option<u32>,
+    expires_at: Option<DateTime<Utc>>,
+}
+
+/// Fields left out of the body are kept; `null` clears a limit.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct UpdateTokenRequest {
+    #[serde(default, deserialize_with = "present")]
+    max_uses: Option<Option<u32>>,
+    #[serde(default, deserialize_with = "present")]
+    expires_at: Option<Option<DateTime<Utc>>>,
+    uses: Option<u32>,
+}
+
+fn present<'de, T, D>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    T: Deserialize<'de>,
+    D: Deserializer<'de>,
+{
+    Option::<T>::deserialize(deserializer).map(Some)
+}
+
+async fn list_tokens(State(state): State<AppState>) -> Json<Vec<TokenView>> {
+    let mut tokens: Vec<_> = state
+        .tokens
+        .list()
+        .into_iter()
+        .map(|(token, invite)| TokenView::new(token, invite))
+        .collect();
+    tokens.sort_by(|a, b| a.token.cmp(&b.token));
+    Json(tokens)
+}
+
+async fn get_token(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<Json<TokenView>, AdminError> {
+    let invite = state.tokens.get(&token).ok_or(AdminError::NotFound)?;
+    Ok(Json(TokenView::new(token, invite)))
+}
+
+async fn create_token(
+    State(state): State<AppState>,
+    Json(request): Json<CreateTokenRequest>,
+) -> Result<(StatusCode, Json<TokenView>), AdminError> {
+    let token = match request.token {
+        Some(token) if token.is_empty() || !token.chars().all(|c| c.is_ascii_alphanumeric()) => {
+            return Err(AdminError::BadRequest(
+                "token must be non-empty and alphanumeric",
+            ));
+        }
+        Some(token) => token,
+        None => rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(GENERATED_TOKEN_LEN)
+            .map(char::from)
+            .collect(),
+    };
+    let invite = InviteToken {
+        max_uses: request.max_uses,
+        uses: 0,
+        expires_at: request.expires_at,
+    };
+
+    if !state.tokens.insert(token.clone(), invite.clone()) {
+        return Err(AdminError::Conflict("token already exists"));
+    }
+    if let Err(err) = state.storage.save_token(&token, &invite) {
+        state.tokens.remove(&token);
+        return Err(err.into());
+    }
+
+    info!("admin created invite token");
+    Ok((StatusCode::CREATED, Json(TokenView::new(token, invite))))
+}
+
+async fn update_token(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+    Json(request): Json<UpdateTokenRequest>,
+) -> Result<Json<TokenView>, AdminError> {
+    let invite = state
+        .tokens
+        .update(&token, |invite| {
+            if let Some(max_uses) = request.max_uses {
+                invite.max_uses = max_uses;
+            }
+            if let Some(expires_at) = request.expires_at {
+                invite.expires_at = expires_at;
+            }
+            if let Some(uses) = request.uses {
+                invite.uses = uses;
+            }
+        })
+        .ok_or(AdminError::NotFound)?;
+    state.storage.save_token(&token, &invite)?;
+
+    Ok(Json(TokenView::new(token, invite)))
+}
+
+async fn revoke_token(
+    State(state): State<AppState>,
+    Path(token): Path<String>,
+) -> Result<StatusCode, AdminError> {
+    state.tokens.remove(&token).ok_or(AdminError::NotFound)?;
+    state.storage.revoke_token(&token)?;
+
+    info!("admin revoked invite token");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AttemptView {
+    ip: IpAddr,
+    /// Attempts still inside the rate limit window.
+    count: usize,
+    last_attempt: Option<DateTime<Utc>>,
+    /// Seconds until the IP may register again; `0` when it isn't blocked.
+    blocked_for_secs: i64,
+}
+
+async fn list_attempts(State(state): State<AppState>) -> Json<Vec<AttemptView>> {
+    let policy = &state.config.rate_limit;
+    let now = Utc::now();
+    let mut attempts: Vec<_> = state
+        .attempts
+        .iter()
+        .map(|entry| AttemptView {
+            ip: *entry.key(),
+            count: entry.count(policy, now),
+            last_attempt: entry.last(),
+            blocked_for_secs: entry
+                .blocked_for(policy, now)
+                .map_or(0, |d| d.num_seconds()),
+        })
+        .collect();
+    attempts.sort_by_key(|attempt| std::cmp::Reverse(attempt.last_attempt));
+    Json(attempts)
+}
+
+async fn clear_attempt(
+    State(state): State<AppState>,
+    Path(ip): Path<IpAddr>,
+) -> Result<StatusCode, AdminError> {
+    state.attempts.remove(&ip).ok_or(AdminError::NotFound)?;
+    state.storage.delete_attempt(ip)?;
+
+    info!("admin cleared attempts for {ip}");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn clear_attempts(State(state): State<AppState>) -> Result<StatusCode, AdminError> {
+    state.attempts.clear();
+    state.storage.clear_attempts()?;
+
+    info!("admin cleared all attempts");
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct ApplicationView {
+    id: String,
+    username: String,
+    display_name: Option<String>,
+    email: Option<String>,
+    client_ip: IpAddr,
+    token_id: String,
+    submitted_at: DateTime<Utc>,
+    state: RegistrationState,
+    decided_at: Option<DateTime<Utc>>,
+}
+
+impl ApplicationView {
+    fn new(state: &AppState, application: Application) -> Self {
+        Self {
+            token_id: state.token_id(&application.token),
+            id: application.id,
+            username: application.username,
+            display_name: application.display_name,
+            email: application.email,
+            client_ip: application.client_ip,
+            submitted_at: application.submitted_at,
+            state: application.state,
+            decided_at: application.decided_at,
+        }
+    }
+}
+
+/// The routes are only mounted with approval on, so this never fails there.
+fn approvals(state: &AppState) -> Result<&Arc<ApprovalQueue>, AdminError> {
+    state.approvals.as_ref().ok_or(AdminError::NotFound)
+}
+
+/// Claims an undecided application for the admin acting on it.
+fn claim(approvals: &Arc<ApprovalQueue>, id: &str) -> Result<Claim, AdminError> {
+    approvals.get(id).ok_or(AdminError::NotFound)?;
+    approvals.claim(id).ok_or(AdminError::Conflict(
+        "application already decided or being decided",
+    ))
+}
+
+/// Undecided applications, oldest first.
+async fn list_applications(
+    State(state): State<AppState>,
+) -> Result<Json<Vec<ApplicationView>>, AdminError> {
+    Ok(Json(
+        approvals(&state)?
+            .pending()
+            .into_iter()
+            .map(|application| ApplicationView::new(&state, application))
+            .collect(),
+    ))
+}
+
+async fn get_application(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ApplicationView>, AdminError> {
+    let application = approvals(&state)?.get(&id).ok_or(AdminError::NotFound)?;
+    Ok(Json(ApplicationView::new(&state, application)))
+}
+
+/// Creates the account. If the homeserver refuses it for good, the
+/// application is decided with the reason and the token use given back; if
+/// it merely fails, the application stays pending to be approved again.
+async fn approve_application(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ApplicationView>, AdminError> {
+    let claim = claim(approvals(&state)?, &id)?;
+    // Carried out in its own task: once the homeserver is asked, an admin
+    // dropping the request mustn't leave the account created but the
+    // application undecided.
+    tokio::spawn(approve(state, claim))
+        .await
+        .expect("approval task panicked")
+}
+
+async fn approve(state: AppState, claim: Claim) -> Result<Json<ApplicationView>, AdminError> {
+    let approvals = approvals(&state)?;
+    let application = &claim.application;
+    let id = application.id.clone();
+    let sealed = application
+        .password
+        .as_deref()
+        .ok_or(ApprovalError::NoPassword)?;
+    let signup = Signup {
+        username: application.username.clone(),
+        password: approvals.open(&id, sealed)?,
+        token: application.token.clone(),
+        client_ip: application.client_ip,
+        display_name: application.display_name.clone(),
+    };
+
+    let started = Instant::now();
+    let result = state
+        .register_user(&signup.username, &signup.password)
+        .await;
+    let upstream = started.elapsed();
+    let decision = account_outcome(result, &signup.username);
+    match decision {
+        RegistrationState::Registered => {}
+        // Dropping the claim leaves the application pending.
+        RegistrationState::InternalError => return Err(AdminError::Upstream),
+        _ => state.refund_token(&signup.token).await,
+    }
+
+    let application = claim.decide(decision).ok_or(AdminError::NotFound)?;
+    state.persist_application(&application);
+    if decision == RegistrationState::Registered {
+        let email = application
+            .email
+            .as_deref()
+            .and_then(|email| email.parse().ok());
+        state.finish_account(&signup, email.as_ref()).await;
+    }
+    let outcome = Outcome {
+        upstream: Some(upstream),
+        ..Outcome::new(decision, &signup.username)
+    };
+    state.audit(&outcome, signup.client_ip, None, &signup.token);
+
+    info!("admin approved application {id}: {decision:?}");
+    Ok(Json(ApplicationView::new(&state, application)))
+}
+
+async fn reject_application(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<Json<ApplicationView>, AdminError> {
+    let claim = claim(approvals(&state)?, &id)?;
+    // Decided first, so a request dropped during the refund can't leave it
+    // pending to be refunded again.
+    let application = claim
+        .decide(RegistrationState::Rejected)
+        .ok_or(AdminError::NotFound)?;
+    state.persist_application(&application);
+    state.refund_token(&application.token).await;
+    let outcome = Outcome::new(RegistrationState::Rejected, &application.username);
+    state.audit(&outcome, application.client_ip, None, &application.token);
+
+    info!("admin rejected application {id}");
+    Ok(Json(ApplicationView::new(&state, application)))
+}
@@ -0,0 +1,211 @@
+//! Signups held back until an admin approves them.
+
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use chrono::{DateTime, Utc};
+use dashmap::{DashMap, DashSet};
+use lettre::Address;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use rand::{distributions::Alphanumeric, Rng};
+use thiserror::Error;
+
+use crate::{RegistrationState, Signup};
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+#[derive(Clone)]
+pub struct ApprovalConfig {
+    /// AES-256-GCM key for the passwords of queued signups.
+    pub key: [u8; 32],
+}
+
+#[derive(Debug, Error)]
+pub enum ApprovalError {
+    #[error("password encryption failed: {0}")]
+    Crypto(#[from] openssl::error::ErrorStack),
+    #[error("encrypted password is truncated")]
+    Truncated,
+    #[error("decrypted password is not UTF-8")]
+    NotUtf8,
+    #[error("pending application has no password")]
+    NoPassword,
+}
+
+/// A signup in the approval queue, as persisted.
+#[derive(Clone, Debug)]
+pub struct Application {
+    /// Random; also what the applicant polls the status with.
+    pub id: String,
+    pub username: String,
+    /// Nonce, ciphertext and tag of the password. Dropped once the
+    /// application is decided.
+    pub password: Option<Vec<u8>>,
+    pub token: String,
+    pub client_ip: IpAddr,
+    pub display_name: Option<String>,
+    pub email: Option<String>,
+    pub submitted_at: DateTime<Utc>,
+    /// `PENDING_APPROVAL` until an admin decides, then `REGISTERED`,
+    /// `REJECTED` or why the homeserver refused the account.
+    pub state: RegistrationState,
+    pub decided_at: Option<DateTime<Utc>>,
+}
+
+impl Application {
+    pub fn is_pending(&self) -> bool {
+        self.state == RegistrationState::PendingApproval
+    }
+}
+
+/// Every application seen since the store was created, decided ones
+/// included so their status can still be polled.
+///
+/// Unlike [`crate::email::EmailVerification`] this outlives restarts, since
+/// an admin may take days to get to a signup; the password is encrypted
+/// with the configured key for as long as it is kept.
+pub struct ApprovalQueue {
+    key: [u8; 32],
+    applications: DashMap<String, Application>,
+    /// Ids an admin is acting on right now.
+    claimed: DashSet<String>,
+}
+
+/// An undecided application held by one admin; others get `None` from
+/// [`ApprovalQueue::claim`] until it is decided or dropped. Dropping it
+/// without deciding, a cancelled request included, leaves the application
+/// pending as it was.
+pub struct Claim {
+    queue: Arc<ApprovalQueue>,
+    pub application: Application,
+}
+
+impl Claim {
+    /// Records the outcome, drops the password and returns the application.
+    pub fn decide(self, state: RegistrationState) -> Option<Application> {
+        let mut entry = self.queue.applications.get_mut(&self.application.id)?;
+        entry.state = state;
+        entry.password = None;
+        entry.decided_at = Some(Utc::now());
+        Some(entry.clone())
+    }
+}
+
+impl Drop for Claim {
+    fn drop(&mut self) {
+        self.queue.claimed.remove(&self.application.id);
+    }
+}
+
+impl ApprovalQueue {
+    pub fn new(config: &ApprovalConfig, applications: Vec<Application>) -> Self {
+        Self {
+            key: config.key,
+            applications: applications
+                .into_iter()
+                .map(|application| (application.id.clone(), application))
+                .collect(),
+            claimed: DashSet::new(),
+        }
+    }
+
+    /// Queues `signup` and returns the stored application.
+    pub fn submit(
+        &self,
+        signup: &Signup,
+        email: Option<&Address>,
+    ) -> Result<Application, ApprovalError> {
+        let id: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        let application = Application {
+            password: Some(self.seal(&id, &signup.password)?),
+            id,
+            username: signup.username.clone(),
+            token: signup.token.clone(),
+            client_ip: signup.client_ip,
+            display_name: signup.display_name.clone(),
+            email: email.map(Address::to_string),
+            submitted_at: Utc::now(),
+            state: RegistrationState::PendingApproval,
+            decided_at: None,
+        };
+        self.applications
+            .insert(application.id.clone(), application.clone());
+        Ok(application)
+    }
+
+    /// Whether an undecided application asks for `username`.
+    pub fn is_pending(&self, username: &str) -> bool {
+        self.applications
+            .iter()
+            .any(|entry| entry.username == username && entry.is_pending())
+    }
+
+    pub fn get(&self, id: &str) -> Option<Application> {
+        self.applications.get(id).map(|entry| entry.clone())
+    }
+
+    /// Undecided applications, oldest first.
+    pub fn pending(&self) -> Vec<Application> {
+        let mut pending: Vec<_> = self
+            .applications
+            .iter()
+            .filter(|entry| entry.is_pending())
+            .map(|entry| entry.clone())
+            .collect();
+        pending.sort_by_key(|application| application.submitted_at);
+        pending
+    }
+
+    /// Claims an undecided application, so that only one admin at a time
+    /// can act on it. `None` if the application is unknown, decided or
+    /// already claimed.
+    pub fn claim(self: &Arc<Self>, id: &str) -> Option<Claim> {
+        let entry = self.applications.get(id)?;
+        if !entry.is_pending() || !self.claimed.insert(id.to_string()) {
+            return None;
+        }
+        Some(Claim {
+            queue: self.clone(),
+            application: entry.clone(),
+        })
+    }
+
+    /// Decrypts the password of a claimed application.
+    pub fn open(&self, id: &str, sealed: &[u8]) -> Result<String, ApprovalError> {
+        if sealed.len() < NONCE_LEN + TAG_LEN {
+            return Err(ApprovalError::Truncated);
+        }
+        let (nonce, rest) = sealed.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        let password = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(nonce),
+            id.as_bytes(),
+            ciphertext,
+            tag,
+        )?;
+        String::from_utf8(password).map_err(|_| ApprovalError::NotUtf8)
+    }
+
+    /// The id is authenticated along with the password, so a ciphertext
+    /// can't be moved to another application.
+    fn seal(&self, id: &str, password: &str) -> Result<Vec<u8>, ApprovalError> {
+        let nonce: [u8; NONCE_LEN] = rand::thread_rng().gen();
+        let mut tag = [0; TAG_LEN];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &self.key,
+            Some(&nonce),
+            id.as_bytes(),
+            password.as_bytes(),
+            &mut tag,
+        )?;
+        Ok([&nonce[..], &ciphertext, &tag].concat())
+    }
+}
@@ -0,0 +1,145 @@
+//! Append-only JSON Lines record of registration attempts.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+
+use crate::RegistrationState;
+
+#[derive(Clone, Debug)]
+pub struct AuditConfig {
+    pub path: PathBuf,
+    /// The file is rotated before a line would take it past this size, and
+    /// on the first write of each UTC day.
+    pub max_bytes: u64,
+    /// Rotated files older than this are deleted.
+    pub retention: chrono::Duration,
+}
+
+/// One line of the audit log.
+#[derive(Clone, Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditEvent {
+    pub timestamp: DateTime<Utc>,
+    pub client_ip: IpAddr,
+    pub username: String,
+    pub outcome: RegistrationState,
+    /// [`crate::tokens::token_id`] of the invite token, never the token.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user_agent: Option<String>,
+    /// Milliseconds spent waiting on the homeserver to create the account.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub upstream_ms: Option<u64>,
+}
+
+pub struct AuditLog {
+    config: AuditConfig,
+    file: Mutex<Current>,
+}
+
+struct Current {
+    file: File,
+    len: u64,
+    /// UTC day of the file's last write.
+    day: NaiveDate,
+}
+
+impl AuditLog {
+    pub fn open(config: AuditConfig) -> io::Result<Self> {
+        let current = open_current(&config.path)?;
+        let log = Self {
+            config,
+            file: Mutex::new(current),
+        };
+        log.prune()?;
+        Ok(log)
+    }
+
+    pub fn write(&self, event: &AuditEvent) -> io::Result<()> {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+
+        let mut current = self
+            .file
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        let today = Utc::now().date_naive();
+        if current.len > 0
+            && (current.len + line.len() as u64 > self.config.max_bytes || current.day != today)
+        {
+            self.rotate(&mut current)?;
+        }
+        current.file.write_all(&line)?;
+        current.len += line.len() as u64;
+        current.day = today;
+        Ok(())
+    }
+
+    /// Rotates a file left over from a previous day and deletes expired
+    /// rotated files, for when no writes come in to do it.
+    pub fn sweep(&self) -> io::Result<()> {
+        let mut current = self
+            .file
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        if current.len > 0 && current.day != Utc::now().date_naive() {
+            self.rotate(&mut current)
+        } else {
+            self.prune()
+        }
+    }
+
+    fn rotate(&self, current: &mut Current) -> io::Result<()> {
+        current.file.flush()?;
+        let rotated = format!(
+            "{}.{}",
+            self.config.path.display(),
+            Utc::now().format("%Y%m%dT%H%M%S%.3fZ")
+        );
+        fs::rename(&self.config.path, rotated)?;
+        *current = open_current(&self.config.path)?;
+        self.prune()
+    }
+
+    /// Deletes rotated files whose last write is older than the retention.
+    fn prune(&self) -> io::Result<()> {
+        let Some(name) = self.config.path.file_name().and_then(|name| name.to_str()) else {
+            return Ok(());
+        };
+        let prefix = format!("{name}.");
+        let dir = match self.config.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let cutoff = SystemTime::from(Utc::now() - self.config.retention);
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let rotated = entry
+                .file_name()
+                .to_str()
+                .is_some_and(|file| file.starts_with(&prefix));
+            if rotated && entry.metadata()?.modified()? < cutoff {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn open_current(path: &Path) -> io::Result<Current> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let metadata = file.metadata()?;
+    Ok(Current {
+        file,
+        len: metadata.len(),
+        day: DateTime::<Utc>::from(metadata.modified()?).date_naive(),
+    })
+}
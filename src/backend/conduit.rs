@@ -0,0 +1,118 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode as ReqStatusCode};
+use serde::{Deserialize, Serialize};
+
+use super::{client_username_available, matrix_error_from, RegisterError, RegistrationBackend};
+use crate::metrics::{Metrics, Upstream};
+
+/// Registers through the client-server `/_matrix/client/v3/register` API,
+/// completing the `m.login.registration_token` stage with the token from
+/// Conduit's (or conduwuit's) `registration_token` setting.
+pub struct ConduitBackend {
+    url: String,
+    registration_token: String,
+    client: Client,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Serialize)]
+struct RegisterRequest<'a> {
+    username: &'a str,
+    password: &'a str,
+    inhibit_login: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    auth: Option<AuthData<'a>>,
+}
+
+#[derive(Serialize)]
+struct AuthData<'a> {
+    #[serde(rename = "type")]
+    kind: &'static str,
+    token: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    session: Option<String>,
+}
+
+/// Body of a 401 asking for user-interactive auth.
+#[derive(Deserialize)]
+struct AuthRequired {
+    session: Option<String>,
+}
+
+impl ConduitBackend {
+    pub fn new(
+        server: &str,
+        registration_token: &str,
+        client: Client,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            url: format!("{server}/_matrix/client/v3/register"),
+            registration_token: registration_token.to_string(),
+            client,
+            metrics,
+        }
+    }
+
+    /// Both rounds of user-interactive auth, if the first asks for a token.
+    async fn register(&self, username: &str, password: &str) -> Result<(), RegisterError> {
+        let mut request = RegisterRequest {
+            username,
+            password,
+            inhibit_login: true,
+            auth: None,
+        };
+
+        let (mut status, mut text) = self.post(&request).await?;
+        if status == ReqStatusCode::UNAUTHORIZED {
+            let session = serde_json::from_str::<AuthRequired>(&text)
+                .ok()
+                .and_then(|auth| auth.session);
+            request.auth = Some(AuthData {
+                kind: "m.login.registration_token",
+                token: &self.registration_token,
+                session,
+            });
+            (status, text) = self.post(&request).await?;
+        }
+
+        match status {
+            ReqStatusCode::OK => Ok(()),
+            status => Err(matrix_error_from(status, text)),
+        }
+    }
+
+    async fn post(
+        &self,
+        body: &RegisterRequest<'_>,
+    ) -> Result<(ReqStatusCode, String), RegisterError> {
+        let response = self
+            .client
+            .post(&self.url)
+            .json(body)
+            .send()
+            .await
+            .map_err(RegisterError::Upstream)?;
+        let status = response.status();
+        let text = response.text().await.map_err(RegisterError::Upstream)?;
+        Ok((status, text))
+    }
+}
+
+#[async_trait]
+impl RegistrationBackend for ConduitBackend {
+    async fn register_user(&self, username: &str, password: &str) -> Result<(), RegisterError> {
+        let started = Instant::now();
+        let result = self.register(username, password).await;
+        self.metrics.observe(Upstream::Register, started.elapsed());
+        result
+    }
+
+    async fn is_username_available(&self, username: &str) -> Result<bool, RegisterError> {
+        let url = format!("{}/available", self.url);
+        client_username_available(&self.client, &url, username).await
+    }
+}
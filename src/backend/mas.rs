@@ -0,0 +1,191 @@
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode as ReqStatusCode, Url};
+use serde::Deserialize;
+use serde_json::json;
+use tokio::sync::Mutex;
+use tracing::warn;
+
+use super::{unexpected, RegisterError, RegistrationBackend};
+use crate::metrics::{Metrics, Upstream};
+
+/// Creates accounts through the Matrix Authentication Service admin API,
+/// authenticating as an OAuth 2.0 client with the `urn:mas:admin` scope.
+pub struct MasBackend {
+    url: String,
+    client_id: String,
+    client_secret: String,
+    client: Client,
+    access_token: Mutex<Option<(String, Instant)>>,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct Single<T> {
+    data: T,
+}
+
+#[derive(Deserialize)]
+struct Resource {
+    id: String,
+}
+
+impl MasBackend {
+    pub fn new(
+        url: &str,
+        client_id: &str,
+        client_secret: &str,
+        client: Client,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        Self {
+            url: url.trim_end_matches('/').to_string(),
+            client_id: client_id.to_string(),
+            client_secret: client_secret.to_string(),
+            client,
+            access_token: Mutex::new(None),
+            metrics,
+        }
+    }
+
+    /// Returns a cached admin token, fetching a new one shortly before the
+    /// old one expires.
+    async fn access_token(&self) -> Result<String, RegisterError> {
+        let mut cached = self.access_token.lock().await;
+        if let Some((token, valid_until)) = cached.as_ref() {
+            if Instant::now() < *valid_until {
+                return Ok(token.clone());
+            }
+        }
+
+        let response = self
+            .client
+            .post(format!("{}/oauth2/token", self.url))
+            .basic_auth(&self.client_id, Some(&self.client_secret))
+            .form(&[
+                ("grant_type", "client_credentials"),
+                ("scope", "urn:mas:admin"),
+            ])
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(unexpected(response).await);
+        }
+        let token: TokenResponse = response.json().await?;
+        let lifetime = Duration::from_secs(token.expires_in.unwrap_or(300))
+            .saturating_sub(Duration::from_secs(30));
+        *cached = Some((token.access_token.clone(), Instant::now() + lifetime));
+        Ok(token.access_token)
+    }
+
+    /// Creates the user and sets its password, deactivating it again if the
+    /// password can't be set.
+    async fn create_user(
+        &self,
+        token: &str,
+        username: &str,
+        password: &str,
+    ) -> Result<(), RegisterError> {
+        let response = self
+            .client
+            .post(format!("{}/api/admin/v1/users", self.url))
+            .bearer_auth(token)
+            .json(&json!({ "username": username }))
+            .send()
+            .await?;
+        let user: Single<Resource> = match response.status() {
+            status if status.is_success() => response.json().await?,
+            ReqStatusCode::CONFLICT => return Err(RegisterError::UserExists),
+            _ => return Err(unexpected(response).await),
+        };
+
+        let response = self
+            .client
+            .post(format!(
+                "{}/api/admin/v1/users/{}/set-password",
+                self.url, user.data.id
+            ))
+            .bearer_auth(token)
+            .json(&json!({ "password": password }))
+            .send()
+            .await;
+        let err = match response {
+            Ok(response) if response.status().is_success() => return Ok(()),
+            // MAS checks the password against its own policy here.
+            Ok(response) if response.status() == ReqStatusCode::BAD_REQUEST => {
+                RegisterError::WeakPassword(response.text().await.unwrap_or_default())
+            }
+            Ok(response) => unexpected(response).await,
+            Err(err) => err.into(),
+        };
+        // MAS can't delete users, so the one just created is deactivated
+        // rather than left active without a password. Its username stays
+        // taken.
+        if let Err(deactivate_err) = self.deactivate(token, &user.data.id).await {
+            warn!("failed to deactivate MAS user {username} left without a password: {deactivate_err}");
+        }
+        Err(err)
+    }
+
+    async fn deactivate(&self, token: &str, id: &str) -> Result<(), RegisterError> {
+        let response = self
+            .client
+            .post(format!("{}/api/admin/v1/users/{id}/deactivate", self.url))
+            .bearer_auth(token)
+            .send()
+            .await?;
+        if !response.status().is_success() {
+            return Err(unexpected(response).await);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl RegistrationBackend for MasBackend {
+    async fn register_user(&self, username: &str, password: &str) -> Result<(), RegisterError> {
+        let token = self.access_token().await?;
+
+        let started = Instant::now();
+        let result = self.create_user(&token, username, password).await;
+        self.metrics.observe(Upstream::Register, started.elapsed());
+        result
+    }
+
+    async fn is_username_available(&self, username: &str) -> Result<bool, RegisterError> {
+        let token = self.access_token().await?;
+        // Localparts may contain `/`, so the username is encoded as a segment.
+        let mut url = Url::parse(&self.url)
+            .ok()
+            .filter(|url| !url.cannot_be_a_base())
+            .ok_or_else(|| {
+                RegisterError::UnexpectedStatus(
+                    ReqStatusCode::INTERNAL_SERVER_ERROR,
+                    format!("invalid MAS_URL {}", self.url),
+                )
+            })?;
+        url.path_segments_mut().expect("checked above").extend([
+            "api",
+            "admin",
+            "v1",
+            "users",
+            "by-username",
+            username,
+        ]);
+
+        let response = self.client.get(url).bearer_auth(&token).send().await?;
+        match response.status() {
+            status if status.is_success() => Ok(false),
+            ReqStatusCode::NOT_FOUND => Ok(true),
+            _ => Err(unexpected(response).await),
+        }
+    }
+}
@@ -0,0 +1,184 @@
+//! Homeserver-specific ways of creating an account.
+
+mod conduit;
+mod mas;
+mod shared_secret;
+
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use reqwest::{Client, StatusCode as ReqStatusCode};
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::metrics::Metrics;
+
+pub use conduit::ConduitBackend;
+pub use mas::MasBackend;
+pub use shared_secret::SharedSecretBackend;
+
+#[cfg(test)]
+pub use shared_secret::calculate_mac;
+
+#[async_trait]
+pub trait RegistrationBackend: Send + Sync {
+    async fn register_user(&self, username: &str, password: &str) -> Result<(), RegisterError>;
+
+    /// Whether `username` could still be registered.
+    async fn is_username_available(&self, username: &str) -> Result<bool, RegisterError>;
+
+    /// Checks the configured credentials against the homeserver without
+    /// creating an account, where the backend has a way to.
+    async fn verify_credentials(&self) -> Result<(), RegisterError> {
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum RegisterError {
+    #[error("user exists")]
+    UserExists,
+    #[error("homeserver refused the username: {0}")]
+    InvalidUsername(String),
+    #[error("username is in an application service's namespace: {0}")]
+    Exclusive(String),
+    #[error("homeserver refused the password: {0}")]
+    WeakPassword(String),
+    /// The HMAC didn't match; nothing can register until the secret does.
+    #[error("homeserver rejected the shared secret: {0}")]
+    SharedSecretRejected(String),
+    #[error("upstream error: {0}")]
+    Upstream(#[from] reqwest::Error),
+    #[error("unexpected upstream status {0}: {1}")]
+    UnexpectedStatus(ReqStatusCode, String),
+}
+
+/// Which homeserver flavour accounts are created on, selected by
+/// `HOMESERVER_TYPE`.
+#[derive(Clone)]
+pub enum BackendConfig {
+    /// Synapse's `/_synapse/admin/v1/register` shared-secret flow.
+    Synapse { shared_secret: String },
+    /// Dendrite serves the same shared-secret endpoint as Synapse.
+    Dendrite { shared_secret: String },
+    /// Conduit/conduwuit: client-server registration with the server's
+    /// registration token.
+    Conduit { registration_token: String },
+    /// Matrix Authentication Service admin API.
+    Mas {
+        url: String,
+        client_id: String,
+        client_secret: String,
+    },
+}
+
+impl BackendConfig {
+    pub fn build(
+        &self,
+        server: &str,
+        client: Client,
+        metrics: Arc<Metrics>,
+    ) -> Arc<dyn RegistrationBackend> {
+        match self {
+            BackendConfig::Synapse { shared_secret }
+            | BackendConfig::Dendrite { shared_secret } => Arc::new(SharedSecretBackend::new(
+                server,
+                shared_secret,
+                client,
+                metrics,
+            )),
+            BackendConfig::Conduit { registration_token } => Arc::new(ConduitBackend::new(
+                server,
+                registration_token,
+                client,
+                metrics,
+            )),
+            BackendConfig::Mas {
+                url,
+                client_id,
+                client_secret,
+            } => Arc::new(MasBackend::new(
+                url,
+                client_id,
+                client_secret,
+                client,
+                metrics,
+            )),
+        }
+    }
+}
+
+/// Reads the rest of a failed response into an [`RegisterError::UnexpectedStatus`].
+async fn unexpected(response: reqwest::Response) -> RegisterError {
+    let status = response.status();
+    let text = response.text().await.unwrap_or_default();
+    RegisterError::UnexpectedStatus(status, text)
+}
+
+#[derive(Deserialize)]
+struct Availability {
+    available: bool,
+}
+
+#[derive(Deserialize)]
+struct MatrixError {
+    errcode: String,
+    #[serde(default)]
+    error: String,
+}
+
+/// Reads a failed response's Matrix `errcode` into the matching
+/// [`RegisterError`]; anything unrecognised stays an unexpected status.
+async fn matrix_error(response: reqwest::Response) -> RegisterError {
+    let status = response.status();
+    let text = response.text().await.unwrap_or_default();
+    matrix_error_from(status, text)
+}
+
+fn matrix_error_from(status: ReqStatusCode, text: String) -> RegisterError {
+    let Ok(err) = serde_json::from_str::<MatrixError>(&text) else {
+        return RegisterError::UnexpectedStatus(status, text);
+    };
+    match err.errcode.as_str() {
+        "M_USER_IN_USE" => RegisterError::UserExists,
+        "M_INVALID_USERNAME" => RegisterError::InvalidUsername(err.error),
+        "M_EXCLUSIVE" => RegisterError::Exclusive(err.error),
+        "M_WEAK_PASSWORD" => RegisterError::WeakPassword(err.error),
+        _ => RegisterError::UnexpectedStatus(status, text),
+    }
+}
+
+/// Asks the client-server `/_matrix/client/v3/register/available` endpoint,
+/// which Synapse, Dendrite and Conduit all serve.
+async fn client_username_available(
+    client: &Client,
+    url: &str,
+    username: &str,
+) -> Result<bool, RegisterError> {
+    let response = client
+        .get(url)
+        .query(&[("username", username)])
+        .send()
+        .await?;
+    match response.status() {
+        ReqStatusCode::OK => Ok(response.json::<Availability>().await?.available),
+        ReqStatusCode::BAD_REQUEST => {
+            let status = response.status();
+            let text = response.text().await.unwrap_or_default();
+            match serde_json::from_str::<MatrixError>(&text) {
+                // Taken, claimed by an application service, or refused by
+                // the server's own rules.
+                Ok(err)
+                    if matches!(
+                        err.errcode.as_str(),
+                        "M_USER_IN_USE" | "M_EXCLUSIVE" | "M_INVALID_USERNAME"
+                    ) =>
+                {
+                    Ok(false)
+                }
+                _ => Err(RegisterError::UnexpectedStatus(status, text)),
+            }
+        }
+        _ => Err(unexpected(response).await),
+    }
+}
@@ -0,0 +1,130 @@
+use std::sync::Arc;
+use std::time::Instant;
+
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use reqwest::{Client, StatusCode as ReqStatusCode};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+
+use super::{client_username_available, matrix_error, RegisterError, RegistrationBackend};
+use crate::metrics::{Metrics, Upstream};
+
+type HmacSha1 = Hmac<Sha1>;
+
+/// Not a valid localpart, so a correctly signed request for it gets past the
+/// HMAC check and is then refused without creating anything.
+const PROBE_USERNAME: &str = "!shared-secret-check";
+
+/// Registers through `/_synapse/admin/v1/register`, authenticating each
+/// request with an HMAC over a fresh nonce and the shared secret. Synapse and
+/// Dendrite both speak this.
+pub struct SharedSecretBackend {
+    url: String,
+    available_url: String,
+    shared_secret: String,
+    client: Client,
+    metrics: Arc<Metrics>,
+}
+
+#[derive(Serialize)]
+struct RegisterUserRequest<'a> {
+    nonce: String,
+    username: &'a str,
+    password: &'a str,
+    admin: bool,
+    mac: String,
+}
+
+#[derive(Deserialize)]
+struct NonceResponse {
+    nonce: String,
+}
+
+impl SharedSecretBackend {
+    pub fn new(server: &str, shared_secret: &str, client: Client, metrics: Arc<Metrics>) -> Self {
+        Self {
+            url: format!("{server}/_synapse/admin/v1/register"),
+            available_url: format!("{server}/_matrix/client/v3/register/available"),
+            shared_secret: shared_secret.to_string(),
+            client,
+            metrics,
+        }
+    }
+
+    async fn fetch_nonce(&self) -> Result<String, RegisterError> {
+        let started = Instant::now();
+        let response = self.client.get(&self.url).send().await;
+        self.metrics.observe(Upstream::Nonce, started.elapsed());
+        let response = response
+            .map_err(RegisterError::Upstream)?
+            .error_for_status()
+            .map_err(|e| {
+                RegisterError::UnexpectedStatus(
+                    e.status().unwrap_or(ReqStatusCode::INTERNAL_SERVER_ERROR),
+                    e.to_string(),
+                )
+            })?;
+
+        let payload: NonceResponse = response.json().await.map_err(RegisterError::Upstream)?;
+        Ok(payload.nonce)
+    }
+}
+
+#[async_trait]
+impl RegistrationBackend for SharedSecretBackend {
+    async fn register_user(&self, username: &str, password: &str) -> Result<(), RegisterError> {
+        let nonce = self.fetch_nonce().await?;
+        let mac = calculate_mac(&nonce, username, password, &self.shared_secret);
+        let body = RegisterUserRequest {
+            nonce,
+            username,
+            password,
+            admin: false,
+            mac,
+        };
+
+        let started = Instant::now();
+        let response = self.client.post(&self.url).json(&body).send().await;
+        self.metrics.observe(Upstream::Register, started.elapsed());
+        let response = response.map_err(RegisterError::Upstream)?;
+
+        match response.status() {
+            ReqStatusCode::OK => Ok(()),
+            ReqStatusCode::FORBIDDEN => {
+                let text = response.text().await.unwrap_or_default();
+                Err(RegisterError::SharedSecretRejected(text))
+            }
+            _ => Err(matrix_error(response).await),
+        }
+    }
+
+    async fn is_username_available(&self, username: &str) -> Result<bool, RegisterError> {
+        client_username_available(&self.client, &self.available_url, username).await
+    }
+
+    async fn verify_credentials(&self) -> Result<(), RegisterError> {
+        match self.register_user(PROBE_USERNAME, "probe").await {
+            Err(RegisterError::InvalidUsername(_)) => Ok(()),
+            Ok(()) => Err(RegisterError::UnexpectedStatus(
+                ReqStatusCode::OK,
+                format!("homeserver registered {PROBE_USERNAME}"),
+            )),
+            Err(err) => Err(err),
+        }
+    }
+}
+
+pub fn calculate_mac(nonce: &str, user: &str, password: &str, shared_secret: &str) -> String {
+    let mut mac = HmacSha1::new_from_slice(shared_secret.as_bytes()).expect("hmac can take key");
+
+    mac.update(nonce.as_bytes());
+    mac.update(&[0]);
+    mac.update(user.as_bytes());
+    mac.update(&[0]);
+    mac.update(password.as_bytes());
+    mac.update(&[0]);
+    mac.update(b"notadmin");
+
+    hex::encode(mac.finalize().into_bytes())
+}
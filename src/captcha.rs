@@ -0,0 +1,245 @@
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::debug;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// How long a proof-of-work challenge may be solved and submitted.
+const CHALLENGE_TTL_SECS: i64 = 300;
+
+#[derive(Clone, Debug)]
+pub enum CaptchaConfig {
+    /// A hosted widget whose answer is checked against the provider's
+    /// `siteverify` endpoint.
+    Remote {
+        provider: CaptchaProvider,
+        site_key: String,
+        secret: String,
+        verify_url: String,
+    },
+    /// A hash puzzle issued and checked by this service; no third party.
+    ProofOfWork {
+        /// Leading zero bits the solution's SHA-256 must have.
+        difficulty: u32,
+    },
+}
+
+impl CaptchaConfig {
+    /// Name the frontend uses to pick its widget.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Remote { provider, .. } => provider.name(),
+            Self::ProofOfWork { .. } => "pow",
+        }
+    }
+
+    pub fn site_key(&self) -> &str {
+        match self {
+            Self::Remote { site_key, .. } => site_key,
+            Self::ProofOfWork { .. } => "",
+        }
+    }
+}
+
+/// Hosted providers. All three speak the same `siteverify` protocol.
+#[derive(Clone, Copy, Debug)]
+pub enum CaptchaProvider {
+    HCaptcha,
+    Turnstile,
+    ReCaptcha,
+}
+
+impl CaptchaProvider {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::HCaptcha => "hcaptcha",
+            Self::Turnstile => "turnstile",
+            Self::ReCaptcha => "recaptcha",
+        }
+    }
+
+    pub fn default_verify_url(self) -> &'static str {
+        match self {
+            Self::HCaptcha => "https://api.hcaptcha.com/siteverify",
+            Self::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/siteverify",
+            Self::ReCaptcha => "https://www.google.com/recaptcha/api/siteverify",
+        }
+    }
+}
+
+#[derive(Debug, Error, PartialEq)]
+pub enum CaptchaError {
+    #[error("captcha not solved")]
+    Failed,
+    #[error("captcha verification failed: {0}")]
+    Upstream(String),
+}
+
+#[derive(Serialize)]
+struct VerifyRequest<'a> {
+    secret: &'a str,
+    response: &'a str,
+    remoteip: String,
+}
+
+#[derive(Deserialize)]
+struct VerifyResponse {
+    success: bool,
+    #[serde(rename = "error-codes", default)]
+    error_codes: Vec<String>,
+}
+
+/// A proof-of-work puzzle as handed to the browser.
+#[derive(Serialize)]
+pub struct Challenge {
+    pub challenge: String,
+    pub difficulty: u32,
+}
+
+pub struct Captcha {
+    config: CaptchaConfig,
+    /// Signs proof-of-work challenges so they need no server-side state
+    /// until they're used.
+    key: [u8; 32],
+    /// Solved challenges, kept until they expire so they can't be replayed.
+    used: DashMap<String, DateTime<Utc>>,
+}
+
+impl Captcha {
+    pub fn new(config: CaptchaConfig) -> Self {
+        Self {
+            config,
+            key: rand::thread_rng().gen(),
+            used: DashMap::new(),
+        }
+    }
+
+    /// A fresh puzzle, or `None` for hosted providers.
+    pub fn challenge(&self) -> Option<Challenge> {
+        let CaptchaConfig::ProofOfWork { difficulty } = self.config else {
+            return None;
+        };
+        let nonce: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        let payload = format!(
+            "{nonce}.{}",
+            (Utc::now() + Duration::seconds(CHALLENGE_TTL_SECS)).timestamp()
+        );
+        Some(Challenge {
+            challenge: format!("{payload}.{}", self.sign(&payload)),
+            difficulty,
+        })
+    }
+
+    /// Checks the answer the form carried in its `captcha` field.
+    pub async fn verify(
+        &self,
+        client: &Client,
+        response: &str,
+        remote_ip: IpAddr,
+    ) -> Result<(), CaptchaError> {
+        if response.is_empty() {
+            return Err(CaptchaError::Failed);
+        }
+        match &self.config {
+            CaptchaConfig::Remote {
+                secret, verify_url, ..
+            } => verify_remote(client, verify_url, secret, response, remote_ip).await,
+            CaptchaConfig::ProofOfWork { difficulty } => self.verify_pow(response, *difficulty),
+        }
+    }
+
+    /// `response` is `{challenge}:{solution}`, where the SHA-256 of the whole
+    /// string has at least `difficulty` leading zero bits.
+    fn verify_pow(&self, response: &str, difficulty: u32) -> Result<(), CaptchaError> {
+        let (challenge, _) = response.rsplit_once(':').ok_or(CaptchaError::Failed)?;
+        let (payload, signature) = challenge.rsplit_once('.').ok_or(CaptchaError::Failed)?;
+        let expires_at = payload
+            .rsplit_once('.')
+            .and_then(|(_, expiry)| expiry.parse().ok())
+            .and_then(|expiry| Utc.timestamp_opt(expiry, 0).single())
+            .ok_or(CaptchaError::Failed)?;
+
+        let signature = hex::decode(signature).map_err(|_| CaptchaError::Failed)?;
+        self.mac(payload)
+            .verify_slice(&signature)
+            .map_err(|_| CaptchaError::Failed)?;
+
+        let now = Utc::now();
+        if expires_at <= now || leading_zero_bits(&Sha256::digest(response)) < difficulty {
+            return Err(CaptchaError::Failed);
+        }
+
+        self.used.retain(|_, expires_at| *expires_at > now);
+        if self
+            .used
+            .insert(challenge.to_string(), expires_at)
+            .is_some()
+        {
+            return Err(CaptchaError::Failed);
+        }
+        Ok(())
+    }
+
+    fn sign(&self, payload: &str) -> String {
+        hex::encode(self.mac(payload).finalize().into_bytes())
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(&self.key).expect("hmac can take key");
+        mac.update(payload.as_bytes());
+        mac
+    }
+}
+
+async fn verify_remote(
+    client: &Client,
+    verify_url: &str,
+    secret: &str,
+    response: &str,
+    remote_ip: IpAddr,
+) -> Result<(), CaptchaError> {
+    let verdict: VerifyResponse = client
+        .post(verify_url)
+        .form(&VerifyRequest {
+            secret,
+            response,
+            remoteip: remote_ip.to_string(),
+        })
+        .send()
+        .await
+        .and_then(reqwest::Response::error_for_status)
+        .map_err(|e| CaptchaError::Upstream(e.to_string()))?
+        .json()
+        .await
+        .map_err(|e| CaptchaError::Upstream(e.to_string()))?;
+
+    if verdict.success {
+        Ok(())
+    } else {
+        debug!("captcha rejected: {:?}", verdict.error_codes);
+        Err(CaptchaError::Failed)
+    }
+}
+
+pub fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
@@ -0,0 +1,107 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::http::HeaderMap;
+use ipnet::IpNet;
+
+/// The one header the trusted proxies set, selected by `FORWARDED_HEADER`.
+///
+/// Only that header is read: proxies usually pass the others through from
+/// the client untouched, so believing them would let anyone pick their IP.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ForwardedHeader {
+    #[default]
+    XForwardedFor,
+    /// RFC 7239 `Forwarded`, using its `for=` parameters.
+    Forwarded,
+    XRealIp,
+}
+
+impl ForwardedHeader {
+    pub fn parse(name: &str) -> Option<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => Some(Self::XForwardedFor),
+            "forwarded" => Some(Self::Forwarded),
+            "x-real-ip" => Some(Self::XRealIp),
+            _ => None,
+        }
+    }
+}
+
+/// Works out the real client address for a request that arrived from `peer`.
+///
+/// The `header` is only believed when `peer` is one of the `trusted`
+/// proxies. Its chain is then walked right-to-left, skipping further trusted
+/// hops, so the first untrusted address is the client.
+pub fn client_ip(
+    headers: &HeaderMap,
+    peer: IpAddr,
+    trusted: &[IpNet],
+    header: ForwardedHeader,
+) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted.iter().any(|net| net.contains(ip));
+    if !is_trusted(&peer) {
+        return peer;
+    }
+
+    let chain = match header {
+        ForwardedHeader::XForwardedFor => header_list(headers, "x-forwarded-for"),
+        ForwardedHeader::Forwarded => forwarded_chain(headers),
+        ForwardedHeader::XRealIp => header_list(headers, "x-real-ip"),
+    };
+    let Some(chain) = chain else {
+        return peer;
+    };
+
+    let mut client = peer;
+    for node in chain.iter().rev() {
+        // An unparsable hop means we can't tell who sent it; stop at the last
+        // address we could vouch for.
+        let Some(ip) = parse_node(node) else {
+            break;
+        };
+        client = ip;
+        if !is_trusted(&ip) {
+            break;
+        }
+    }
+    client
+}
+
+/// All `for=` values of every `Forwarded` header, in order.
+fn forwarded_chain(headers: &HeaderMap) -> Option<Vec<String>> {
+    let mut chain = Vec::new();
+    for value in headers.get_all("forwarded") {
+        let value = value.to_str().ok()?;
+        for element in value.split(',') {
+            let node = element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                key.eq_ignore_ascii_case("for")
+                    .then(|| value.trim().trim_matches('"').to_string())
+            });
+            // An element without `for=` still is a hop we know nothing about.
+            chain.push(node.unwrap_or_default());
+        }
+    }
+    (!chain.is_empty()).then_some(chain)
+}
+
+fn header_list(headers: &HeaderMap, name: &str) -> Option<Vec<String>> {
+    let mut chain = Vec::new();
+    for value in headers.get_all(name) {
+        let value = value.to_str().ok()?;
+        chain.extend(value.split(',').map(|node| node.trim().to_string()));
+    }
+    (!chain.is_empty()).then_some(chain)
+}
+
+/// Accepts `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1` and `[2001:db8::1]:80`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| {
+            node.strip_prefix('[')
+                .and_then(|rest| rest.strip_suffix(']'))
+                .and_then(|ip| ip.parse().ok())
+        })
+}
@@ -0,0 +1,206 @@
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use lettre::message::{header::ContentType, Mailbox};
+use lettre::{Address, AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::{Client, Url};
+use serde_json::json;
+use thiserror::Error;
+
+use crate::Signup;
+
+/// Settings for confirming an email address before the account is created.
+#[derive(Clone)]
+pub struct EmailConfig {
+    pub transport: AsyncSmtpTransport<Tokio1Executor>,
+    pub from: Mailbox,
+    /// Where this service is reachable from a browser; verification links
+    /// point at `{public_url}/verify/{code}`.
+    pub public_url: String,
+    /// How long a verification link stays valid.
+    pub ttl: Duration,
+    /// Access token of a Synapse server admin, used to bind the address.
+    pub admin_token: String,
+}
+
+#[derive(Debug, Error)]
+pub enum EmailError {
+    #[error("failed to build message: {0}")]
+    Message(#[from] lettre::error::Error),
+    #[error("failed to send message: {0}")]
+    Smtp(#[from] lettre::transport::smtp::Error),
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("invalid MATRIX_SERVER url")]
+    InvalidServer,
+}
+
+/// A signup waiting for its address to be confirmed. Its invite token was
+/// checked but not spent; the use is taken once the link is confirmed.
+pub struct PendingRegistration {
+    pub signup: Signup,
+    pub email: Address,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl PendingRegistration {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at <= now
+    }
+}
+
+/// Pending registrations keyed by their verification code.
+///
+/// They only live in memory: nothing, least of all the password, is written
+/// to disk before the account exists. A restart drops every pending signup.
+pub struct EmailVerification {
+    server: String,
+    config: EmailConfig,
+    client: Client,
+    pending: DashMap<String, PendingRegistration>,
+}
+
+impl EmailVerification {
+    pub fn new(server: &str, config: EmailConfig, client: Client) -> Self {
+        Self {
+            server: server.to_string(),
+            config,
+            client,
+            pending: DashMap::new(),
+        }
+    }
+
+    /// Whether a signup for `username` is still waiting to be confirmed.
+    pub fn is_pending(&self, username: &str) -> bool {
+        let now = Utc::now();
+        self.pending
+            .iter()
+            .any(|entry| entry.signup.username == username && !entry.is_expired(now))
+    }
+
+    /// Stores `signup` and mails the verification link to `email`. Nothing is
+    /// kept if the mail can't be sent.
+    pub async fn start(
+        &self,
+        signup: Signup,
+        email: Address,
+        user_id: &str,
+    ) -> Result<(), EmailError> {
+        let code: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(32)
+            .map(char::from)
+            .collect();
+        let link = format!(
+            "{}/verify/{code}",
+            self.config.public_url.trim_end_matches('/')
+        );
+        let message = Message::builder()
+            .from(self.config.from.clone())
+            .to(Mailbox::new(None, email.clone()))
+            .subject("Confirm your Matrix account")
+            .header(ContentType::TEXT_PLAIN)
+            .body(format!(
+                "Someone, hopefully you, asked to register {user_id} with this address.\n\
+                 \n\
+                 Open this link within {ttl} and confirm to create the account:\n\
+                 \n\
+                 {link}\n\
+                 \n\
+                 If this wasn't you, ignore this email; no account is created \
+                 unless the link is opened and confirmed.\n",
+                ttl = describe_ttl(self.config.ttl),
+            ))?;
+
+        self.pending.insert(
+            code.clone(),
+            PendingRegistration {
+                signup,
+                email,
+                expires_at: Utc::now() + self.config.ttl,
+            },
+        );
+        if let Err(err) = self.config.transport.send(message).await {
+            self.pending.remove(&code);
+            return Err(err.into());
+        }
+        Ok(())
+    }
+
+    /// Whether `code` belongs to a registration waiting to be confirmed.
+    pub fn contains(&self, code: &str) -> bool {
+        self.pending.contains_key(code)
+    }
+
+    /// Hands out the pending registration for `code`, once, unless it is
+    /// [restored](Self::restore).
+    pub fn take(&self, code: &str) -> Option<PendingRegistration> {
+        self.pending.remove(code).map(|(_, pending)| pending)
+    }
+
+    /// Puts back a registration whose confirmation failed on our side or
+    /// upstream, so the link can be used again.
+    pub fn restore(&self, code: String, pending: PendingRegistration) {
+        self.pending.insert(code, pending);
+    }
+
+    /// Drops every expired registration, passwords included.
+    pub fn drop_expired(&self) {
+        let now = Utc::now();
+        self.pending.retain(|_, pending| !pending.is_expired(now));
+    }
+
+    /// Adds `email` to the account's third-party identifiers through the
+    /// Synapse admin API.
+    pub async fn bind(&self, user_id: &str, email: &Address) -> Result<(), EmailError> {
+        let mut url = Url::parse(&self.server).map_err(|_| EmailError::InvalidServer)?;
+        url.path_segments_mut()
+            .map_err(|_| EmailError::InvalidServer)?
+            .extend(["_synapse", "admin", "v2", "users", user_id]);
+        self.client
+            .put(url)
+            .bearer_auth(&self.config.admin_token)
+            .json(&json!({
+                "threepids": [{ "medium": "email", "address": email.to_string() }],
+            }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// What opening a verification link shows. Mail scanners that fetch links
+/// to check them stop here; the account is only created when the form posts
+/// back to the same URL.
+pub const CONFIRM_PAGE: &str = r#"<!DOCTYPE html>
+<html lang="en">
+<head>
+    <meta charset="utf-8">
+    <meta name="viewport" content="width=device-width, initial-scale=1">
+    <meta name="robots" content="noindex">
+    <title>Confirm your Matrix account</title>
+</head>
+<body>
+    <form method="post">
+        <p>Confirm your email address to create the account.</p>
+        <button type="submit">Create account</button>
+    </form>
+</body>
+</html>
+"#;
+
+/// `ttl` in words, e.g. "30 minutes" or "1 hour 30 minutes"; never less than
+/// a minute.
+fn describe_ttl(ttl: Duration) -> String {
+    let minutes = ttl.num_minutes().max(1);
+    let count = |n: i64, unit: &str| match n {
+        1 => format!("1 {unit}"),
+        n => format!("{n} {unit}s"),
+    };
+    match (minutes / 60, minutes % 60) {
+        (0, minutes) => count(minutes, "minute"),
+        (hours, 0) => count(hours, "hour"),
+        (hours, minutes) => format!("{} {}", count(hours, "hour"), count(minutes, "minute")),
+    }
+}
@@ -0,0 +1,122 @@
+use std::borrow::Cow;
+use std::path::{Component, Path as FsPath, PathBuf};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+    routing::get,
+    Router,
+};
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+
+use crate::AppState;
+
+#[cfg(feature = "embed-static")]
+static EMBEDDED: include_dir::Dir<'static> =
+    include_dir::include_dir!("$CARGO_MANIFEST_DIR/static");
+
+static PLACEHOLDER_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"\{\{\s*([A-Za-z0-9_]+)\s*\}\}").unwrap());
+
+/// Where the bundled frontend is served from.
+#[derive(Clone, Debug)]
+pub enum StaticSource {
+    Disk(PathBuf),
+    #[cfg(feature = "embed-static")]
+    Embedded,
+}
+
+#[derive(Clone, Debug)]
+pub struct FrontendConfig {
+    pub source: StaticSource,
+    /// Values substituted for `{{ name }}` placeholders in HTML and JS files.
+    pub vars: Vec<(String, String)>,
+}
+
+impl FrontendConfig {
+    fn load(&self, path: &str) -> Option<Cow<'static, [u8]>> {
+        match &self.source {
+            StaticSource::Disk(root) => {
+                let relative = FsPath::new(path);
+                // Refuse anything that could climb out of the static root.
+                if !relative
+                    .components()
+                    .all(|component| matches!(component, Component::Normal(_)))
+                {
+                    return None;
+                }
+                std::fs::read(root.join(relative)).ok().map(Cow::Owned)
+            }
+            #[cfg(feature = "embed-static")]
+            StaticSource::Embedded => EMBEDDED
+                .get_file(path)
+                .map(|file| Cow::Borrowed(file.contents())),
+        }
+    }
+
+    fn render(&self, body: &str) -> String {
+        PLACEHOLDER_RE
+            .replace_all(body, |caps: &Captures| {
+                self.vars
+                    .iter()
+                    .find(|(name, _)| name == &caps[1])
+                    .map_or_else(|| caps[0].to_string(), |(_, value)| value.clone())
+            })
+            .into_owned()
+    }
+}
+
+pub fn router() -> Router<AppState> {
+    Router::new()
+        .route("/", get(index))
+        .route("/*path", get(asset))
+}
+
+async fn index(State(state): State<AppState>) -> Response {
+    serve(&state, "index.html")
+}
+
+async fn asset(State(state): State<AppState>, Path(path): Path<String>) -> Response {
+    if path.is_empty() || path.ends_with('/') {
+        return serve(&state, &format!("{path}index.html"));
+    }
+    serve(&state, &path)
+}
+
+fn serve(state: &AppState, path: &str) -> Response {
+    let Some(frontend) = state.config.frontend.as_ref() else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let Some(body) = frontend.load(path) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let mime = mime_guess::from_path(path).first_or_octet_stream();
+    let is_html = mime.subtype() == mime_guess::mime::HTML;
+    let templated = is_html || mime.subtype() == mime_guess::mime::JAVASCRIPT;
+    // Templated files carry rendered config and must be revalidated; other
+    // assets rarely change.
+    let cache_control = if templated {
+        "no-cache"
+    } else {
+        "public, max-age=86400"
+    };
+
+    let body = match std::str::from_utf8(&body) {
+        Ok(text) if templated => frontend.render(text).into_bytes(),
+        _ => body.into_owned(),
+    };
+
+    let mut response = body.into_response();
+    let headers = response.headers_mut();
+    if let Ok(content_type) = HeaderValue::from_str(mime.as_ref()) {
+        headers.insert(header::CONTENT_TYPE, content_type);
+    }
+    headers.insert(
+        header::CACHE_CONTROL,
+        HeaderValue::from_static(cache_control),
+    );
+    response
+}
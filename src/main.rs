@@ -1,181 +1,1190 @@
 use std::net::{IpAddr, SocketAddr};
+use std::path::PathBuf;
+use std::sync::Arc;
 use dotenv::dotenv;
+use async_trait::async_trait;
 use axum::{
-    extract::{ConnectInfo, Form, State},
-    http::{HeaderMap, StatusCode},
-    response::{IntoResponse, Json},
-    routing::post,
+    extract::{ConnectInfo, Form, FromRequest, Path, Query, Request, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{Html, IntoResponse, Json, Response},
+    routing::{get, post},
     Router,
 };
-use chrono::{DateTime, Utc};
+use chrono::Utc;
 use dashmap::DashMap;
-use hmac::{Hmac, Mac};
-use once_cell::sync::Lazy;
+use ipnet::IpNet;
+use lettre::transport::smtp::authentication::Credentials;
+use lettre::{Address, AsyncSmtpTransport, Tokio1Executor};
+use rand::{distributions::Alphanumeric, Rng};
 use regex::Regex;
-use reqwest::{Client, StatusCode as ReqStatusCode};
+use reqwest::Client;
 use serde::{Deserialize, Serialize};
-use sha1::Sha1;
+use std::time::Instant;
 use thiserror::Error;
 use tokio::net::TcpListener;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
-type HmacSha1 = Hmac<Sha1>;
+mod admin;
+mod approval;
+mod audit;
+mod backend;
+mod captcha;
+mod client_ip;
+mod email;
+mod frontend;
+mod metrics;
+mod notify;
+mod password;
+mod provision;
+mod pwned;
+mod ratelimit;
+mod storage;
+mod synapse_tokens;
+mod tokens;
+mod username;
+mod webhooks;
 
-static USERNAME_RE: Lazy<Regex> = Lazy::new(|| Regex::new("^[a-zA-Z0-9]+$").unwrap());
+#[cfg(test)]
+mod tests;
+
+use approval::{Application, ApprovalConfig, ApprovalQueue};
+use audit::{AuditConfig, AuditEvent, AuditLog};
+use backend::{BackendConfig, RegisterError, RegistrationBackend};
+use captcha::{Captcha, CaptchaConfig, CaptchaError, CaptchaProvider};
+use client_ip::{client_ip, ForwardedHeader};
+use email::{EmailConfig, EmailError, EmailVerification, CONFIRM_PAGE};
+use frontend::{FrontendConfig, StaticSource};
+use metrics::{Metrics, Snapshot};
+use notify::{Notifier, NotifyConfig};
+use password::{PasswordError, PasswordPolicy};
+use provision::{ProvisionConfig, Provisioner, WelcomeMessage};
+use pwned::PwnedPasswords;
+use ratelimit::{Attempt, RateLimitPolicy};
+use storage::{MemoryStorage, Registration, SqliteStorage, Storage, StorageError};
+use synapse_tokens::SynapseTokens;
+use tokens::{parse_token_spec, token_id, InviteToken, TokenError, TokenStore};
+use username::{UsernameError, UsernamePolicy};
+use webhooks::{WebhookConfig, Webhooks};
 
 #[derive(Clone)]
 struct AppConfig {
-    token: String,
+    tokens: Vec<(String, InviteToken)>,
+    token_backend: TokenBackend,
+    /// Key for the [`token_id`]s shown in place of invite tokens.
+    token_id_key: String,
     server: String,
-    shared_secret: String,
+    /// The homeserver's `server_name`, the part after the colon in MXIDs.
+    server_name: String,
+    username: UsernamePolicy,
+    backend: BackendConfig,
     bind_addr: SocketAddr,
+    storage: StorageConfig,
+    admin_secret: Option<String>,
+    allowlist: Vec<IpNet>,
+    trusted_proxies: Vec<IpNet>,
+    /// Which header `trusted_proxies` put the client address in.
+    forwarded_header: ForwardedHeader,
+    rate_limit: RateLimitPolicy,
+    /// Separate budget for `/username_available`, which is cheap to call.
+    availability_rate_limit: RateLimitPolicy,
+    password: PasswordPolicy,
+    pwned: Option<PwnedPasswords>,
+    frontend: Option<FrontendConfig>,
+    provision: Option<ProvisionConfig>,
+    email: Option<EmailConfig>,
+    captcha: Option<CaptchaConfig>,
+    status_codes: StatusCodes,
+    /// Serve `/metrics`.
+    metrics: bool,
+    audit: Option<AuditConfig>,
+    webhooks: Option<WebhookConfig>,
+    notify: Option<NotifyConfig>,
+    /// Queue signups for an admin instead of creating them right away.
+    approval: Option<ApprovalConfig>,
+}
+
+/// How registration outcomes map onto HTTP statuses, selected by
+/// `STATUS_CODES`.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum StatusCodes {
+    /// What the original app sent: 200 for nearly everything, 422 for
+    /// `USER_EXISTS` and 500 for `INTERNAL_ERROR`.
+    #[default]
+    Legacy,
+    /// A status that matches each state; see [`RegistrationState::status`].
+    Strict,
+}
+
+impl StatusCodes {
+    fn from_env() -> Result<Self, ConfigError> {
+        match env_non_empty("STATUS_CODES").as_deref() {
+            None | Some("legacy") => Ok(Self::Legacy),
+            Some("strict") => Ok(Self::Strict),
+            Some(_) => Err(ConfigError::Invalid("STATUS_CODES")),
+        }
+    }
+
+    /// The status for an `INTERNAL_ERROR` caused by the homeserver, the
+    /// token backend, the CAPTCHA provider or the mail server.
+    fn upstream_failure(self) -> StatusCode {
+        match self {
+            Self::Legacy => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Strict => StatusCode::BAD_GATEWAY,
+        }
+    }
+}
+
+/// Where invite tokens are checked and spent.
+#[derive(Clone)]
+enum TokenBackend {
+    /// Our own token store, fed by `MATRIX_TOKEN(S)` and the admin API.
+    Local,
+    /// Synapse's registration tokens admin API.
+    Synapse { admin_token: String },
+}
+
+impl TokenBackend {
+    fn from_env() -> Result<Self, ConfigError> {
+        match env_non_empty("TOKEN_BACKEND").as_deref() {
+            None | Some("local") => Ok(Self::Local),
+            Some("synapse") => Ok(Self::Synapse {
+                admin_token: env_non_empty("SYNAPSE_ADMIN_TOKEN")
+                    .ok_or(ConfigError::Missing("SYNAPSE_ADMIN_TOKEN"))?,
+            }),
+            Some(_) => Err(ConfigError::Invalid("TOKEN_BACKEND")),
+        }
+    }
+}
+
+#[derive(Clone)]
+enum StorageConfig {
+    Sqlite(PathBuf),
+    Memory,
+}
+
+impl StorageConfig {
+    fn from_env() -> Result<Self, ConfigError> {
+        match env_non_empty("STORAGE_BACKEND").as_deref() {
+            None | Some("sqlite") => Ok(Self::Sqlite(
+                env_non_empty("DATABASE_PATH")
+                    .unwrap_or_else(|| "matrix-registration.db".to_string())
+                    .into(),
+            )),
+            Some("memory") => Ok(Self::Memory),
+            Some(_) => Err(ConfigError::InvalidStorageBackend),
+        }
+    }
+
+    fn open(&self) -> Result<Arc<dyn Storage>, StorageError> {
+        Ok(match self {
+            Self::Sqlite(path) => Arc::new(SqliteStorage::open(path)?),
+            Self::Memory => Arc::new(MemoryStorage),
+        })
+    }
 }
 
 impl AppConfig {
     fn from_env() -> Result<Self, ConfigError> {
-        let token =
-            std::env::var("MATRIX_TOKEN").map_err(|_| ConfigError::Missing("MATRIX_TOKEN"))?;
-        let server =
-            std::env::var("MATRIX_SERVER").map_err(|_| ConfigError::Missing("MATRIX_SERVER"))?;
-        let shared_secret = std::env::var("MATRIX_SHARED_SECRET")
-            .map_err(|_| ConfigError::Missing("MATRIX_SHARED_SECRET"))?;
-        let bind_addr: SocketAddr = std::env::var("BIND_ADDR")
-            .unwrap_or_else(|_| "0.0.0.0:8080".to_string())
+        let mut tokens = Vec::new();
+        if let Some(token) = env_non_empty("MATRIX_TOKEN") {
+            tokens.push((token, InviteToken::unlimited()));
+        }
+        if let Some(raw) = env_non_empty("MATRIX_TOKENS") {
+            for spec in raw.split(',').filter(|spec| !spec.trim().is_empty()) {
+                let token = parse_token_spec(spec)
+                    .ok_or_else(|| ConfigError::InvalidTokenSpec(spec.trim().to_string()))?;
+                tokens.push(token);
+            }
+        }
+        let server = env_non_empty("MATRIX_SERVER").ok_or(ConfigError::Missing("MATRIX_SERVER"))?;
+        let backend = backend_from_env()?;
+        let bind_addr: SocketAddr = env_non_empty("BIND_ADDR")
+            .unwrap_or_else(|| "0.0.0.0:8080".to_string())
             .parse()
             .map_err(|_| ConfigError::InvalidBindAddr)?;
+        let token_backend = TokenBackend::from_env()?;
+        // Without a key of its own, ids only stay the same until a restart.
+        let token_id_key = env_non_empty("TOKEN_ID_KEY").unwrap_or_else(|| {
+            rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(32)
+                .map(char::from)
+                .collect()
+        });
+        let storage = StorageConfig::from_env()?;
+        let admin_secret = env_non_empty("ADMIN_SECRET");
+        let allowlist = networks_from_env("RATE_LIMIT_ALLOWLIST")?;
+        let trusted_proxies = networks_from_env("TRUSTED_PROXIES")?;
+        let forwarded_header = match env_non_empty("FORWARDED_HEADER") {
+            Some(raw) => {
+                ForwardedHeader::parse(&raw).ok_or(ConfigError::Invalid("FORWARDED_HEADER"))?
+            }
+            None => ForwardedHeader::default(),
+        };
+        let rate_limit = rate_limit_from_env()?;
+        let availability_rate_limit = availability_rate_limit_from_env()?;
+        let password = password_policy_from_env()?;
+        let pwned = pwned_from_env()?;
+        let server_name = env_non_empty("SERVER_NAME").unwrap_or_else(|| {
+            reqwest::Url::parse(&server)
+                .ok()
+                .and_then(|url| url.host_str().map(str::to_string))
+                .unwrap_or_else(|| server.clone())
+        });
+        let provision = provision_from_env()?;
+        let email = email_from_env()?;
+        let captcha = captcha_from_env()?;
+        let status_codes = StatusCodes::from_env()?;
+        let audit = audit_from_env()?;
+        let webhooks = webhooks_from_env()?;
+        let notify = notify_from_env()?;
+        let approval = approval_from_env()?;
+        if approval.is_some() && admin_secret.is_none() {
+            // Nobody could ever approve anything.
+            return Err(ConfigError::Missing("ADMIN_SECRET"));
+        }
+        let metrics = match env_non_empty("METRICS") {
+            Some(raw) => raw.parse().map_err(|_| ConfigError::Invalid("METRICS"))?,
+            // Unauthenticated, so only served when asked for.
+            None => false,
+        };
+        let username = username_policy_from_env(&server_name)?;
+        let frontend =
+            frontend_from_env(&server_name, &password, email.is_some(), captcha.as_ref())?;
 
         Ok(Self {
-            token,
+            tokens,
+            token_backend,
+            token_id_key,
             server: server.trim_end_matches('/').to_string(),
-            shared_secret,
+            server_name,
+            username,
+            backend,
             bind_addr,
+            storage,
+            admin_secret,
+            allowlist,
+            trusted_proxies,
+            forwarded_header,
+            rate_limit,
+            availability_rate_limit,
+            password,
+            pwned,
+            frontend,
+            provision,
+            email,
+            captcha,
+            status_codes,
+            metrics,
+            audit,
+            webhooks,
+            notify,
+            approval,
         })
     }
 }
 
+fn backend_from_env() -> Result<BackendConfig, ConfigError> {
+    let required = |var: &'static str| env_non_empty(var).ok_or(ConfigError::Missing(var));
+    match env_non_empty("HOMESERVER_TYPE").as_deref() {
+        None | Some("synapse") => Ok(BackendConfig::Synapse {
+            shared_secret: required("MATRIX_SHARED_SECRET")?,
+        }),
+        Some("dendrite") => Ok(BackendConfig::Dendrite {
+            shared_secret: required("MATRIX_SHARED_SECRET")?,
+        }),
+        Some("conduit") => Ok(BackendConfig::Conduit {
+            registration_token: required("HOMESERVER_REGISTRATION_TOKEN")?,
+        }),
+        Some("mas") => Ok(BackendConfig::Mas {
+            url: required("MAS_URL")?,
+            client_id: required("MAS_CLIENT_ID")?,
+            client_secret: required("MAS_CLIENT_SECRET")?,
+        }),
+        Some(_) => Err(ConfigError::Invalid("HOMESERVER_TYPE")),
+    }
+}
+
+fn rate_limit_from_env() -> Result<RateLimitPolicy, ConfigError> {
+    let mut policy = RateLimitPolicy::default();
+    limits_from_env(
+        &mut policy,
+        "RATE_LIMIT_MAX_ATTEMPTS",
+        "RATE_LIMIT_WINDOW_SECS",
+    )?;
+    if let Some(raw) = env_non_empty("RATE_LIMIT_COUNT_SUCCESS") {
+        policy.count_successful = raw
+            .parse()
+            .map_err(|_| ConfigError::Invalid("RATE_LIMIT_COUNT_SUCCESS"))?;
+    }
+    Ok(policy)
+}
+
+fn availability_rate_limit_from_env() -> Result<RateLimitPolicy, ConfigError> {
+    let mut policy = RateLimitPolicy {
+        max_attempts: 20,
+        window: chrono::Duration::minutes(10),
+        count_successful: true,
+    };
+    limits_from_env(
+        &mut policy,
+        "AVAILABILITY_RATE_LIMIT_MAX_ATTEMPTS",
+        "AVAILABILITY_RATE_LIMIT_WINDOW_SECS",
+    )?;
+    Ok(policy)
+}
+
+fn limits_from_env(
+    policy: &mut RateLimitPolicy,
+    max_var: &'static str,
+    window_var: &'static str,
+) -> Result<(), ConfigError> {
+    if let Some(raw) = env_non_empty(max_var) {
+        policy.max_attempts = raw
+            .parse()
+            .ok()
+            .filter(|&max| max > 0)
+            .ok_or(ConfigError::Invalid(max_var))?;
+    }
+    if let Some(raw) = env_non_empty(window_var) {
+        let secs: i64 = raw
+            .parse()
+            .ok()
+            .filter(|&secs| secs > 0)
+            .ok_or(ConfigError::Invalid(window_var))?;
+        policy.window = chrono::Duration::seconds(secs);
+    }
+    Ok(())
+}
+
+/// Reserved names replace the built-in list when `RESERVED_USERNAMES` is set;
+/// patterns are whitespace-separated since commas are common in regexes.
+fn username_policy_from_env(server_name: &str) -> Result<UsernamePolicy, ConfigError> {
+    let mut policy = UsernamePolicy::new(server_name);
+    // Set but empty reserves nothing.
+    if let Ok(raw) = std::env::var("RESERVED_USERNAMES") {
+        policy.reserved = raw
+            .split(',')
+            .map(|name| name.trim().to_lowercase())
+            .filter(|name| !name.is_empty())
+            .collect();
+    }
+    if let Some(raw) = env_non_empty("RESERVED_USERNAME_PATTERNS") {
+        policy.reserved_patterns = raw
+            .split_whitespace()
+            .map(|pattern| {
+                Regex::new(pattern).map_err(|_| ConfigError::Invalid("RESERVED_USERNAME_PATTERNS"))
+            })
+            .collect::<Result<_, _>>()?;
+    }
+    Ok(policy)
+}
+
+fn password_policy_from_env() -> Result<PasswordPolicy, ConfigError> {
+    let mut policy = PasswordPolicy::default();
+    if let Some(raw) = env_non_empty("PASSWORD_MIN_LENGTH") {
+        policy.min_length = raw
+            .parse()
+            .ok()
+            .filter(|&min| min > 0)
+            .ok_or(ConfigError::Invalid("PASSWORD_MIN_LENGTH"))?;
+    }
+    if let Some(raw) = env_non_empty("PASSWORD_MAX_LENGTH") {
+        policy.max_length = Some(
+            raw.parse()
+                .ok()
+                .filter(|&max| max >= policy.min_length)
+                .ok_or(ConfigError::Invalid("PASSWORD_MAX_LENGTH"))?,
+        );
+    }
+    for (var, rule) in [
+        ("PASSWORD_REQUIRE_DIGIT", &mut policy.require_digit),
+        ("PASSWORD_REQUIRE_UPPERCASE", &mut policy.require_uppercase),
+        ("PASSWORD_REQUIRE_LOWERCASE", &mut policy.require_lowercase),
+        ("PASSWORD_REQUIRE_SYMBOL", &mut policy.require_symbol),
+        ("PASSWORD_REJECT_USERNAME", &mut policy.reject_username),
+    ] {
+        if let Some(raw) = env_non_empty(var) {
+            *rule = raw.parse().map_err(|_| ConfigError::Invalid(var))?;
+        }
+    }
+    if let Some(raw) = env_non_empty("PASSWORD_MIN_SCORE") {
+        policy.min_score = Some(
+            raw.parse()
+                .ok()
+                .filter(|&score| score <= 4)
+                .ok_or(ConfigError::Invalid("PASSWORD_MIN_SCORE"))?,
+        );
+    }
+    Ok(policy)
+}
+
+fn pwned_from_env() -> Result<Option<PwnedPasswords>, ConfigError> {
+    match env_non_empty("PWNED_PASSWORDS").as_deref() {
+        None | Some("off") => Ok(None),
+        Some("api") => Ok(Some(PwnedPasswords::Api {
+            base_url: env_non_empty("PWNED_PASSWORDS_URL")
+                .unwrap_or_else(|| "https://api.pwnedpasswords.com".to_string()),
+        })),
+        Some("file") => Ok(Some(PwnedPasswords::HashFile(
+            env_non_empty("PWNED_PASSWORDS_FILE")
+                .ok_or(ConfigError::Missing("PWNED_PASSWORDS_FILE"))?
+                .into(),
+        ))),
+        Some(_) => Err(ConfigError::Invalid("PWNED_PASSWORDS")),
+    }
+}
+
+fn frontend_from_env(
+    server_name: &str,
+    password: &PasswordPolicy,
+    email: bool,
+    captcha: Option<&CaptchaConfig>,
+) -> Result<Option<FrontendConfig>, ConfigError> {
+    let source = match env_non_empty("SERVE_STATIC").as_deref() {
+        None | Some("off") => return Ok(None),
+        Some("disk") => StaticSource::Disk(
+            env_non_empty("STATIC_DIR")
+                .unwrap_or_else(|| "static".to_string())
+                .into(),
+        ),
+        #[cfg(feature = "embed-static")]
+        Some("embedded") => StaticSource::Embedded,
+        Some(_) => return Err(ConfigError::Invalid("SERVE_STATIC")),
+    };
+    Ok(Some(FrontendConfig {
+        source,
+        vars: vec![
+            ("pw_length".to_string(), password.min_length.to_string()),
+            ("server_name".to_string(), server_name.to_string()),
+            (
+                "email_hidden".to_string(),
+                if email { "" } else { "hidden" }.to_string(),
+            ),
+            (
+                "captcha_provider".to_string(),
+                captcha.map_or("", CaptchaConfig::kind).to_string(),
+            ),
+            (
+                "captcha_site_key".to_string(),
+                captcha.map_or("", CaptchaConfig::site_key).to_string(),
+            ),
+        ],
+    }))
+}
+
+/// Provisioning is on as soon as any step is configured; it then needs a
+/// Synapse admin token.
+fn provision_from_env() -> Result<Option<ProvisionConfig>, ConfigError> {
+    let set_display_name = match env_non_empty("PROVISION_DISPLAY_NAME") {
+        Some(raw) => raw
+            .parse()
+            .map_err(|_| ConfigError::Invalid("PROVISION_DISPLAY_NAME"))?,
+        None => false,
+    };
+    let default_avatar = env_non_empty("PROVISION_DEFAULT_AVATAR");
+    let auto_join: Vec<String> = env_non_empty("PROVISION_AUTO_JOIN")
+        .map(|raw| {
+            raw.split(',')
+                .map(str::trim)
+                .filter(|room| !room.is_empty())
+                .map(str::to_string)
+                .collect()
+        })
+        .unwrap_or_default();
+    let welcome = match env_non_empty("PROVISION_WELCOME_MESSAGE") {
+        Some(body) => Some(WelcomeMessage {
+            bot_token: env_non_empty("PROVISION_BOT_TOKEN")
+                .ok_or(ConfigError::Missing("PROVISION_BOT_TOKEN"))?,
+            body,
+        }),
+        None => None,
+    };
+
+    if !set_display_name && default_avatar.is_none() && auto_join.is_empty() && welcome.is_none() {
+        return Ok(None);
+    }
+    Ok(Some(ProvisionConfig {
+        admin_token: env_non_empty("SYNAPSE_ADMIN_TOKEN")
+            .ok_or(ConfigError::Missing("SYNAPSE_ADMIN_TOKEN"))?,
+        set_display_name,
+        default_avatar,
+        auto_join,
+        welcome,
+    }))
+}
+
+/// Email verification is on when `SMTP_HOST` is set.
+fn email_from_env() -> Result<Option<EmailConfig>, ConfigError> {
+    let Some(host) = env_non_empty("SMTP_HOST") else {
+        return Ok(None);
+    };
+
+    let builder = match env_non_empty("SMTP_TLS").as_deref() {
+        None | Some("starttls") => AsyncSmtpTransport::<Tokio1Executor>::starttls_relay(&host),
+        Some("tls") => AsyncSmtpTransport::<Tokio1Executor>::relay(&host),
+        Some("off") => Ok(AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous(
+            &host,
+        )),
+        Some(_) => return Err(ConfigError::Invalid("SMTP_TLS")),
+    };
+    let mut builder = builder.map_err(|_| ConfigError::Invalid("SMTP_HOST"))?;
+    if let Some(port) = env_non_empty("SMTP_PORT") {
+        builder = builder.port(
+            port.parse()
+                .map_err(|_| ConfigError::Invalid("SMTP_PORT"))?,
+        );
+    }
+    if let Some(username) = env_non_empty("SMTP_USERNAME") {
+        let password =
+            env_non_empty("SMTP_PASSWORD").ok_or(ConfigError::Missing("SMTP_PASSWORD"))?;
+        builder = builder.credentials(Credentials::new(username, password));
+    }
+
+    let ttl = match env_non_empty("EMAIL_VERIFICATION_TTL_SECS") {
+        Some(raw) => raw
+            .parse()
+            .ok()
+            .filter(|secs| *secs > 0)
+            .map(chrono::Duration::seconds)
+            .ok_or(ConfigError::Invalid("EMAIL_VERIFICATION_TTL_SECS"))?,
+        None => chrono::Duration::hours(24),
+    };
+
+    Ok(Some(EmailConfig {
+        transport: builder.build(),
+        from: env_non_empty("SMTP_FROM")
+            .ok_or(ConfigError::Missing("SMTP_FROM"))?
+            .parse()
+            .map_err(|_| ConfigError::Invalid("SMTP_FROM"))?,
+        public_url: env_non_empty("PUBLIC_URL").ok_or(ConfigError::Missing("PUBLIC_URL"))?,
+        ttl,
+        admin_token: env_non_empty("SYNAPSE_ADMIN_TOKEN")
+            .ok_or(ConfigError::Missing("SYNAPSE_ADMIN_TOKEN"))?,
+    }))
+}
+
+fn captcha_from_env() -> Result<Option<CaptchaConfig>, ConfigError> {
+    let provider = match env_non_empty("CAPTCHA_PROVIDER").as_deref() {
+        None | Some("off") => return Ok(None),
+        Some("hcaptcha") => CaptchaProvider::HCaptcha,
+        Some("turnstile") => CaptchaProvider::Turnstile,
+        Some("recaptcha") => CaptchaProvider::ReCaptcha,
+        Some("pow") => {
+            let difficulty = match env_non_empty("CAPTCHA_POW_DIFFICULTY") {
+                Some(raw) => raw
+                    .parse()
+                    .ok()
+                    .filter(|bits| (1..=32).contains(bits))
+                    .ok_or(ConfigError::Invalid("CAPTCHA_POW_DIFFICULTY"))?,
+                None => 16,
+            };
+            return Ok(Some(CaptchaConfig::ProofOfWork { difficulty }));
+        }
+        Some(_) => return Err(ConfigError::Invalid("CAPTCHA_PROVIDER")),
+    };
+    Ok(Some(CaptchaConfig::Remote {
+        provider,
+        site_key: env_non_empty("CAPTCHA_SITE_KEY")
+            .ok_or(ConfigError::Missing("CAPTCHA_SITE_KEY"))?,
+        secret: env_non_empty("CAPTCHA_SECRET").ok_or(ConfigError::Missing("CAPTCHA_SECRET"))?,
+        verify_url: env_non_empty("CAPTCHA_VERIFY_URL")
+            .unwrap_or_else(|| provider.default_verify_url().to_string()),
+    }))
+}
+
+fn audit_from_env() -> Result<Option<AuditConfig>, ConfigError> {
+    let Some(path) = env_non_empty("AUDIT_LOG_PATH") else {
+        return Ok(None);
+    };
+    let max_bytes = match env_non_empty("AUDIT_LOG_MAX_BYTES") {
+        Some(raw) => raw
+            .parse()
+            .ok()
+            .filter(|&max| max > 0)
+            .ok_or(ConfigError::Invalid("AUDIT_LOG_MAX_BYTES"))?,
+        None => 10 * 1024 * 1024,
+    };
+    let retention_days = match env_non_empty("AUDIT_LOG_RETENTION_DAYS") {
+        Some(raw) => raw
+            .parse()
+            .map_err(|_| ConfigError::Invalid("AUDIT_LOG_RETENTION_DAYS"))?,
+        None => 90,
+    };
+    Ok(Some(AuditConfig {
+        path: path.into(),
+        max_bytes,
+        retention: chrono::Duration::days(retention_days),
+    }))
+}
+
+/// Room notifications are on when `NOTIFY_ROOM_ID` is set.
+fn notify_from_env() -> Result<Option<NotifyConfig>, ConfigError> {
+    let Some(room_id) = env_non_empty("NOTIFY_ROOM_ID") else {
+        return Ok(None);
+    };
+    // The send API only takes room IDs, not aliases.
+    if !room_id.starts_with('!') {
+        return Err(ConfigError::Invalid("NOTIFY_ROOM_ID"));
+    }
+    Ok(Some(NotifyConfig {
+        bot_token: env_non_empty("NOTIFY_BOT_TOKEN")
+            .ok_or(ConfigError::Missing("NOTIFY_BOT_TOKEN"))?,
+        room_id,
+    }))
+}
+
+/// Approval is on when `REQUIRE_APPROVAL` is true; queued passwords are then
+/// encrypted with `APPROVAL_KEY`, 32 bytes in hex.
+fn approval_from_env() -> Result<Option<ApprovalConfig>, ConfigError> {
+    let required = match env_non_empty("REQUIRE_APPROVAL") {
+        Some(raw) => raw
+            .parse()
+            .map_err(|_| ConfigError::Invalid("REQUIRE_APPROVAL"))?,
+        None => false,
+    };
+    if !required {
+        return Ok(None);
+    }
+    let raw = env_non_empty("APPROVAL_KEY").ok_or(ConfigError::Missing("APPROVAL_KEY"))?;
+    let mut key = [0; 32];
+    hex::decode_to_slice(raw.trim(), &mut key).map_err(|_| ConfigError::Invalid("APPROVAL_KEY"))?;
+    Ok(Some(ApprovalConfig { key }))
+}
+
+/// Webhooks are on when `WEBHOOK_URLS` is set.
+fn webhooks_from_env() -> Result<Option<WebhookConfig>, ConfigError> {
+    let urls: Vec<String> = env_non_empty("WEBHOOK_URLS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|url| !url.is_empty())
+        .map(str::to_string)
+        .collect();
+    if urls.is_empty() {
+        return Ok(None);
+    }
+    let secret = env_non_empty("WEBHOOK_SECRET").ok_or(ConfigError::Missing("WEBHOOK_SECRET"))?;
+    let events = match env_non_empty("WEBHOOK_EVENTS") {
+        Some(raw) => raw
+            .split(',')
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(|name| {
+                serde_json::from_value(serde_json::Value::String(name.to_string()))
+                    .map_err(|_| ConfigError::Invalid("WEBHOOK_EVENTS"))
+            })
+            .collect::<Result<_, _>>()?,
+        None => vec![
+            RegistrationState::Registered,
+            RegistrationState::Blocked,
+            RegistrationState::InvalidToken,
+        ],
+    };
+    let max_attempts = match env_non_empty("WEBHOOK_MAX_ATTEMPTS") {
+        Some(raw) => raw
+            .parse()
+            .ok()
+            .filter(|&max| max > 0)
+            .ok_or(ConfigError::Invalid("WEBHOOK_MAX_ATTEMPTS"))?,
+        None => 5,
+    };
+    let retry_delay = match env_non_empty("WEBHOOK_RETRY_DELAY_SECS") {
+        Some(raw) => raw
+            .parse()
+            .map_err(|_| ConfigError::Invalid("WEBHOOK_RETRY_DELAY_SECS"))?,
+        None => 2,
+    };
+    Ok(Some(WebhookConfig {
+        urls,
+        secret,
+        events,
+        max_attempts,
+        retry_delay: std::time::Duration::from_secs(retry_delay),
+        dead_letter: env_non_empty("WEBHOOK_DEAD_LETTER_PATH").map(PathBuf::from),
+    }))
+}
+
+/// Reads `var`, treating an empty value the same as an unset one.
+fn env_non_empty(var: &str) -> Option<String> {
+    std::env::var(var).ok().filter(|value| !value.is_empty())
+}
+
+/// Reads a comma-separated list of IPs and CIDRs from `var`; a bare IP is
+/// taken as a single-host network.
+fn networks_from_env(var: &'static str) -> Result<Vec<IpNet>, ConfigError> {
+    let Some(raw) = env_non_empty(var) else {
+        return Ok(Vec::new());
+    };
+    raw.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| ConfigError::InvalidNetwork(var, entry.to_string()))
+        })
+        .collect()
+}
+
 #[derive(Debug, Error)]
 enum ConfigError {
     #[error("missing required env var {0}")]
     Missing(&'static str),
+    #[error("invalid value for env var {0}")]
+    Invalid(&'static str),
     #[error("invalid BIND_ADDR; expected host:port")]
     InvalidBindAddr,
+    #[error("invalid MATRIX_TOKENS entry {0:?}; expected token[:max_uses[:rfc3339_expiry]]")]
+    InvalidTokenSpec(String),
+    #[error("invalid STORAGE_BACKEND; expected sqlite or memory")]
+    InvalidStorageBackend,
+    #[error("invalid {0} entry {1:?}; expected an IP or CIDR")]
+    InvalidNetwork(&'static str, String),
+}
+
+/// Why [`AppState::new`] couldn't build the state.
+#[derive(Debug, Error)]
+enum StateError {
+    #[error(transparent)]
+    Storage(#[from] StorageError),
+    #[error("failed to open audit log: {0}")]
+    Audit(#[from] std::io::Error),
 }
 
 #[derive(Clone)]
 struct AppState {
     config: AppConfig,
     attempts: Attempts,
+    /// Only kept in memory; losing them on restart costs nothing.
+    availability_checks: Attempts,
+    tokens: Arc<TokenStore>,
+    synapse_tokens: Option<Arc<SynapseTokens>>,
+    storage: Arc<dyn Storage>,
+    backend: Arc<dyn RegistrationBackend>,
+    provisioner: Option<Arc<Provisioner>>,
+    email: Option<Arc<EmailVerification>>,
+    captcha: Option<Arc<Captcha>>,
+    metrics: Arc<Metrics>,
+    audit: Option<Arc<AuditLog>>,
+    webhooks: Option<Arc<Webhooks>>,
+    notifier: Option<Notifier>,
+    approvals: Option<Arc<ApprovalQueue>>,
     client: Client,
 }
 
-type Attempts = std::sync::Arc<DashMap<IpAddr, Attempt>>;
-
-#[derive(Clone, Debug)]
-struct Attempt {
-    count: u32,
-    last: DateTime<Utc>,
-}
+type Attempts = Arc<DashMap<IpAddr, Attempt>>;
 
 impl AppState {
-    fn new(config: AppConfig) -> Self {
+    /// Builds the state from whatever `storage` remembers. Tokens from the
+    /// config keep their stored usage counts across restarts but take their
+    /// limits from the config, and revoked ones stay revoked.
+    fn new(config: AppConfig, storage: Arc<dyn Storage>) -> Result<Self, StateError> {
+        let audit = match &config.audit {
+            Some(audit) => Some(Arc::new(AuditLog::open(audit.clone())?)),
+            None => None,
+        };
         let client = Client::builder().build().expect("reqwest client");
-        Self {
+        let attempts: DashMap<IpAddr, Attempt> = storage.load_attempts()?.into_iter().collect();
+
+        let mut tokens = storage.load_tokens()?;
+        let revoked = storage.load_revoked_tokens()?;
+        for (token, invite) in &config.tokens {
+            if revoked.contains(token) {
+                continue;
+            }
+            match tokens.iter_mut().find(|(known, _)| known == token) {
+                Some((_, stored)) => {
+                    if (stored.max_uses, stored.expires_at) != (invite.max_uses, invite.expires_at)
+                    {
+                        info!(
+                            "invite token {} takes its limits from the config again",
+                            token_id(&config.token_id_key, token)
+                        );
+                        stored.max_uses = invite.max_uses;
+                        stored.expires_at = invite.expires_at;
+                        storage.save_token(token, stored)?;
+                    }
+                }
+                None => {
+                    storage.save_token(token, invite)?;
+                    tokens.push((token.clone(), invite.clone()));
+                }
+            }
+        }
+        let tokens = TokenStore::new(tokens);
+        let synapse_tokens = match &config.token_backend {
+            TokenBackend::Local => {
+                if tokens.is_empty() {
+                    warn!("no invite tokens configured; every registration will be rejected");
+                }
+                None
+            }
+            TokenBackend::Synapse { admin_token } => Some(Arc::new(SynapseTokens::new(
+                &config.server,
+                admin_token.clone(),
+            ))),
+        };
+
+        let metrics = Arc::new(Metrics::default());
+        let backend = config
+            .backend
+            .build(&config.server, client.clone(), metrics.clone());
+        let provisioner = config
+            .provision
+            .clone()
+            .map(|provision| Arc::new(Provisioner::new(&config.server, provision, client.clone())));
+        let email = config.email.clone().map(|email| {
+            Arc::new(EmailVerification::new(
+                &config.server,
+                email,
+                client.clone(),
+            ))
+        });
+
+        let captcha = config
+            .captcha
+            .clone()
+            .map(|captcha| Arc::new(Captcha::new(captcha)));
+        let webhooks = config
+            .webhooks
+            .clone()
+            .map(|webhooks| Arc::new(Webhooks::new(webhooks, client.clone())));
+        let notifier = config
+            .notify
+            .clone()
+            .map(|notify| Notifier::new(&config.server, notify, client.clone()));
+        let approvals = match &config.approval {
+            Some(approval) => Some(Arc::new(ApprovalQueue::new(
+                approval,
+                storage.load_applications()?,
+            ))),
+            None => None,
+        };
+
+        Ok(Self {
             config,
-            attempts: std::sync::Arc::new(DashMap::new()),
+            attempts: Arc::new(attempts),
+            availability_checks: Attempts::default(),
+            tokens: Arc::new(tokens),
+            synapse_tokens,
+            storage,
+            backend,
+            provisioner,
+            email,
+            captcha,
+            metrics,
+            audit,
+            webhooks,
+            notifier,
+            approvals,
             client,
+        })
+    }
+
+    fn client_ip(&self, headers: &HeaderMap, peer: IpAddr) -> IpAddr {
+        client_ip(
+            headers,
+            peer,
+            &self.config.trusted_proxies,
+            self.config.forwarded_header,
+        )
+    }
+
+    fn is_allowlisted(&self, ip: IpAddr) -> bool {
+        self.config.allowlist.iter().any(|net| net.contains(&ip))
+    }
+
+    /// How much longer `ip` is blocked from registering, if it is.
+    fn blocked_for(&self, ip: IpAddr) -> Option<chrono::Duration> {
+        if self.is_allowlisted(ip) {
+            return None;
+        }
+        let policy = &self.config.rate_limit;
+        let now = Utc::now();
+        let mut entry = self.attempts.get_mut(&ip)?;
+        if entry.prune(policy, now) {
+            if entry.hits().is_empty() {
+                drop(entry);
+                self.attempts
+                    .remove_if(&ip, |_, attempt| attempt.hits().is_empty());
+                self.forget_attempt(ip);
+                return None;
+            }
+            self.persist_attempt(ip, &entry);
         }
+        entry.blocked_for(policy, now)
     }
 
-    fn too_many_requests(&self, ip: IpAddr) -> bool {
-        if let Some(mut entry) = self.attempts.get_mut(&ip) {
-            let elapsed = Utc::now() - entry.last;
-            if elapsed > chrono::Duration::hours(24) {
-                entry.count = 0;
-                entry.last = Utc::now();
-                return false;
+    /// Counts an availability check from `ip`; fails with the time left once
+    /// it has used up its budget, or is blocked from registering anyway.
+    fn allow_availability_check(&self, ip: IpAddr) -> Result<(), chrono::Duration> {
+        if self.is_allowlisted(ip) {
+            return Ok(());
+        }
+        if let Some(wait) = self.blocked_for(ip) {
+            return Err(wait);
+        }
+        let policy = &self.config.availability_rate_limit;
+        let now = Utc::now();
+        // Nothing else removes entries here, so idle IPs are swept out once
+        // the map gets big.
+        if self.availability_checks.len() > 10_000 {
+            self.availability_checks.retain(|_, attempt| {
+                attempt.prune(policy, now);
+                !attempt.hits().is_empty()
+            });
+        }
+        let mut entry = self.availability_checks.entry(ip).or_default();
+        entry.prune(policy, now);
+        if let Some(wait) = entry.blocked_for(policy, now) {
+            return Err(wait);
+        }
+        entry.record(policy, now);
+        Ok(())
+    }
+
+    /// Audits `outcome` and turns it into the response.
+    fn answer(
+        &self,
+        outcome: Outcome,
+        client_ip: IpAddr,
+        headers: &HeaderMap,
+        token: &str,
+    ) -> Response {
+        let user_agent = headers
+            .get(header::USER_AGENT)
+            .and_then(|value| value.to_str().ok());
+        self.audit(&outcome, client_ip, user_agent, token);
+
+        let response = self.respond(&outcome);
+        match outcome.retry_after {
+            Some(wait) => blocked(response, wait),
+            None => response,
+        }
+    }
+
+    /// Logs `outcome` and hands it to the audit log and the webhooks.
+    fn audit(&self, outcome: &Outcome, client_ip: IpAddr, user_agent: Option<&str>, token: &str) {
+        let event = AuditEvent {
+            timestamp: Utc::now(),
+            client_ip,
+            username: outcome.username.clone(),
+            outcome: outcome.state,
+            token_id: (!token.is_empty()).then(|| self.token_id(token)),
+            user_agent: user_agent.map(str::to_string),
+            upstream_ms: outcome.upstream.map(|upstream| upstream.as_millis() as u64),
+        };
+        info!(
+            target: "audit",
+            client_ip = %event.client_ip,
+            username = %event.username,
+            outcome = ?event.outcome,
+            token_id = event.token_id.as_deref(),
+            user_agent = event.user_agent.as_deref(),
+            upstream_ms = event.upstream_ms,
+            "registration attempt"
+        );
+        if let Some(audit) = &self.audit {
+            if let Err(err) = audit.write(&event) {
+                error!("failed to write audit log: {err}");
             }
-            return entry.count >= 3;
         }
-        false
+        if let Some(webhooks) = &self.webhooks {
+            let user_id = (outcome.state == RegistrationState::Registered)
+                .then(|| self.user_id(&outcome.username));
+            webhooks.dispatch(event, user_id);
+        }
+    }
+
+    /// Answers with `outcome` under the configured status codes.
+    fn respond(&self, outcome: &Outcome) -> Response {
+        if let Ok(serde_json::Value::String(name)) = serde_json::to_value(outcome.state) {
+            self.metrics.record_outcome(&name);
+        }
+        let (status, Json(mut body)) = response(
+            outcome.status(self.config.status_codes),
+            outcome.state,
+            &outcome.username,
+        );
+        body.status_url = outcome.application.as_deref().map(status_url);
+        (status, Json(body)).into_response()
     }
 
     fn record_attempt(&self, ip: IpAddr) {
+        if self.is_allowlisted(ip) {
+            return;
+        }
+        let policy = &self.config.rate_limit;
         let now = Utc::now();
-        self.attempts
-            .entry(ip)
-            .and_modify(|attempt| {
-                attempt.count += 1;
-                attempt.last = now;
-            })
-            .or_insert(Attempt {
-                count: 1,
-                last: now,
-            });
+        let (attempt, was_blocked) = {
+            let mut entry = self.attempts.entry(ip).or_default();
+            let was_blocked = entry.blocked_for(policy, now).is_some();
+            entry.record(policy, now);
+            (entry.clone(), was_blocked)
+        };
+        self.persist_attempt(ip, &attempt);
+        if let (Some(notifier), false, Some(wait)) = (
+            &self.notifier,
+            was_blocked,
+            attempt.blocked_for(policy, now),
+        ) {
+            notifier.blocked(ip, attempt.count(policy, now), now + wait);
+        }
     }
 
-    fn is_token_ok(&self, token: &str) -> bool {
-        self.config.token == token
+    fn persist_attempt(&self, ip: IpAddr, attempt: &Attempt) {
+        if let Err(err) = self.storage.save_attempt(ip, attempt) {
+            error!("failed to persist attempt for {ip}: {err}");
+        }
     }
 
-    async fn register_user(&self, username: &str, password: &str) -> Result<(), RegisterError> {
-        let nonce = self.fetch_nonce().await?;
-        let mac = calculate_mac(&nonce, username, password, &self.config.shared_secret);
-        let body = RegisterUserRequest {
-            nonce,
-            username,
-            password,
-            admin: false,
-            mac,
+    fn forget_attempt(&self, ip: IpAddr) {
+        if let Err(err) = self.storage.delete_attempt(ip) {
+            error!("failed to forget attempts for {ip}: {err}");
+        }
+    }
+
+    async fn consume_token(&self, token: &str) -> Result<(), TokenError> {
+        if let Some(synapse_tokens) = &self.synapse_tokens {
+            return synapse_tokens.consume(&self.client, token).await;
+        }
+        let invite = self.tokens.consume(token)?;
+        self.persist_token(token, &invite);
+        Ok(())
+    }
+
+    async fn check_token(&self, token: &str) -> Result<(), TokenError> {
+        if let Some(synapse_tokens) = &self.synapse_tokens {
+            return synapse_tokens.check(&self.client, token).await;
+        }
+        self.tokens.check(token)
+    }
+
+    async fn refund_token(&self, token: &str) {
+        if let Some(synapse_tokens) = &self.synapse_tokens {
+            if let Err(err) = synapse_tokens.refund(&self.client, token).await {
+                error!("failed to refund Synapse registration token: {err}");
+            }
+            return;
+        }
+        if let Some(invite) = self.tokens.refund(token) {
+            self.persist_token(token, &invite);
+        }
+    }
+
+    fn persist_token(&self, token: &str, invite: &InviteToken) {
+        if let Err(err) = self.storage.save_token(token, invite) {
+            error!("failed to persist token usage: {err}");
+        }
+    }
+
+    fn record_registration(&self, username: &str, token: &str, client_ip: IpAddr) {
+        let registration = Registration {
+            username: username.to_string(),
+            registered_at: Utc::now(),
+            token: token.to_string(),
+            client_ip,
         };
+        if let Err(err) = self.storage.record_registration(&registration) {
+            error!("failed to record registration of {username}: {err}");
+        }
+    }
 
-        let url = format!("{}/_synapse/admin/v1/register", self.config.server);
-        let response = self
-            .client
-            .post(url)
-            .json(&body)
-            .send()
-            .await
-            .map_err(RegisterError::Upstream)?;
-
-        match response.status() {
-            ReqStatusCode::OK => Ok(()),
-            ReqStatusCode::BAD_REQUEST => Err(RegisterError::UserExists),
-            status => {
-                let text = response.text().await.unwrap_or_default();
-                Err(RegisterError::UnexpectedStatus(status, text))
+    async fn register_user(&self, username: &str, password: &str) -> Result<(), RegisterError> {
+        self.backend.register_user(username, password).await
+    }
+
+    /// Creates the account for `signup` and runs everything that follows it.
+    /// The token use is given back if the account can't be created.
+    async fn create_account(
+        &self,
+        signup: &Signup,
+        email: Option<&Address>,
+    ) -> Result<(), RegisterError> {
+        let result = self.register_user(&signup.username, &signup.password).await;
+        if result.is_err() || self.config.rate_limit.count_successful {
+            self.record_attempt(signup.client_ip);
+        }
+        if result.is_err() {
+            self.refund_token(&signup.token).await;
+            return result;
+        }
+        self.finish_account(signup, email).await;
+        Ok(())
+    }
+
+    /// Everything that follows once the homeserver has created the account.
+    async fn finish_account(&self, signup: &Signup, email: Option<&Address>) {
+        self.record_registration(&signup.username, &signup.token, signup.client_ip);
+        let user_id = self.user_id(&signup.username);
+        if let Some(notifier) = &self.notifier {
+            notifier.registered(&user_id, &self.token_id(&signup.token), signup.client_ip);
+        }
+        if let (Some(verification), Some(email)) = (&self.email, email) {
+            if let Err(err) = verification.bind(&user_id, email).await {
+                error!("failed to bind {email} to {user_id}: {err}");
             }
         }
+        self.provision_user(&signup.username, signup.display_name.as_deref())
+            .await;
     }
 
-    async fn fetch_nonce(&self) -> Result<String, RegisterError> {
-        let url = format!("{}/_synapse/admin/v1/register", self.config.server);
-        let response = self
-            .client
-            .get(url)
-            .send()
-            .await
-            .map_err(RegisterError::Upstream)?
-            .error_for_status()
-            .map_err(|e| {
-                RegisterError::UnexpectedStatus(
-                    e.status().unwrap_or(ReqStatusCode::INTERNAL_SERVER_ERROR),
-                    e.to_string(),
-                )
-            })?;
+    /// Puts `signup` in the approval queue instead of creating the account.
+    /// The token use stays taken until an admin decides.
+    async fn queue_for_approval(
+        &self,
+        approvals: &ApprovalQueue,
+        signup: &Signup,
+        email: Option<&Address>,
+    ) -> Outcome {
+        let application = match approvals.submit(signup, email) {
+            Ok(application) => application,
+            Err(err) => {
+                error!("failed to queue {} for approval: {err}", signup.username);
+                self.refund_token(&signup.token).await;
+                return Outcome::new(RegistrationState::InternalError, &signup.username);
+            }
+        };
+        self.persist_application(&application);
+        if let Some(notifier) = &self.notifier {
+            notifier.awaiting_approval(
+                &self.user_id(&signup.username),
+                &application.id,
+                signup.client_ip,
+            );
+        }
+        Outcome {
+            application: Some(application.id),
+            ..Outcome::new(RegistrationState::PendingApproval, &signup.username)
+        }
+    }
 
-        let payload: NonceResponse = response.json().await.map_err(RegisterError::Upstream)?;
-        Ok(payload.nonce)
+    fn persist_application(&self, application: &Application) {
+        if let Err(err) = self.storage.save_application(application) {
+            error!(
+                "failed to persist the application of {}: {err}",
+                application.username
+            );
+        }
     }
-}
 
-#[derive(Debug, Error)]
-enum RegisterError {
-    #[error("user exists")]
-    UserExists,
-    #[error("upstream error: {0}")]
-    Upstream(#[from] reqwest::Error),
-    #[error("unexpected upstream status {0}: {1}")]
-    UnexpectedStatus(ReqStatusCode, String),
+    fn token_id(&self, token: &str) -> String {
+        token_id(&self.config.token_id_key, token)
+    }
+
+    fn user_id(&self, username: &str) -> String {
+        format!("@{username}:{}", self.config.server_name)
+    }
+
+    async fn provision_user(&self, username: &str, display_name: Option<&str>) {
+        if let Some(provisioner) = &self.provisioner {
+            provisioner
+                .provision(&self.user_id(username), display_name)
+                .await;
+        }
+    }
 }
 
 #[derive(Deserialize)]
@@ -185,169 +1194,815 @@ struct RegisterForm {
     #[serde(rename = "passwordConfirmation", default)]
     password_confirmation: String,
     token: String,
+    #[serde(rename = "displayName", default)]
+    display_name: Option<String>,
+    #[serde(default)]
+    email: Option<String>,
+    #[serde(default)]
+    captcha: String,
 }
 
-#[derive(Serialize)]
+/// A validated registration whose invite token use has been taken.
+#[derive(Clone)]
+struct Signup {
+    username: String,
+    password: String,
+    token: String,
+    client_ip: IpAddr,
+    display_name: Option<String>,
+}
+
+/// How a registration attempt ended, before it is audited and answered.
+struct Outcome {
+    state: RegistrationState,
+    username: String,
+    /// How long a `BLOCKED` IP has to wait.
+    retry_after: Option<chrono::Duration>,
+    /// Time spent creating the account on the homeserver.
+    upstream: Option<std::time::Duration>,
+    /// The `INTERNAL_ERROR` came from the homeserver or another service we
+    /// called rather than from this one.
+    failed_upstream: bool,
+    /// Id of the queued application for `PENDING_APPROVAL`.
+    application: Option<String>,
+}
+
+impl Outcome {
+    fn new(state: RegistrationState, username: &str) -> Self {
+        Self {
+            state,
+            username: username.to_string(),
+            retry_after: None,
+            upstream: None,
+            failed_upstream: false,
+            application: None,
+        }
+    }
+
+    /// An `INTERNAL_ERROR` caused by a service we called.
+    fn upstream_failure(username: &str) -> Self {
+        Self {
+            failed_upstream: true,
+            ..Self::new(RegistrationState::InternalError, username)
+        }
+    }
+
+    /// How creating the account on the homeserver went, which took
+    /// `elapsed`.
+    fn account(
+        result: Result<(), RegisterError>,
+        username: &str,
+        elapsed: std::time::Duration,
+    ) -> Self {
+        let state = account_outcome(result, username);
+        Self {
+            upstream: Some(elapsed),
+            failed_upstream: state == RegistrationState::InternalError,
+            ..Self::new(state, username)
+        }
+    }
+
+    fn status(&self, mode: StatusCodes) -> StatusCode {
+        if self.failed_upstream {
+            mode.upstream_failure()
+        } else {
+            self.state.status(mode)
+        }
+    }
+}
+
+/// A registration posted as JSON or as a urlencoded form, told apart by
+/// `Content-Type`.
+struct RegisterBody(RegisterForm);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequest<S> for RegisterBody {
+    type Rejection = (StatusCode, Json<RegistrationResponse>);
+
+    async fn from_request(req: Request, state: &S) -> Result<Self, Self::Rejection> {
+        let mime = req
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.split(';').next())
+            .unwrap_or_default()
+            .trim()
+            .to_ascii_lowercase();
+
+        let result = if mime == "application/json" || mime.ends_with("+json") {
+            Json::<RegisterForm>::from_request(req, state)
+                .await
+                .map(|Json(form)| form)
+                .map_err(|rejection| rejection.body_text())
+        } else if mime == "application/x-www-form-urlencoded" {
+            Form::<RegisterForm>::from_request(req, state)
+                .await
+                .map(|Form(form)| form)
+                .map_err(|rejection| rejection.body_text())
+        } else {
+            return Err(invalid_request(
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "expected application/json or application/x-www-form-urlencoded".to_string(),
+            ));
+        };
+        result
+            .map(Self)
+            .map_err(|message| invalid_request(StatusCode::BAD_REQUEST, message))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
 enum RegistrationState {
     Registered,
-    InvalidUserOrPass,
+    /// The body couldn't be read as a registration at all.
+    InvalidRequest,
     Blocked,
     InvalidToken,
+    TokenExpired,
     InvalidUsername,
     InvalidPassword,
     InvalidPasswordVerification,
+    UsernameTooLong,
+    UsernameReserved,
+    /// An application service owns the name.
+    UsernameExclusive,
+    PasswordTooShort,
+    PasswordTooLong,
+    PasswordHasWhitespace,
+    PasswordMissingDigit,
+    PasswordMissingUppercase,
+    PasswordMissingLowercase,
+    PasswordMissingSymbol,
+    PasswordContainsUsername,
+    PasswordTooWeak,
+    PasswordCompromised,
+    /// The homeserver's own password policy refused it.
+    PasswordRejected,
     UserExists,
+    InvalidEmail,
+    VerificationSent,
+    InvalidVerification,
+    VerificationExpired,
+    InvalidCaptcha,
+    /// Queued for an admin to approve.
+    PendingApproval,
+    /// An admin turned the queued signup down.
+    Rejected,
     InternalError,
 }
 
-#[derive(Serialize)]
-#[serde(rename_all = "camelCase")]
-struct RegistrationResponse {
-    registration_state: RegistrationState,
-    username: String,
-}
+impl RegistrationState {
+    fn status(self, mode: StatusCodes) -> StatusCode {
+        match (mode, self) {
+            (_, Self::InvalidRequest) => StatusCode::BAD_REQUEST,
+            (_, Self::InvalidVerification) => StatusCode::NOT_FOUND,
+            (_, Self::VerificationExpired) => StatusCode::GONE,
+            (StatusCodes::Legacy, Self::UserExists) => StatusCode::UNPROCESSABLE_ENTITY,
+            (StatusCodes::Legacy, Self::InternalError) => StatusCode::INTERNAL_SERVER_ERROR,
+            (StatusCodes::Legacy, _) => StatusCode::OK,
+            (StatusCodes::Strict, Self::Registered) => StatusCode::OK,
+            (StatusCodes::Strict, Self::VerificationSent | Self::PendingApproval) => {
+                StatusCode::ACCEPTED
+            }
+            (StatusCodes::Strict, Self::Blocked) => StatusCode::TOO_MANY_REQUESTS,
+            (StatusCodes::Strict, Self::InvalidToken | Self::TokenExpired) => {
+                StatusCode::UNAUTHORIZED
+            }
+            (StatusCodes::Strict, Self::InvalidCaptcha | Self::Rejected) => StatusCode::FORBIDDEN,
+            (StatusCodes::Strict, Self::UserExists) => StatusCode::CONFLICT,
+            // A local failure; see [`StatusCodes::upstream_failure`] for
+            // the homeserver and the other services we call.
+            (StatusCodes::Strict, Self::InternalError) => StatusCode::INTERNAL_SERVER_ERROR,
+            (StatusCodes::Strict, _) => StatusCode::BAD_REQUEST,
+        }
+    }
 
-#[derive(Serialize)]
-struct RegisterUserRequest<'a> {
-    nonce: String,
-    username: &'a str,
-    password: &'a str,
-    admin: bool,
-    mac: String,
+    /// What went wrong and which field to point the user at; `None` for the
+    /// states that aren't failures.
+    fn describe(self) -> Option<(&'static str, Option<&'static str>)> {
+        Some(match self {
+            Self::Registered | Self::VerificationSent | Self::PendingApproval => return None,
+            Self::InvalidRequest => ("The request body is not a valid registration.", None),
+            Self::Blocked => (
+                "Too many attempts from this address; try again later.",
+                None,
+            ),
+            Self::InvalidToken => ("The invite token is unknown or used up.", Some("token")),
+            Self::TokenExpired => ("The invite token has expired.", Some("token")),
+            Self::InvalidUsername => (
+                "The username is not a valid Matrix localpart.",
+                Some("username"),
+            ),
+            Self::UsernameTooLong => ("The username is too long.", Some("username")),
+            Self::UsernameReserved => ("The username is reserved.", Some("username")),
+            Self::UsernameExclusive => (
+                "The username belongs to a bridge or bot on this server.",
+                Some("username"),
+            ),
+            Self::UserExists => ("The username is already taken.", Some("username")),
+            Self::InvalidPassword => ("A password is required.", Some("password")),
+            Self::InvalidPasswordVerification => {
+                ("The passwords don't match.", Some("passwordConfirmation"))
+            }
+            Self::PasswordTooShort => ("The password is too short.", Some("password")),
+            Self::PasswordTooLong => ("The password is too long.", Some("password")),
+            Self::PasswordHasWhitespace => (
+                "The password must not contain whitespace.",
+                Some("password"),
+            ),
+            Self::PasswordMissingDigit => ("The password must contain a digit.", Some("password")),
+            Self::PasswordMissingUppercase => (
+                "The password must contain an uppercase letter.",
+                Some("password"),
+            ),
+            Self::PasswordMissingLowercase => (
+                "The password must contain a lowercase letter.",
+                Some("password"),
+            ),
+            Self::PasswordMissingSymbol => {
+                ("The password must contain a symbol.", Some("password"))
+            }
+            Self::PasswordContainsUsername => (
+                "The password must not contain the username.",
+                Some("password"),
+            ),
+            Self::PasswordTooWeak => ("The password is too easy to guess.", Some("password")),
+            Self::PasswordCompromised => {
+                ("The password appeared in a data breach.", Some("password"))
+            }
+            Self::PasswordRejected => (
+                "The homeserver's password rules refused the password.",
+                Some("password"),
+            ),
+            Self::InvalidEmail => ("A valid email address is required.", Some("email")),
+            Self::InvalidVerification => (
+                "The verification link is invalid or was already used.",
+                None,
+            ),
+            Self::VerificationExpired => ("The verification link has expired.", None),
+            Self::InvalidCaptcha => ("The captcha was not solved.", Some("captcha")),
+            Self::Rejected => ("An admin declined the registration.", None),
+            Self::InternalError => ("Something went wrong on our side.", None),
+        })
+    }
 }
 
-#[derive(Deserialize)]
-struct NonceResponse {
-    nonce: String,
+impl From<UsernameError> for RegistrationState {
+    fn from(err: UsernameError) -> Self {
+        match err {
+            UsernameError::Invalid => Self::InvalidUsername,
+            UsernameError::TooLong => Self::UsernameTooLong,
+            UsernameError::Reserved => Self::UsernameReserved,
+        }
+    }
 }
 
-fn validate_username(username: &str) -> bool {
-    USERNAME_RE.is_match(username)
+impl From<PasswordError> for RegistrationState {
+    fn from(err: PasswordError) -> Self {
+        match err {
+            PasswordError::TooShort => Self::PasswordTooShort,
+            PasswordError::TooLong => Self::PasswordTooLong,
+            PasswordError::Whitespace => Self::PasswordHasWhitespace,
+            PasswordError::MissingDigit => Self::PasswordMissingDigit,
+            PasswordError::MissingUppercase => Self::PasswordMissingUppercase,
+            PasswordError::MissingLowercase => Self::PasswordMissingLowercase,
+            PasswordError::MissingSymbol => Self::PasswordMissingSymbol,
+            PasswordError::ContainsUsername => Self::PasswordContainsUsername,
+            PasswordError::TooWeak => Self::PasswordTooWeak,
+        }
+    }
 }
 
-fn validate_password(password: &str) -> bool {
-    password.len() >= 3 && !password.chars().any(char::is_whitespace)
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RegistrationResponse {
+    registration_state: RegistrationState,
+    username: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<ApiError>,
+    /// Where a `PENDING_APPROVAL` applicant can poll for the decision.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status_url: Option<String>,
 }
 
-fn calculate_mac(nonce: &str, user: &str, password: &str, shared_secret: &str) -> String {
-    let mut mac = HmacSha1::new_from_slice(shared_secret.as_bytes()).expect("hmac can take key");
-
-    mac.update(nonce.as_bytes());
-    mac.update(&[0]);
-    mac.update(user.as_bytes());
-    mac.update(&[0]);
-    mac.update(password.as_bytes());
-    mac.update(&[0]);
-    mac.update(b"notadmin");
-
-    hex::encode(mac.finalize().into_bytes())
+/// Machine-readable detail for a failed registration.
+#[derive(Serialize)]
+struct ApiError {
+    /// Same as `registrationState`.
+    code: RegistrationState,
+    message: String,
+    /// The form field at fault, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    field: Option<&'static str>,
 }
 
 async fn register_handler(
     State(state): State<AppState>,
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     headers: HeaderMap,
-    Form(form): Form<RegisterForm>,
-) -> impl IntoResponse {
-    let client_ip = extract_ip(&headers).unwrap_or_else(|| addr.ip());
+    RegisterBody(form): RegisterBody,
+) -> Response {
+    let client_ip = state.client_ip(&headers, addr.ip());
+    let token = form.token.clone();
+    let outcome = register(&state, client_ip, form).await;
+    state.answer(outcome, client_ip, &headers, &token)
+}
 
+async fn register(state: &AppState, client_ip: IpAddr, mut form: RegisterForm) -> Outcome {
     if form.username.is_empty() {
-        return response(
-            StatusCode::OK,
-            RegistrationState::InvalidUsername,
-            &form.username,
-        );
+        return Outcome::new(RegistrationState::InvalidUsername, &form.username);
     }
     if form.password.is_empty() {
-        return response(
-            StatusCode::OK,
-            RegistrationState::InvalidPassword,
-            &form.username,
-        );
+        return Outcome::new(RegistrationState::InvalidPassword, &form.username);
     }
     if form.token.is_empty() {
-        return response(
-            StatusCode::OK,
-            RegistrationState::InvalidToken,
-            &form.username,
-        );
+        return Outcome::new(RegistrationState::InvalidToken, &form.username);
     }
 
-    if state.too_many_requests(client_ip) {
-        return response(StatusCode::OK, RegistrationState::Blocked, &form.username);
+    if let Some(wait) = state.blocked_for(client_ip) {
+        return Outcome {
+            retry_after: Some(wait),
+            ..Outcome::new(RegistrationState::Blocked, &form.username)
+        };
     }
 
     if form.password != form.password_confirmation {
-        return response(
-            StatusCode::OK,
+        return Outcome::new(
             RegistrationState::InvalidPasswordVerification,
             &form.username,
         );
     }
 
-    if !validate_username(&form.username) || !validate_password(&form.password) {
-        return response(
-            StatusCode::OK,
-            RegistrationState::InvalidUserOrPass,
-            &form.username,
-        );
+    match state.config.username.normalize(&form.username) {
+        Ok(username) => form.username = username,
+        Err(err) => return Outcome::new(err.into(), &form.username),
+    }
+    if let Err(err) = state.config.password.check(&form.password, &form.username) {
+        return Outcome::new(err.into(), &form.username);
+    }
+    if let Some(pwned) = &state.config.pwned {
+        match pwned.is_pwned(&state.client, &form.password).await {
+            Ok(true) => {
+                return Outcome::new(RegistrationState::PasswordCompromised, &form.username)
+            }
+            Ok(false) => {}
+            // A lookup outage shouldn't stop registrations altogether.
+            Err(err) => warn!("breached password check failed, allowing password: {err}"),
+        }
+    }
+
+    let mut email = None;
+    if let Some(verification) = &state.email {
+        match form
+            .email
+            .as_deref()
+            .map(str::trim)
+            .map(str::parse::<Address>)
+        {
+            Some(Ok(address)) => email = Some(address),
+            _ => return Outcome::new(RegistrationState::InvalidEmail, &form.username),
+        }
+        if verification.is_pending(&form.username) {
+            return Outcome::new(RegistrationState::UserExists, &form.username);
+        }
+    }
+    if state
+        .approvals
+        .as_ref()
+        .is_some_and(|approvals| approvals.is_pending(&form.username))
+    {
+        return Outcome::new(RegistrationState::UserExists, &form.username);
     }
 
-    if !state.is_token_ok(&form.token) {
+    // Last before the token, so the provider is only asked about otherwise
+    // valid signups.
+    if let Some(captcha) = &state.captcha {
+        match captcha
+            .verify(&state.client, &form.captcha, client_ip)
+            .await
+        {
+            Ok(()) => {}
+            Err(CaptchaError::Failed) => {
+                state.record_attempt(client_ip);
+                return Outcome::new(RegistrationState::InvalidCaptcha, &form.username);
+            }
+            Err(err) => {
+                error!("{err}");
+                return Outcome::upstream_failure(&form.username);
+            }
+        }
+    }
+
+    // A signup waiting for its email only spends the token once the link is
+    // confirmed, so one that never is, or is lost to a restart, holds no use.
+    let token = if state.email.is_some() {
+        state.check_token(&form.token).await
+    } else {
+        state.consume_token(&form.token).await
+    };
+    if let Err(err) = token {
+        let Some(registration_state) = token_refusal(err) else {
+            return Outcome::upstream_failure(&form.username);
+        };
         state.record_attempt(client_ip);
-        return response(
-            StatusCode::OK,
-            RegistrationState::InvalidToken,
-            &form.username,
+        return Outcome::new(registration_state, &form.username);
+    }
+
+    let display_name = form
+        .display_name
+        .as_deref()
+        .map(str::trim)
+        .filter(|name| !name.is_empty());
+    let signup = Signup {
+        username: form.username.clone(),
+        password: form.password,
+        token: form.token,
+        client_ip,
+        display_name: display_name.map(str::to_string),
+    };
+
+    if let (Some(verification), Some(email)) = (&state.email, email) {
+        let user_id = state.user_id(&signup.username);
+        if let Err(err) = verification.start(signup.clone(), email, &user_id).await {
+            error!("failed to send verification email: {err}");
+            return match err {
+                EmailError::Smtp(_) | EmailError::Http(_) => {
+                    Outcome::upstream_failure(&signup.username)
+                }
+                _ => Outcome::new(RegistrationState::InternalError, &signup.username),
+            };
+        }
+        if state.config.rate_limit.count_successful {
+            state.record_attempt(client_ip);
+        }
+        return Outcome::new(RegistrationState::VerificationSent, &signup.username);
+    }
+
+    if let Some(approvals) = &state.approvals {
+        if state.config.rate_limit.count_successful {
+            state.record_attempt(client_ip);
+        }
+        return state.queue_for_approval(approvals, &signup, None).await;
+    }
+
+    let started = Instant::now();
+    let result = state.create_account(&signup, None).await;
+    Outcome::account(result, &signup.username, started.elapsed())
+}
+
+/// The state a token the store refused answers with, or `None` when the
+/// lookup itself failed.
+fn token_refusal(err: TokenError) -> Option<RegistrationState> {
+    match err {
+        TokenError::Expired => Some(RegistrationState::TokenExpired),
+        TokenError::Unknown | TokenError::Exhausted => Some(RegistrationState::InvalidToken),
+        TokenError::Upstream(err) => {
+            error!("token check failed: {err}");
+            None
+        }
+    }
+}
+
+/// Asks the owner of a verification link to confirm it, so that fetching
+/// the link creates nothing.
+async fn confirm_page_handler(State(state): State<AppState>, Path(code): Path<String>) -> Response {
+    if state
+        .email
+        .as_ref()
+        .is_some_and(|email| email.contains(&code))
+    {
+        return Html(CONFIRM_PAGE).into_response();
+    }
+    let registration_state = RegistrationState::InvalidVerification;
+    response(
+        registration_state.status(state.config.status_codes),
+        registration_state,
+        "",
+    )
+    .into_response()
+}
+
+/// Completes a registration once its verification link is confirmed.
+async fn verify_handler(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Path(code): Path<String>,
+) -> Response {
+    // Carried out in its own task, like approvals: a dropped request mustn't
+    // leave the token spent but the link neither used up nor restored.
+    tokio::spawn(verify(state, addr, headers, code))
+        .await
+        .expect("verification task panicked")
+}
+
+async fn verify(state: AppState, addr: SocketAddr, headers: HeaderMap, code: String) -> Response {
+    let client_ip = state.client_ip(&headers, addr.ip());
+    let taken = (state.email.as_ref()).and_then(|email| Some((email, email.take(&code)?)));
+    let Some((email, pending)) = taken else {
+        let outcome = Outcome::new(RegistrationState::InvalidVerification, "");
+        return state.answer(outcome, client_ip, &headers, "");
+    };
+    let username = &pending.signup.username;
+    let outcome = if pending.is_expired(Utc::now()) {
+        Outcome::new(RegistrationState::VerificationExpired, username)
+    } else if let Err(err) = state.consume_token(&pending.signup.token).await {
+        // Spent or revoked since the signup.
+        match token_refusal(err) {
+            Some(registration_state) => Outcome::new(registration_state, username),
+            None => Outcome::upstream_failure(username),
+        }
+    } else if let Some(approvals) = &state.approvals {
+        state
+            .queue_for_approval(approvals, &pending.signup, Some(&pending.email))
+            .await
+    } else {
+        let started = Instant::now();
+        let result = state
+            .create_account(&pending.signup, Some(&pending.email))
+            .await;
+        Outcome::account(result, username, started.elapsed())
+    };
+    let token = pending.signup.token.clone();
+    // Whatever failed gave the token use back, so the link can be retried.
+    if outcome.state == RegistrationState::InternalError {
+        email.restore(code, pending);
+    }
+    state.answer(outcome, client_ip, &headers, &token)
+}
+
+#[derive(Deserialize)]
+struct AvailabilityQuery {
+    #[serde(default)]
+    username: String,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct AvailabilityResponse {
+    username: String,
+    available: bool,
+    /// Why the name can't be had.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reason: Option<RegistrationState>,
+}
+
+fn availability(
+    status: StatusCode,
+    username: String,
+    reason: Option<RegistrationState>,
+) -> Response {
+    (
+        status,
+        Json(AvailabilityResponse {
+            username,
+            available: reason.is_none(),
+            reason,
+        }),
+    )
+        .into_response()
+}
+
+/// Checks a username with the same rules as a registration and asks the
+/// homeserver whether it is still free.
+async fn username_available_handler(
+    State(state): State<AppState>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    headers: HeaderMap,
+    Query(query): Query<AvailabilityQuery>,
+) -> impl IntoResponse {
+    let client_ip = state.client_ip(&headers, addr.ip());
+    if let Err(wait) = state.allow_availability_check(client_ip) {
+        return blocked(
+            availability(
+                StatusCode::TOO_MANY_REQUESTS,
+                query.username,
+                Some(RegistrationState::Blocked),
+            ),
+            wait,
         );
     }
 
-    let result = state.register_user(&form.username, &form.password).await;
-    state.record_attempt(client_ip);
+    let username = match state.config.username.normalize(&query.username) {
+        Ok(username) => username,
+        Err(err) => return availability(StatusCode::OK, query.username, Some(err.into())),
+    };
+    if state
+        .email
+        .as_ref()
+        .is_some_and(|email| email.is_pending(&username))
+        || state
+            .approvals
+            .as_ref()
+            .is_some_and(|approvals| approvals.is_pending(&username))
+    {
+        return availability(
+            StatusCode::OK,
+            username,
+            Some(RegistrationState::UserExists),
+        );
+    }
 
-    match result {
-        Ok(_) => response(
+    match state.backend.is_username_available(&username).await {
+        Ok(true) => availability(StatusCode::OK, username, None),
+        Ok(false) => availability(
             StatusCode::OK,
-            RegistrationState::Registered,
-            &form.username,
-        ),
-        Err(RegisterError::UserExists) => response(
-            StatusCode::UNPROCESSABLE_ENTITY,
-            RegistrationState::UserExists,
-            &form.username,
+            username,
+            Some(RegistrationState::UserExists),
         ),
         Err(err) => {
-            error!("registration failed: {err}");
-            response(
-                StatusCode::INTERNAL_SERVER_ERROR,
-                RegistrationState::InternalError,
-                &form.username,
+            error!("availability check failed: {err}");
+            availability(
+                state.config.status_codes.upstream_failure(),
+                username,
+                Some(RegistrationState::InternalError),
             )
         }
     }
 }
 
+/// Tells an applicant whether their queued registration was decided.
+async fn application_status_handler(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Response {
+    let Some(application) = state
+        .approvals
+        .as_ref()
+        .and_then(|approvals| approvals.get(&id))
+    else {
+        return invalid_request(StatusCode::NOT_FOUND, "no such registration".to_string())
+            .into_response();
+    };
+    let (status, Json(mut body)) = response(
+        application.state.status(state.config.status_codes),
+        application.state,
+        &application.username,
+    );
+    if application.is_pending() {
+        body.status_url = Some(status_url(&application.id));
+    }
+    (status, Json(body)).into_response()
+}
+
+/// Serves counters and gauges in the Prometheus text format.
+async fn metrics_handler(State(state): State<AppState>) -> impl IntoResponse {
+    let policy = &state.config.rate_limit;
+    let now = Utc::now();
+    let blocked_ips = state
+        .attempts
+        .iter()
+        .filter(|entry| entry.blocked_for(policy, now).is_some())
+        .count();
+    // Synapse keeps the counts for its own tokens.
+    let tokens = match state.synapse_tokens {
+        Some(_) => Vec::new(),
+        None => state
+            .tokens
+            .list()
+            .into_iter()
+            .map(|(token, invite)| (state.token_id(&token), invite.uses, invite.max_uses))
+            .collect(),
+    };
+    (
+        [(header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+        state.metrics.render(&Snapshot {
+            blocked_ips,
+            tokens,
+        }),
+    )
+}
+
+/// Hands out a proof-of-work puzzle for the registration form.
+async fn captcha_challenge_handler(State(state): State<AppState>) -> impl IntoResponse {
+    match state
+        .captcha
+        .as_ref()
+        .and_then(|captcha| captcha.challenge())
+    {
+        Some(challenge) => Json(challenge).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    }
+}
+
+fn account_outcome(result: Result<(), RegisterError>, username: &str) -> RegistrationState {
+    match result {
+        Ok(_) => RegistrationState::Registered,
+        Err(RegisterError::UserExists) => RegistrationState::UserExists,
+        Err(RegisterError::InvalidUsername(err)) => {
+            info!("homeserver refused username {username}: {err}");
+            RegistrationState::InvalidUsername
+        }
+        Err(RegisterError::Exclusive(err)) => {
+            info!("username {username} is in an application service namespace: {err}");
+            RegistrationState::UsernameExclusive
+        }
+        Err(RegisterError::WeakPassword(err)) => {
+            info!("homeserver refused the password for {username}: {err}");
+            RegistrationState::PasswordRejected
+        }
+        Err(RegisterError::SharedSecretRejected(err)) => {
+            alert_shared_secret_rejected(&err);
+            RegistrationState::InternalError
+        }
+        Err(err) => {
+            error!("registration failed: {err}");
+            RegistrationState::InternalError
+        }
+    }
+}
+
+/// Logged at startup and on every registration while the homeserver refuses
+/// our HMAC: nothing can register until the secret is fixed.
+fn alert_shared_secret_rejected(detail: &str) {
+    error!(
+        "the homeserver REJECTED MATRIX_SHARED_SECRET ({detail}); every registration will fail \
+         until it matches registration_shared_secret in the homeserver config"
+    );
+}
+
+fn status_url(application: &str) -> String {
+    format!("/api/v1/register/{application}")
+}
+
+/// Turns a `BLOCKED` answer into a 429 telling the client when to come back.
+fn blocked(mut response: Response, wait: chrono::Duration) -> Response {
+    if response.status() == StatusCode::TOO_MANY_REQUESTS {
+        // Rounded up, so a client that waits exactly this long gets through.
+        let seconds = (wait.num_milliseconds().max(0) as u64).div_ceil(1000);
+        response
+            .headers_mut()
+            .insert(header::RETRY_AFTER, seconds.into());
+    }
+    response
+}
+
 fn response(
     status: StatusCode,
     registration_state: RegistrationState,
     username: &str,
 ) -> (StatusCode, Json<RegistrationResponse>) {
+    let error = registration_state
+        .describe()
+        .map(|(message, field)| ApiError {
+            code: registration_state,
+            message: message.to_string(),
+            field,
+        });
     (
         status,
         Json(RegistrationResponse {
             registration_state,
             username: username.to_string(),
+            error,
+            status_url: None,
         }),
     )
 }
 
-fn extract_ip(headers: &HeaderMap) -> Option<IpAddr> {
-    headers
-        .get("x-forwarded-for")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|raw| raw.split(',').next())
-        .and_then(|part| part.trim().parse().ok())
+fn invalid_request(
+    status: StatusCode,
+    message: String,
+) -> (StatusCode, Json<RegistrationResponse>) {
+    let (status, mut response) = response(status, RegistrationState::InvalidRequest, "");
+    if let Some(error) = &mut response.error {
+        error.message = message;
+    }
+    (status, response)
+}
+
+fn app(state: AppState) -> Router {
+    let mut app = Router::new()
+        .route("/api/v1/register", post(register_handler))
+        // The original endpoint, kept for existing frontends.
+        .route("/registration", post(register_handler))
+        .route("/username_available", get(username_available_handler));
+    if state.config.admin_secret.is_some() {
+        app = app.merge(admin::router(state.clone()));
+    }
+    if state.config.metrics {
+        app = app.route("/metrics", get(metrics_handler));
+    }
+    if state.config.email.is_some() {
+        app = app.route(
+            "/verify/:code",
+            get(confirm_page_handler).post(verify_handler),
+        );
+    }
+    if state.config.approval.is_some() {
+        app = app.route("/api/v1/register/:id", get(application_status_handler));
+    }
+    if matches!(
+        state.config.captcha,
+        Some(CaptchaConfig::ProofOfWork { .. })
+    ) {
+        app = app.route("/captcha/challenge", get(captcha_challenge_handler));
+    }
+    if state.config.frontend.is_some() {
+        app = app.merge(frontend::router());
+    }
+    app.with_state(state)
 }
 
 #[tokio::main]
@@ -363,16 +2018,44 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
     );
 
     let bind_addr = config.bind_addr;
-    let state = AppState::new(config);
-
-    let app = Router::new()
-        .route("/registration", post(register_handler))
-        .with_state(state);
+    let storage = config.storage.open()?;
+    let state = AppState::new(config, storage)?;
+    if state.config.admin_secret.is_none() {
+        info!("ADMIN_SECRET not set; admin API disabled");
+    }
+    if let Some(email) = state.email.clone() {
+        tokio::spawn(async move {
+            let mut sweep = tokio::time::interval(std::time::Duration::from_secs(60));
+            loop {
+                sweep.tick().await;
+                email.drop_expired();
+            }
+        });
+    }
+    if let Some(audit) = state.audit.clone() {
+        tokio::spawn(async move {
+            let mut sweep = tokio::time::interval(std::time::Duration::from_secs(60 * 60));
+            loop {
+                sweep.tick().await;
+                if let Err(err) = audit.sweep() {
+                    error!("failed to sweep audit log: {err}");
+                }
+            }
+        });
+    }
+    let backend = state.backend.clone();
+    tokio::spawn(async move {
+        match backend.verify_credentials().await {
+            Ok(()) => {}
+            Err(RegisterError::SharedSecretRejected(err)) => alert_shared_secret_rejected(&err),
+            Err(err) => warn!("could not check the homeserver credentials: {err}"),
+        }
+    });
 
     let listener = TcpListener::bind(bind_addr).await?;
     axum::serve(
         listener,
-        app.into_make_service_with_connect_info::<SocketAddr>(),
+        app(state).into_make_service_with_connect_info::<SocketAddr>(),
     )
     .await?;
 
@@ -0,0 +1,166 @@
+//! Counters and histograms rendered in the Prometheus text exposition format.
+
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use dashmap::DashMap;
+
+/// Upper bounds in seconds, Prometheus' default buckets.
+const BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+/// Calls to the homeserver whose latency is tracked.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Upstream {
+    /// `GET /_synapse/admin/v1/register` for a nonce.
+    Nonce,
+    /// Creating the account: `POST /_synapse/admin/v1/register`, Conduit's
+    /// client-server register POSTs, or MAS's user creation and password
+    /// calls.
+    Register,
+}
+
+impl Upstream {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Nonce => "nonce",
+            Self::Register => "register",
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    outcomes: DashMap<String, AtomicU64>,
+    nonce: Histogram,
+    register: Histogram,
+}
+
+#[derive(Default)]
+struct Histogram {
+    /// Per bucket, not cumulative; `+Inf` is `count`.
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, elapsed: Duration) {
+        let seconds = elapsed.as_secs_f64();
+        if let Some(bucket) = BUCKETS.iter().position(|&bound| seconds <= bound) {
+            self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, labels: &str) {
+        let mut cumulative = 0;
+        for (bound, bucket) in BUCKETS.iter().zip(&self.buckets) {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let _ = writeln!(out, "{name}_bucket{{{labels},le=\"{bound}\"}} {cumulative}");
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{name}_bucket{{{labels},le=\"+Inf\"}} {count}");
+        let _ = writeln!(out, "{name}_sum{{{labels}}} {sum}");
+        let _ = writeln!(out, "{name}_count{{{labels}}} {count}");
+    }
+}
+
+/// Values read from the app's state at scrape time rather than counted.
+pub struct Snapshot {
+    pub blocked_ips: usize,
+    /// Token id, uses so far and allowed uses, for tokens kept locally.
+    pub tokens: Vec<(String, u32, Option<u32>)>,
+}
+
+impl Metrics {
+    /// Counts one answer to a registration attempt.
+    pub fn record_outcome(&self, state: &str) {
+        if let Some(counter) = self.outcomes.get(state) {
+            counter.fetch_add(1, Ordering::Relaxed);
+            return;
+        }
+        self.outcomes
+            .entry(state.to_string())
+            .or_default()
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn observe(&self, upstream: Upstream, elapsed: Duration) {
+        self.histogram(upstream).observe(elapsed);
+    }
+
+    fn histogram(&self, upstream: Upstream) -> &Histogram {
+        match upstream {
+            Upstream::Nonce => &self.nonce,
+            Upstream::Register => &self.register,
+        }
+    }
+
+    pub fn render(&self, snapshot: &Snapshot) -> String {
+        let mut out = String::new();
+
+        out.push_str("# HELP matrix_registration_outcomes_total Registration answers by registrationState.\n");
+        out.push_str("# TYPE matrix_registration_outcomes_total counter\n");
+        let mut outcomes: Vec<_> = self
+            .outcomes
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().load(Ordering::Relaxed)))
+            .collect();
+        outcomes.sort();
+        for (state, count) in outcomes {
+            let _ = writeln!(
+                out,
+                "matrix_registration_outcomes_total{{state=\"{state}\"}} {count}"
+            );
+        }
+
+        let name = "matrix_registration_upstream_request_duration_seconds";
+        let _ = writeln!(out, "# HELP {name} Latency of calls to the homeserver.");
+        let _ = writeln!(out, "# TYPE {name} histogram");
+        for upstream in [Upstream::Nonce, Upstream::Register] {
+            self.histogram(upstream).render(
+                &mut out,
+                name,
+                &format!("request=\"{}\"", upstream.name()),
+            );
+        }
+
+        out.push_str(
+            "# HELP matrix_registration_blocked_ips IPs currently blocked by the rate limit.\n",
+        );
+        out.push_str("# TYPE matrix_registration_blocked_ips gauge\n");
+        let _ = writeln!(
+            out,
+            "matrix_registration_blocked_ips {}",
+            snapshot.blocked_ips
+        );
+
+        out.push_str(
+            "# HELP matrix_registration_token_uses Uses of each invite token, by token id.\n",
+        );
+        out.push_str("# TYPE matrix_registration_token_uses gauge\n");
+        for (id, uses, _) in &snapshot.tokens {
+            let _ = writeln!(
+                out,
+                "matrix_registration_token_uses{{token=\"{id}\"}} {uses}"
+            );
+        }
+        out.push_str("# HELP matrix_registration_token_uses_allowed Use limit of each limited invite token.\n");
+        out.push_str("# TYPE matrix_registration_token_uses_allowed gauge\n");
+        for (id, _, max_uses) in &snapshot.tokens {
+            if let Some(max_uses) = max_uses {
+                let _ = writeln!(
+                    out,
+                    "matrix_registration_token_uses_allowed{{token=\"{id}\"}} {max_uses}"
+                );
+            }
+        }
+        out
+    }
+}
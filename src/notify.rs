@@ -0,0 +1,103 @@
+//! Messages about registrations posted to a moderation room by a bot.
+
+use std::net::IpAddr;
+
+use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::{Client, Url};
+use serde_json::json;
+use thiserror::Error;
+use tracing::warn;
+
+#[derive(Clone, Debug)]
+pub struct NotifyConfig {
+    /// Access token of the bot account, which must be joined to `room_id`.
+    pub bot_token: String,
+    pub room_id: String,
+}
+
+#[derive(Debug, Error)]
+pub enum NotifyError {
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("invalid MATRIX_SERVER url")]
+    InvalidServer,
+}
+
+/// Cheap to clone; every message is sent from its own task so a slow
+/// homeserver never holds up an answer.
+#[derive(Clone)]
+pub struct Notifier {
+    server: String,
+    config: NotifyConfig,
+    client: Client,
+}
+
+impl Notifier {
+    pub fn new(server: &str, config: NotifyConfig, client: Client) -> Self {
+        Self {
+            server: server.to_string(),
+            config,
+            client,
+        }
+    }
+
+    pub fn registered(&self, user_id: &str, token_id: &str, client_ip: IpAddr) {
+        self.post(format!(
+            "New account {user_id} registered from {client_ip} with token {token_id}"
+        ));
+    }
+
+    pub fn awaiting_approval(&self, user_id: &str, application: &str, client_ip: IpAddr) {
+        self.post(format!(
+            "{user_id} from {client_ip} is waiting for approval as application {application}"
+        ));
+    }
+
+    /// Sent once, when `client_ip` crosses the limit, not for every request
+    /// it makes while blocked.
+    pub fn blocked(&self, client_ip: IpAddr, attempts: usize, until: DateTime<Utc>) {
+        self.post(format!(
+            "Blocked {client_ip} after {attempts} registration attempts, until {}",
+            until.format("%Y-%m-%d %H:%M UTC")
+        ));
+    }
+
+    fn post(&self, body: String) {
+        let notifier = self.clone();
+        tokio::spawn(async move {
+            if let Err(err) = notifier.send(&body).await {
+                warn!("failed to notify {}: {err}", notifier.config.room_id);
+            }
+        });
+    }
+
+    async fn send(&self, body: &str) -> Result<(), NotifyError> {
+        let txn_id: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        let mut url = Url::parse(&self.server).map_err(|_| NotifyError::InvalidServer)?;
+        url.path_segments_mut()
+            .map_err(|_| NotifyError::InvalidServer)?
+            .extend([
+                "_matrix",
+                "client",
+                "v3",
+                "rooms",
+                &self.config.room_id,
+                "send",
+                "m.room.message",
+                &txn_id,
+            ]);
+        self.client
+            .put(url)
+            .bearer_auth(&self.config.bot_token)
+            .json(&json!({ "msgtype": "m.notice", "body": body }))
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,98 @@
+use thiserror::Error;
+
+/// Rules a new password has to meet, meant to mirror the homeserver's own
+/// `password_config` so the user hears about a rejection before an account
+/// is attempted.
+#[derive(Clone, Debug)]
+pub struct PasswordPolicy {
+    /// In characters, not bytes.
+    pub min_length: usize,
+    pub max_length: Option<usize>,
+    pub require_digit: bool,
+    pub require_uppercase: bool,
+    pub require_lowercase: bool,
+    /// Any character that isn't alphanumeric.
+    pub require_symbol: bool,
+    /// Lowest acceptable zxcvbn score, 0 to 4.
+    pub min_score: Option<u8>,
+    /// Refuse passwords that contain the username, case-insensitively.
+    pub reject_username: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_length: 3,
+            max_length: None,
+            require_digit: false,
+            require_uppercase: false,
+            require_lowercase: false,
+            require_symbol: false,
+            min_score: None,
+            reject_username: false,
+        }
+    }
+}
+
+/// The first rule a password broke.
+#[derive(Clone, Copy, Debug, Error, PartialEq)]
+pub enum PasswordError {
+    #[error("password is too short")]
+    TooShort,
+    #[error("password is too long")]
+    TooLong,
+    #[error("password contains whitespace")]
+    Whitespace,
+    #[error("password has no digit")]
+    MissingDigit,
+    #[error("password has no uppercase letter")]
+    MissingUppercase,
+    #[error("password has no lowercase letter")]
+    MissingLowercase,
+    #[error("password has no symbol")]
+    MissingSymbol,
+    #[error("password contains the username")]
+    ContainsUsername,
+    #[error("password is too easy to guess")]
+    TooWeak,
+}
+
+impl PasswordPolicy {
+    pub fn check(&self, password: &str, username: &str) -> Result<(), PasswordError> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Err(PasswordError::TooShort);
+        }
+        if self.max_length.is_some_and(|max| length > max) {
+            return Err(PasswordError::TooLong);
+        }
+        if password.chars().any(char::is_whitespace) {
+            return Err(PasswordError::Whitespace);
+        }
+        if self.require_digit && !password.chars().any(|c| c.is_ascii_digit()) {
+            return Err(PasswordError::MissingDigit);
+        }
+        if self.require_uppercase && !password.chars().any(char::is_uppercase) {
+            return Err(PasswordError::MissingUppercase);
+        }
+        if self.require_lowercase && !password.chars().any(char::is_lowercase) {
+            return Err(PasswordError::MissingLowercase);
+        }
+        if self.require_symbol && password.chars().all(char::is_alphanumeric) {
+            return Err(PasswordError::MissingSymbol);
+        }
+        if self.reject_username
+            && !username.is_empty()
+            && password.to_lowercase().contains(&username.to_lowercase())
+        {
+            return Err(PasswordError::ContainsUsername);
+        }
+        if let Some(min_score) = self.min_score {
+            let score = u8::from(zxcvbn::zxcvbn(password, &[username]).score());
+            if score < min_score {
+                return Err(PasswordError::TooWeak);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,190 @@
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::{Client, Method, Url};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use thiserror::Error;
+use tracing::{info, warn};
+
+/// What to set up for a freshly registered account, all through Synapse's
+/// admin API plus an optional bot account.
+#[derive(Clone, Debug, Default)]
+pub struct ProvisionConfig {
+    /// Access token of a Synapse server admin.
+    pub admin_token: String,
+    /// Whether the optional `displayName` form field is applied.
+    pub set_display_name: bool,
+    /// `mxc://` URI given to every new account.
+    pub default_avatar: Option<String>,
+    /// Room IDs or aliases new accounts are force-joined to.
+    pub auto_join: Vec<String>,
+    pub welcome: Option<WelcomeMessage>,
+}
+
+/// A direct message sent by a bot account to every new user.
+#[derive(Clone, Debug)]
+pub struct WelcomeMessage {
+    pub bot_token: String,
+    pub body: String,
+}
+
+#[derive(Debug, Error)]
+pub enum ProvisionError {
+    #[error("request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("invalid MATRIX_SERVER url")]
+    InvalidServer,
+}
+
+#[derive(Deserialize)]
+struct CreatedRoom {
+    room_id: String,
+}
+
+pub struct Provisioner {
+    server: String,
+    config: ProvisionConfig,
+    client: Client,
+}
+
+impl Provisioner {
+    pub fn new(server: &str, config: ProvisionConfig, client: Client) -> Self {
+        Self {
+            server: server.to_string(),
+            config,
+            client,
+        }
+    }
+
+    /// Runs every configured step for `user_id`. A failing step is logged and
+    /// doesn't stop the others; the account exists either way.
+    pub async fn provision(&self, user_id: &str, display_name: Option<&str>) {
+        if let Err(err) = self.set_profile(user_id, display_name).await {
+            warn!("failed to set profile of {user_id}: {err}");
+        }
+        for room in &self.config.auto_join {
+            if let Err(err) = self.join(user_id, room).await {
+                warn!("failed to join {user_id} to {room}: {err}");
+            }
+        }
+        if let Some(welcome) = &self.config.welcome {
+            if let Err(err) = self.send_welcome(user_id, welcome).await {
+                warn!("failed to send welcome message to {user_id}: {err}");
+            }
+        }
+    }
+
+    async fn set_profile(
+        &self,
+        user_id: &str,
+        display_name: Option<&str>,
+    ) -> Result<(), ProvisionError> {
+        let mut body = serde_json::Map::new();
+        if let Some(display_name) = display_name.filter(|_| self.config.set_display_name) {
+            body.insert("displayname".into(), display_name.into());
+        }
+        if let Some(avatar) = &self.config.default_avatar {
+            body.insert("avatar_url".into(), avatar.as_str().into());
+        }
+        if body.is_empty() {
+            return Ok(());
+        }
+
+        let url = self.url(&["_synapse", "admin", "v2", "users", user_id])?;
+        self.send(
+            Method::PUT,
+            url,
+            &self.config.admin_token,
+            Value::Object(body),
+        )
+        .await?;
+        Ok(())
+    }
+
+    async fn join(&self, user_id: &str, room: &str) -> Result<(), ProvisionError> {
+        let url = self.url(&["_synapse", "admin", "v1", "join", room])?;
+        self.send(
+            Method::POST,
+            url,
+            &self.config.admin_token,
+            json!({ "user_id": user_id }),
+        )
+        .await?;
+        info!("joined {user_id} to {room}");
+        Ok(())
+    }
+
+    /// Opens a DM from the bot to `user_id` and posts the welcome text. The
+    /// user sees it as an invite the first time they log in.
+    async fn send_welcome(
+        &self,
+        user_id: &str,
+        welcome: &WelcomeMessage,
+    ) -> Result<(), ProvisionError> {
+        let url = self.url(&["_matrix", "client", "v3", "createRoom"])?;
+        let room: CreatedRoom = self
+            .send(
+                Method::POST,
+                url,
+                &welcome.bot_token,
+                json!({
+                    "invite": [user_id],
+                    "is_direct": true,
+                    "preset": "trusted_private_chat",
+                }),
+            )
+            .await?
+            .json()
+            .await?;
+
+        let txn_id: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(16)
+            .map(char::from)
+            .collect();
+        let url = self.url(&[
+            "_matrix",
+            "client",
+            "v3",
+            "rooms",
+            &room.room_id,
+            "send",
+            "m.room.message",
+            &txn_id,
+        ])?;
+        self.send(
+            Method::PUT,
+            url,
+            &welcome.bot_token,
+            json!({ "msgtype": "m.text", "body": welcome.body }),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Builds a URL on the homeserver, percent-encoding each segment so room
+    /// aliases and user IDs can be used as-is.
+    fn url(&self, segments: &[&str]) -> Result<Url, ProvisionError> {
+        let mut url = Url::parse(&self.server).map_err(|_| ProvisionError::InvalidServer)?;
+        url.path_segments_mut()
+            .map_err(|_| ProvisionError::InvalidServer)?
+            .extend(segments);
+        Ok(url)
+    }
+
+    async fn send(
+        &self,
+        method: Method,
+        url: Url,
+        token: &str,
+        body: Value,
+    ) -> Result<reqwest::Response, ProvisionError> {
+        Ok(self
+            .client
+            .request(method, url)
+            .bearer_auth(token)
+            .json(&body)
+            .send()
+            .await?
+            .error_for_status()?)
+    }
+}
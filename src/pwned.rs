@@ -0,0 +1,101 @@
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use reqwest::Client;
+use sha1::{Digest, Sha1};
+use thiserror::Error;
+
+/// Where to look up passwords from the Have I Been Pwned corpus.
+#[derive(Clone, Debug)]
+pub enum PwnedPasswords {
+    /// The k-anonymity range API, or a mirror of it: only the first five hex
+    /// characters of the SHA-1 leave this service.
+    Api { base_url: String },
+    /// A local copy of the SHA-1 list ordered by hash, one `HASH:COUNT` per
+    /// line, as published by HIBP. It is binary-searched on disk, never
+    /// loaded.
+    HashFile(PathBuf),
+}
+
+#[derive(Debug, Error)]
+pub enum PwnedError {
+    #[error("range request failed: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("failed to read hash file: {0}")]
+    Io(#[from] io::Error),
+}
+
+impl PwnedPasswords {
+    pub async fn is_pwned(&self, client: &Client, password: &str) -> Result<bool, PwnedError> {
+        let hash = hex::encode_upper(Sha1::digest(password.as_bytes()));
+        match self {
+            Self::Api { base_url } => range_contains(client, base_url, &hash).await,
+            Self::HashFile(path) => {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || file_contains(&path, &hash))
+                    .await
+                    .expect("hash file lookup panicked")
+            }
+        }
+    }
+}
+
+async fn range_contains(client: &Client, base_url: &str, hash: &str) -> Result<bool, PwnedError> {
+    let (prefix, suffix) = hash.split_at(5);
+    let body = client
+        .get(format!("{}/range/{prefix}", base_url.trim_end_matches('/')))
+        // Pads the answer with fake zero-count suffixes so its size doesn't
+        // give the prefix away either.
+        .header("Add-Padding", "true")
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+
+    Ok(body.lines().any(|line| {
+        line.split_once(':')
+            .is_some_and(|(candidate, count)| candidate == suffix && count.trim() != "0")
+    }))
+}
+
+/// Binary search over byte offsets: each probe seeks somewhere into the
+/// remaining range and compares the first full line starting there.
+fn file_contains(path: &Path, hash: &str) -> Result<bool, PwnedError> {
+    let mut file = BufReader::new(File::open(path)?);
+    let (mut lo, mut hi) = (0, file.get_ref().metadata()?.len());
+    let mut line = Vec::new();
+
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        // Skip to the start of the next line, unless `mid` already is one.
+        let mut start = mid;
+        if mid > 0 {
+            file.seek(SeekFrom::Start(mid - 1))?;
+            line.clear();
+            start = mid - 1 + file.read_until(b'\n', &mut line)? as u64;
+        } else {
+            file.seek(SeekFrom::Start(0))?;
+        }
+        if start >= hi {
+            hi = mid;
+            continue;
+        }
+
+        line.clear();
+        let read = file.read_until(b'\n', &mut line)? as u64;
+        let candidate = line.split(|&b| b == b':').next().unwrap_or_default();
+        match candidate
+            .trim_ascii()
+            .to_ascii_uppercase()
+            .as_slice()
+            .cmp(hash.as_bytes())
+        {
+            std::cmp::Ordering::Equal => return Ok(true),
+            std::cmp::Ordering::Less => lo = start + read,
+            std::cmp::Ordering::Greater => hi = mid,
+        }
+    }
+    Ok(false)
+}
@@ -0,0 +1,82 @@
+use chrono::{DateTime, Duration, Utc};
+
+/// How many attempts an IP may make within a sliding window.
+#[derive(Clone, Debug)]
+pub struct RateLimitPolicy {
+    pub max_attempts: u32,
+    pub window: Duration,
+    /// Whether a successful registration counts as an attempt too.
+    pub count_successful: bool,
+}
+
+impl Default for RateLimitPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 3,
+            window: Duration::hours(24),
+            count_successful: true,
+        }
+    }
+}
+
+/// Timestamps of the most recent attempts from one IP, oldest first.
+///
+/// Only the last `max_attempts` hits matter for a sliding window, so older
+/// ones are dropped as new ones come in.
+#[derive(Clone, Debug, Default)]
+pub struct Attempt {
+    hits: Vec<DateTime<Utc>>,
+}
+
+impl Attempt {
+    pub fn from_hits(mut hits: Vec<DateTime<Utc>>) -> Self {
+        hits.sort();
+        Self { hits }
+    }
+
+    pub fn hits(&self) -> &[DateTime<Utc>] {
+        &self.hits
+    }
+
+    pub fn last(&self) -> Option<DateTime<Utc>> {
+        self.hits.last().copied()
+    }
+
+    /// Number of attempts still inside the window.
+    pub fn count(&self, policy: &RateLimitPolicy, now: DateTime<Utc>) -> usize {
+        self.hits
+            .iter()
+            .filter(|&&hit| now - hit < policy.window)
+            .count()
+    }
+
+    pub fn record(&mut self, policy: &RateLimitPolicy, now: DateTime<Utc>) {
+        self.prune(policy, now);
+        self.hits.push(now);
+        let excess = self.hits.len().saturating_sub(policy.max_attempts as usize);
+        self.hits.drain(..excess);
+    }
+
+    /// Drops hits that have left the window; returns `true` if any were removed.
+    pub fn prune(&mut self, policy: &RateLimitPolicy, now: DateTime<Utc>) -> bool {
+        let before = self.hits.len();
+        self.hits.retain(|&hit| now - hit < policy.window);
+        self.hits.len() != before
+    }
+
+    /// How long this entry keeps its IP blocked, if it blocks at all.
+    pub fn blocked_for(&self, policy: &RateLimitPolicy, now: DateTime<Utc>) -> Option<Duration> {
+        let max = policy.max_attempts as usize;
+        let recent: Vec<_> = self
+            .hits
+            .iter()
+            .filter(|&&hit| now - hit < policy.window)
+            .collect();
+        if recent.len() < max {
+            return None;
+        }
+        // The block lifts once the oldest of the last `max` hits slides out.
+        let oldest = *recent[recent.len() - max];
+        Some(oldest + policy.window - now)
+    }
+}
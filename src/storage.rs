@@ -0,0 +1,432 @@
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use rusqlite::{params, Connection};
+use thiserror::Error;
+
+use crate::approval::Application;
+use crate::ratelimit::Attempt;
+use crate::tokens::InviteToken;
+use crate::RegistrationState;
+
+/// A successful registration as written to the ledger.
+#[derive(Clone, Debug)]
+pub struct Registration {
+    pub username: String,
+    pub registered_at: DateTime<Utc>,
+    pub token: String,
+    pub client_ip: IpAddr,
+}
+
+#[derive(Debug, Error)]
+pub enum StorageError {
+    #[error("sqlite error: {0}")]
+    Sqlite(#[from] rusqlite::Error),
+    #[error("corrupt row in {table}: {detail}")]
+    Corrupt { table: &'static str, detail: String },
+}
+
+/// Everything `AppState` needs to survive a restart.
+///
+/// Implementations are called synchronously from request handlers, so they
+/// should only do small, local writes.
+pub trait Storage: Send + Sync {
+    fn load_attempts(&self) -> Result<Vec<(IpAddr, Attempt)>, StorageError>;
+    fn save_attempt(&self, ip: IpAddr, attempt: &Attempt) -> Result<(), StorageError>;
+    fn delete_attempt(&self, ip: IpAddr) -> Result<(), StorageError>;
+    fn clear_attempts(&self) -> Result<(), StorageError>;
+    /// Every token that hasn't been revoked.
+    fn load_tokens(&self) -> Result<Vec<(String, InviteToken)>, StorageError>;
+    /// Revoked tokens, remembered so `MATRIX_TOKEN(S)` can't bring them back.
+    fn load_revoked_tokens(&self) -> Result<Vec<String>, StorageError>;
+    /// Saving a revoked token makes it live again.
+    fn save_token(&self, token: &str, invite: &InviteToken) -> Result<(), StorageError>;
+    fn revoke_token(&self, token: &str) -> Result<(), StorageError>;
+    fn record_registration(&self, registration: &Registration) -> Result<(), StorageError>;
+    fn load_applications(&self) -> Result<Vec<Application>, StorageError>;
+    fn save_application(&self, application: &Application) -> Result<(), StorageError>;
+}
+
+/// Keeps nothing; state is lost on restart like before persistence existed.
+pub struct MemoryStorage;
+
+impl Storage for MemoryStorage {
+    fn load_attempts(&self) -> Result<Vec<(IpAddr, Attempt)>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    fn save_attempt(&self, _ip: IpAddr, _attempt: &Attempt) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn delete_attempt(&self, _ip: IpAddr) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn clear_attempts(&self) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn load_tokens(&self) -> Result<Vec<(String, InviteToken)>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    fn load_revoked_tokens(&self) -> Result<Vec<String>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    fn save_token(&self, _token: &str, _invite: &InviteToken) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn revoke_token(&self, _token: &str) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn record_registration(&self, _registration: &Registration) -> Result<(), StorageError> {
+        Ok(())
+    }
+
+    fn load_applications(&self) -> Result<Vec<Application>, StorageError> {
+        Ok(Vec::new())
+    }
+
+    fn save_application(&self, _application: &Application) -> Result<(), StorageError> {
+        Ok(())
+    }
+}
+
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: impl AsRef<Path>) -> Result<Self, StorageError> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+             CREATE TABLE IF NOT EXISTS attempts (
+                 ip TEXT PRIMARY KEY,
+                 count INTEGER NOT NULL,
+                 last INTEGER NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS tokens (
+                 token TEXT PRIMARY KEY,
+                 max_uses INTEGER,
+                 uses INTEGER NOT NULL DEFAULT 0,
+                 expires_at INTEGER
+             );
+             CREATE TABLE IF NOT EXISTS registrations (
+                 id INTEGER PRIMARY KEY AUTOINCREMENT,
+                 username TEXT NOT NULL,
+                 registered_at INTEGER NOT NULL,
+                 token TEXT NOT NULL,
+                 client_ip TEXT NOT NULL
+             );
+             CREATE TABLE IF NOT EXISTS applications (
+                 id TEXT PRIMARY KEY,
+                 username TEXT NOT NULL,
+                 password BLOB,
+                 token TEXT NOT NULL,
+                 client_ip TEXT NOT NULL,
+                 display_name TEXT,
+                 email TEXT,
+                 submitted_at INTEGER NOT NULL,
+                 state TEXT NOT NULL,
+                 decided_at INTEGER
+             );",
+        )?;
+        migrate(&conn)?;
+        Ok(Self {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    fn conn(&self) -> std::sync::MutexGuard<'_, Connection> {
+        self.conn
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl Storage for SqliteStorage {
+    fn load_attempts(&self) -> Result<Vec<(IpAddr, Attempt)>, StorageError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT ip, count, last, hits FROM attempts")?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, u32>(1)?,
+                row.get::<_, i64>(2)?,
+                row.get::<_, Option<String>>(3)?,
+            ))
+        })?;
+
+        let mut attempts = Vec::new();
+        for row in rows {
+            let (ip, count, last, hits) = row?;
+            let ip = ip.parse().map_err(|_| StorageError::Corrupt {
+                table: "attempts",
+                detail: format!("invalid ip {ip:?}"),
+            })?;
+            let hits = match hits {
+                Some(hits) => hits
+                    .split(',')
+                    .filter(|hit| !hit.is_empty())
+                    .map(|hit| {
+                        let secs = hit.parse().map_err(|_| StorageError::Corrupt {
+                            table: "attempts",
+                            detail: format!("invalid hit {hit:?}"),
+                        })?;
+                        timestamp("attempts", secs)
+                    })
+                    .collect::<Result<_, _>>()?,
+                // Rows from before the sliding window only know a count and
+                // the time of the last attempt.
+                None => vec![timestamp("attempts", last)?; count as usize],
+            };
+            attempts.push((ip, Attempt::from_hits(hits)));
+        }
+        Ok(attempts)
+    }
+
+    fn save_attempt(&self, ip: IpAddr, attempt: &Attempt) -> Result<(), StorageError> {
+        self.conn().execute(
+            "INSERT INTO attempts (ip, count, last, hits) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(ip) DO UPDATE SET
+                 count = excluded.count,
+                 last = excluded.last,
+                 hits = excluded.hits",
+            params![
+                ip.to_string(),
+                attempt.hits().len(),
+                attempt.last().map_or(0, |at| at.timestamp()),
+                attempt
+                    .hits()
+                    .iter()
+                    .map(|hit| hit.timestamp().to_string())
+                    .collect::<Vec<_>>()
+                    .join(",")
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn delete_attempt(&self, ip: IpAddr) -> Result<(), StorageError> {
+        self.conn().execute(
+            "DELETE FROM attempts WHERE ip = ?1",
+            params![ip.to_string()],
+        )?;
+        Ok(())
+    }
+
+    fn clear_attempts(&self) -> Result<(), StorageError> {
+        self.conn().execute("DELETE FROM attempts", [])?;
+        Ok(())
+    }
+
+    fn load_tokens(&self) -> Result<Vec<(String, InviteToken)>, StorageError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT token, max_uses, uses, expires_at FROM tokens WHERE revoked_at IS NULL",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, Option<u32>>(1)?,
+                row.get::<_, u32>(2)?,
+                row.get::<_, Option<i64>>(3)?,
+            ))
+        })?;
+
+        let mut tokens = Vec::new();
+        for row in rows {
+            let (token, max_uses, uses, expires_at) = row?;
+            let expires_at = expires_at
+                .map(|secs| timestamp("tokens", secs))
+                .transpose()?;
+            tokens.push((
+                token,
+                InviteToken {
+                    max_uses,
+                    uses,
+                    expires_at,
+                },
+            ));
+        }
+        Ok(tokens)
+    }
+
+    fn load_revoked_tokens(&self) -> Result<Vec<String>, StorageError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare("SELECT token FROM tokens WHERE revoked_at IS NOT NULL")?;
+        let tokens = stmt
+            .query_map([], |row| row.get(0))?
+            .collect::<Result<_, _>>()?;
+        Ok(tokens)
+    }
+
+    fn save_token(&self, token: &str, invite: &InviteToken) -> Result<(), StorageError> {
+        self.conn().execute(
+            "INSERT INTO tokens (token, max_uses, uses, expires_at) VALUES (?1, ?2, ?3, ?4)
+             ON CONFLICT(token) DO UPDATE SET
+                 max_uses = excluded.max_uses,
+                 uses = excluded.uses,
+                 expires_at = excluded.expires_at,
+                 revoked_at = NULL",
+            params![
+                token,
+                invite.max_uses,
+                invite.uses,
+                invite.expires_at.map(|at| at.timestamp())
+            ],
+        )?;
+        Ok(())
+    }
+
+    /// Keeps the row as a tombstone rather than deleting it.
+    fn revoke_token(&self, token: &str) -> Result<(), StorageError> {
+        self.conn().execute(
+            "INSERT INTO tokens (token, revoked_at) VALUES (?1, ?2)
+             ON CONFLICT(token) DO UPDATE SET revoked_at = excluded.revoked_at",
+            params![token, Utc::now().timestamp()],
+        )?;
+        Ok(())
+    }
+
+    fn record_registration(&self, registration: &Registration) -> Result<(), StorageError> {
+        self.conn().execute(
+            "INSERT INTO registrations (username, registered_at, token, client_ip)
+             VALUES (?1, ?2, ?3, ?4)",
+            params![
+                registration.username,
+                registration.registered_at.timestamp(),
+                registration.token,
+                registration.client_ip.to_string()
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn load_applications(&self) -> Result<Vec<Application>, StorageError> {
+        let conn = self.conn();
+        let mut stmt = conn.prepare(
+            "SELECT id, username, password, token, client_ip, display_name, email,
+                    submitted_at, state, decided_at
+             FROM applications",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, Option<Vec<u8>>>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, String>(4)?,
+                row.get::<_, Option<String>>(5)?,
+                row.get::<_, Option<String>>(6)?,
+                row.get::<_, i64>(7)?,
+                row.get::<_, String>(8)?,
+                row.get::<_, Option<i64>>(9)?,
+            ))
+        })?;
+
+        let mut applications = Vec::new();
+        for row in rows {
+            let (
+                id,
+                username,
+                password,
+                token,
+                client_ip,
+                display_name,
+                email,
+                submitted_at,
+                state,
+                decided_at,
+            ) = row?;
+            let client_ip = client_ip.parse().map_err(|_| StorageError::Corrupt {
+                table: "applications",
+                detail: format!("invalid ip {client_ip:?}"),
+            })?;
+            let state: RegistrationState =
+                serde_json::from_value(serde_json::Value::String(state.clone())).map_err(|_| {
+                    StorageError::Corrupt {
+                        table: "applications",
+                        detail: format!("invalid state {state:?}"),
+                    }
+                })?;
+            applications.push(Application {
+                id,
+                username,
+                password,
+                token,
+                client_ip,
+                display_name,
+                email,
+                submitted_at: timestamp("applications", submitted_at)?,
+                state,
+                decided_at: decided_at
+                    .map(|secs| timestamp("applications", secs))
+                    .transpose()?,
+            });
+        }
+        Ok(applications)
+    }
+
+    fn save_application(&self, application: &Application) -> Result<(), StorageError> {
+        let state = serde_json::to_value(application.state)
+            .ok()
+            .and_then(|state| state.as_str().map(str::to_string))
+            .unwrap_or_default();
+        self.conn().execute(
+            "INSERT INTO applications (id, username, password, token, client_ip, display_name,
+                                       email, submitted_at, state, decided_at)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10)
+             ON CONFLICT(id) DO UPDATE SET
+                 password = excluded.password,
+                 state = excluded.state,
+                 decided_at = excluded.decided_at",
+            params![
+                application.id,
+                application.username,
+                application.password,
+                application.token,
+                application.client_ip.to_string(),
+                application.display_name,
+                application.email,
+                application.submitted_at.timestamp(),
+                state,
+                application.decided_at.map(|at| at.timestamp())
+            ],
+        )?;
+        Ok(())
+    }
+}
+
+/// Schema changes on top of the tables created in [`SqliteStorage::open`],
+/// tracked with `PRAGMA user_version`.
+fn migrate(conn: &Connection) -> Result<(), StorageError> {
+    let version: u32 = conn.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+    if version < 1 {
+        conn.execute_batch(
+            "ALTER TABLE attempts ADD COLUMN hits TEXT;
+             PRAGMA user_version = 1;",
+        )?;
+    }
+    if version < 2 {
+        conn.execute_batch(
+            "ALTER TABLE tokens ADD COLUMN revoked_at INTEGER;
+             PRAGMA user_version = 2;",
+        )?;
+    }
+    Ok(())
+}
+
+fn timestamp(table: &'static str, secs: i64) -> Result<DateTime<Utc>, StorageError> {
+    DateTime::from_timestamp(secs, 0).ok_or_else(|| StorageError::Corrupt {
+        table,
+        detail: format!("invalid timestamp {secs}"),
+    })
+}
@@ -0,0 +1,169 @@
+use std::sync::Arc;
+
+use chrono::Utc;
+use dashmap::DashMap;
+use reqwest::{Client, StatusCode as ReqStatusCode, Url};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, OwnedMutexGuard};
+
+use crate::tokens::TokenError;
+
+/// Invite tokens managed by Synapse's `/_synapse/admin/v1/registration_tokens`
+/// API instead of our own [`crate::tokens::TokenStore`].
+///
+/// The admin API has no "use" operation, so a limited token is spent by
+/// lowering its `uses_allowed` by one. Synapse can't do that atomically for
+/// us, so calls for the same token are serialized here; this assumes a single
+/// instance of this service talks to the homeserver. A token's lock only lives
+/// while a call for it is in flight, so arbitrary guesses don't pile up.
+pub struct SynapseTokens {
+    server: String,
+    admin_token: String,
+    locks: DashMap<String, Arc<Mutex<()>>>,
+}
+
+#[derive(Deserialize)]
+struct RegistrationToken {
+    uses_allowed: Option<u32>,
+    pending: u32,
+    completed: u32,
+    /// Milliseconds since the epoch.
+    expiry_time: Option<i64>,
+}
+
+impl RegistrationToken {
+    /// Checks the token can still be used, returning its limit if it has one.
+    fn usable(&self) -> Result<Option<u32>, TokenError> {
+        if self
+            .expiry_time
+            .is_some_and(|expiry| expiry <= Utc::now().timestamp_millis())
+        {
+            return Err(TokenError::Expired);
+        }
+        match self.uses_allowed {
+            Some(allowed) if self.pending + self.completed >= allowed => Err(TokenError::Exhausted),
+            uses_allowed => Ok(uses_allowed),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct UpdateUsesAllowed {
+    uses_allowed: u32,
+}
+
+impl SynapseTokens {
+    pub fn new(server: &str, admin_token: String) -> Self {
+        Self {
+            server: server.to_string(),
+            admin_token,
+            locks: DashMap::new(),
+        }
+    }
+
+    pub async fn consume(&self, client: &Client, token: &str) -> Result<(), TokenError> {
+        let _guard = self.lock(token).await;
+
+        match self.fetch(client, token).await?.usable()? {
+            None => Ok(()),
+            Some(allowed) => self.set_uses_allowed(client, token, allowed - 1).await,
+        }
+    }
+
+    /// Whether `token` could be spent right now, without spending it.
+    pub async fn check(&self, client: &Client, token: &str) -> Result<(), TokenError> {
+        self.fetch(client, token).await?.usable().map(|_| ())
+    }
+
+    /// Gives back the use taken by [`SynapseTokens::consume`].
+    pub async fn refund(&self, client: &Client, token: &str) -> Result<(), TokenError> {
+        let _guard = self.lock(token).await;
+
+        match self.fetch(client, token).await?.uses_allowed {
+            None => Ok(()),
+            Some(allowed) => self.set_uses_allowed(client, token, allowed + 1).await,
+        }
+    }
+
+    /// How many tokens currently have a lock in the map.
+    #[cfg(test)]
+    pub fn locked_tokens(&self) -> usize {
+        self.locks.len()
+    }
+
+    async fn lock(&self, token: &str) -> TokenLock<'_> {
+        // Built first so a caller cancelled while waiting still cleans up.
+        let mut held = TokenLock {
+            locks: &self.locks,
+            token: token.to_string(),
+            guard: None,
+        };
+        let lock = self.locks.entry(token.to_string()).or_default().clone();
+        held.guard = Some(lock.lock_owned().await);
+        held
+    }
+
+    fn url(&self, token: &str) -> Result<Url, TokenError> {
+        let mut url = Url::parse(&self.server).map_err(|e| TokenError::Upstream(e.to_string()))?;
+        url.path_segments_mut()
+            .map_err(|_| TokenError::Upstream("MATRIX_SERVER can't be a base URL".into()))?
+            .extend(["_synapse", "admin", "v1", "registration_tokens", token]);
+        Ok(url)
+    }
+
+    async fn fetch(&self, client: &Client, token: &str) -> Result<RegistrationToken, TokenError> {
+        let response = client
+            .get(self.url(token)?)
+            .bearer_auth(&self.admin_token)
+            .send()
+            .await
+            .map_err(|e| TokenError::Upstream(e.to_string()))?;
+
+        match response.status() {
+            ReqStatusCode::OK => response
+                .json()
+                .await
+                .map_err(|e| TokenError::Upstream(e.to_string())),
+            // Synapse answers 400 for tokens with characters it never issues.
+            ReqStatusCode::NOT_FOUND | ReqStatusCode::BAD_REQUEST => Err(TokenError::Unknown),
+            status => Err(TokenError::Upstream(format!(
+                "unexpected status {status} looking up registration token"
+            ))),
+        }
+    }
+
+    async fn set_uses_allowed(
+        &self,
+        client: &Client,
+        token: &str,
+        uses_allowed: u32,
+    ) -> Result<(), TokenError> {
+        client
+            .put(self.url(token)?)
+            .bearer_auth(&self.admin_token)
+            .json(&UpdateUsesAllowed { uses_allowed })
+            .send()
+            .await
+            .and_then(reqwest::Response::error_for_status)
+            .map_err(|e| TokenError::Upstream(e.to_string()))?;
+        Ok(())
+    }
+}
+
+/// Holds a token's lock, and drops it from the map when nobody else is
+/// waiting on it.
+struct TokenLock<'a> {
+    locks: &'a DashMap<String, Arc<Mutex<()>>>,
+    token: String,
+    guard: Option<OwnedMutexGuard<()>>,
+}
+
+impl Drop for TokenLock<'_> {
+    fn drop(&mut self) {
+        self.guard.take();
+        // Waiters hold a clone, and new callers are kept out by the shard lock
+        // while this runs.
+        self.locks
+            .remove_if(&self.token, |_, lock| Arc::strong_count(lock) == 1);
+    }
+}
@@ -0,0 +1,192 @@
+use std::sync::Arc;
+
+use reqwest::{Method, StatusCode};
+use serde_json::json;
+
+use super::{admin, config, form, MockSynapse, TestApp, ADMIN_SECRET, SHARED_SECRET, TOKEN};
+use crate::storage::{SqliteStorage, Storage};
+
+async fn setup() -> (MockSynapse, TestApp) {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.admin_secret = Some(ADMIN_SECRET.to_string());
+    (synapse, TestApp::start(config).await)
+}
+
+#[tokio::test]
+async fn requires_the_bearer_secret() {
+    let (_synapse, app) = setup().await;
+    let client = reqwest::Client::new();
+    let url = format!("{}/admin/tokens", app.url);
+
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = client.get(&url).bearer_auth("wrong").send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = client
+        .get(&url)
+        .header(reqwest::header::AUTHORIZATION, ADMIN_SECRET)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let (status, _) = admin(&app.url, Method::GET, "/admin/tokens", None).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn not_mounted_without_a_secret() {
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let app = TestApp::start(config(&server)).await;
+
+    let (status, _) = admin(&app.url, Method::GET, "/admin/tokens", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn token_changes_are_persisted() {
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let path = std::env::temp_dir().join(format!("admin-tokens-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut config = config(&server);
+    config.admin_secret = Some(ADMIN_SECRET.to_string());
+    let storage = Arc::new(SqliteStorage::open(&path).unwrap());
+    let app = TestApp::start_with_storage(config, storage).await;
+    let stored = |token: &str| {
+        SqliteStorage::open(&path)
+            .unwrap()
+            .load_tokens()
+            .unwrap()
+            .into_iter()
+            .find(|(known, _)| known == token)
+            .map(|(_, invite)| invite)
+    };
+
+    let (status, body) = admin(
+        &app.url,
+        Method::POST,
+        "/admin/tokens",
+        Some(json!({ "token": "party", "maxUses": 2 })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(body["token"], "party");
+    assert_eq!(stored("party").unwrap().max_uses, Some(2));
+
+    let (status, _) = admin(
+        &app.url,
+        Method::POST,
+        "/admin/tokens",
+        Some(json!({ "token": "party" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = admin(
+        &app.url,
+        Method::POST,
+        "/admin/tokens",
+        Some(json!({ "token": "not valid!" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, body) = admin(&app.url, Method::POST, "/admin/tokens", Some(json!({}))).await;
+    assert_eq!(status, StatusCode::CREATED);
+    assert_eq!(body["token"].as_str().unwrap().len(), 24);
+
+    let (status, body) = admin(
+        &app.url,
+        Method::PATCH,
+        "/admin/tokens/party",
+        Some(json!({ "maxUses": null, "uses": 1 })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["maxUses"], serde_json::Value::Null);
+    assert_eq!(body["uses"], 1);
+    let invite = stored("party").unwrap();
+    assert_eq!((invite.max_uses, invite.uses), (None, 1));
+
+    let (status, body) = admin(&app.url, Method::GET, "/admin/tokens/party", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["uses"], 1);
+
+    let (status, _) = admin(&app.url, Method::DELETE, "/admin/tokens/party", None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    assert!(stored("party").is_none());
+    let (status, _) = admin(&app.url, Method::GET, "/admin/tokens/party", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, body) = app.register(&form("alice", "hunter22", "party")).await;
+    assert_eq!(body["registrationState"], "INVALID_TOKEN");
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn revoked_config_tokens_stay_revoked() {
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let path = std::env::temp_dir().join(format!("admin-revoke-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let mut config = config(&server);
+    config.admin_secret = Some(ADMIN_SECRET.to_string());
+    let app = TestApp::start_with_storage(
+        config.clone(),
+        Arc::new(SqliteStorage::open(&path).unwrap()),
+    )
+    .await;
+
+    let path_to_token = format!("/admin/tokens/{TOKEN}");
+    let (status, _) = admin(&app.url, Method::DELETE, &path_to_token, None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    drop(app);
+
+    // `TOKEN` is still in the config.
+    let app =
+        TestApp::start_with_storage(config, Arc::new(SqliteStorage::open(&path).unwrap())).await;
+    let (_, body) = app.register(&form("alice", "hunter22", TOKEN)).await;
+    assert_eq!(body["registrationState"], "INVALID_TOKEN");
+    let (status, _) = admin(&app.url, Method::GET, &path_to_token, None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn clearing_attempts_lifts_a_block() {
+    let (_synapse, app) = setup().await;
+    for _ in 0..3 {
+        app.register(&form("alice", "hunter22", "wrong")).await;
+    }
+    let (_, body) = app.register(&form("alice", "hunter22", TOKEN)).await;
+    assert_eq!(body["registrationState"], "BLOCKED");
+
+    let (status, body) = admin(&app.url, Method::GET, "/admin/attempts", None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body[0]["ip"], "127.0.0.1");
+    assert_eq!(body[0]["count"], 3);
+    assert!(body[0]["blockedForSecs"].as_i64().unwrap() > 0);
+
+    let (status, _) = admin(&app.url, Method::DELETE, "/admin/attempts/127.0.0.1", None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (status, _) = admin(&app.url, Method::DELETE, "/admin/attempts/127.0.0.1", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (_, body) = app.register(&form("alice", "hunter22", TOKEN)).await;
+    assert_eq!(body["registrationState"], "REGISTERED");
+}
+
+#[tokio::test]
+async fn clearing_all_attempts() {
+    let (_synapse, app) = setup().await;
+    for _ in 0..3 {
+        app.register(&form("alice", "hunter22", "wrong")).await;
+    }
+
+    let (status, _) = admin(&app.url, Method::DELETE, "/admin/attempts", None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+    let (_, body) = admin(&app.url, Method::GET, "/admin/attempts", None).await;
+    assert_eq!(body, json!([]));
+    let (_, body) = app.register(&form("alice", "hunter22", TOKEN)).await;
+    assert_eq!(body["registrationState"], "REGISTERED");
+}
@@ -0,0 +1,125 @@
+use reqwest::StatusCode;
+use serde_json::json;
+
+use super::{config, MockSynapse, TestApp, SHARED_SECRET, TOKEN};
+
+async fn setup() -> (MockSynapse, TestApp) {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    (synapse, TestApp::start(config(&server)).await)
+}
+
+fn json_body(username: &str, password: &str) -> String {
+    json!({
+        "username": username,
+        "password": password,
+        "passwordConfirmation": password,
+        "token": TOKEN,
+    })
+    .to_string()
+}
+
+#[tokio::test]
+async fn registers_from_json() {
+    let (synapse, app) = setup().await;
+
+    let (status, body) = app
+        .post(
+            "/api/v1/register",
+            "application/json; charset=utf-8",
+            json_body("alice", "hunter22"),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert_eq!(body["username"], "alice");
+    assert!(body.get("error").is_none());
+    assert!(synapse.users().contains("alice"));
+}
+
+#[tokio::test]
+async fn registers_from_form() {
+    let (synapse, app) = setup().await;
+
+    let (status, body) = app
+        .post(
+            "/api/v1/register",
+            "application/x-www-form-urlencoded",
+            format!("username=bob&password=hunter22&passwordConfirmation=hunter22&token={TOKEN}"),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert!(synapse.users().contains("bob"));
+}
+
+#[tokio::test]
+async fn failures_carry_an_error_object() {
+    let (_synapse, app) = setup().await;
+
+    let mut body: serde_json::Value =
+        serde_json::from_str(&json_body("alice", "hunter22")).unwrap();
+    body["passwordConfirmation"] = json!("hunter23");
+    let (_, body) = app
+        .post("/api/v1/register", "application/json", body.to_string())
+        .await;
+    assert_eq!(body["registrationState"], "INVALID_PASSWORD_VERIFICATION");
+    assert_eq!(body["error"]["code"], "INVALID_PASSWORD_VERIFICATION");
+    assert_eq!(body["error"]["field"], "passwordConfirmation");
+    assert!(body["error"]["message"]
+        .as_str()
+        .is_some_and(|m| !m.is_empty()));
+
+    let (_, body) = app
+        .post(
+            "/api/v1/register",
+            "application/json",
+            json_body("admin", "hunter22"),
+        )
+        .await;
+    assert_eq!(body["error"]["code"], "USERNAME_RESERVED");
+    assert_eq!(body["error"]["field"], "username");
+}
+
+#[tokio::test]
+async fn rejects_unreadable_bodies() {
+    let (synapse, app) = setup().await;
+
+    let (status, body) = app
+        .post(
+            "/api/v1/register",
+            "application/json",
+            "{\"username\":".into(),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["registrationState"], "INVALID_REQUEST");
+    assert_eq!(body["error"]["code"], "INVALID_REQUEST");
+
+    let (status, body) = app
+        .post(
+            "/api/v1/register",
+            "text/plain",
+            json_body("alice", "hunter22"),
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(body["registrationState"], "INVALID_REQUEST");
+
+    assert!(synapse.users().is_empty());
+}
+
+#[tokio::test]
+async fn legacy_endpoint_takes_json_too() {
+    let (synapse, app) = setup().await;
+
+    let (status, body) = app
+        .post(
+            "/registration",
+            "application/json",
+            json_body("carol", "hunter22"),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert!(synapse.users().contains("carol"));
+}
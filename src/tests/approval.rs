@@ -0,0 +1,289 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use reqwest::StatusCode;
+use serde_json::Value;
+
+use super::{
+    admin, config, eventually, form, spawn, MockSynapse, TestApp, ADMIN_SECRET, SHARED_SECRET,
+    TOKEN,
+};
+use crate::approval::ApprovalConfig;
+use crate::storage::SqliteStorage;
+use crate::tokens::InviteToken;
+use crate::{app, AppConfig, AppState, StatusCodes};
+
+const PASSWORD: &str = "correct-horse-battery";
+
+fn approval_config(server: &str) -> AppConfig {
+    let mut config = config(server);
+    config.admin_secret = Some(ADMIN_SECRET.to_string());
+    config.approval = Some(ApprovalConfig { key: [7; 32] });
+    config
+}
+
+async fn setup() -> (MockSynapse, TestApp) {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    (synapse, TestApp::start(approval_config(&server)).await)
+}
+
+async fn decide(app: &TestApp, id: &str, decision: &str) -> (StatusCode, Value) {
+    admin(
+        &app.url,
+        reqwest::Method::POST,
+        &format!("/admin/approvals/{id}/{decision}"),
+        None,
+    )
+    .await
+}
+
+/// Registers `username` and returns the id from its status URL.
+async fn apply(app: &TestApp, username: &str) -> String {
+    let (status, body) = app.register(&form(username, PASSWORD, TOKEN)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["registrationState"], "PENDING_APPROVAL");
+    body["statusUrl"]
+        .as_str()
+        .unwrap()
+        .strip_prefix("/api/v1/register/")
+        .unwrap()
+        .to_string()
+}
+
+#[tokio::test]
+async fn signup_waits_for_approval() {
+    let (synapse, app) = setup().await;
+
+    let id = apply(&app, "alice").await;
+    assert!(synapse.users().is_empty());
+
+    let (status, body) = app.get(&format!("/api/v1/register/{id}")).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["registrationState"], "PENDING_APPROVAL");
+    assert_eq!(body["username"], "alice");
+
+    let (status, queue) = admin(&app.url, reqwest::Method::GET, "/admin/approvals", None).await;
+    assert_eq!(status, StatusCode::OK);
+    let queue = queue.as_array().unwrap();
+    assert_eq!(queue.len(), 1);
+    assert_eq!(queue[0]["id"], id.as_str());
+    assert_eq!(queue[0]["username"], "alice");
+    assert!(queue[0].get("password").is_none());
+    assert!(queue[0].get("token").is_none());
+
+    let (status, body) = decide(&app, &id, "approve").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["state"], "REGISTERED");
+    assert_eq!(synapse.password("alice").as_deref(), Some(PASSWORD));
+
+    let (_, body) = app.get(&format!("/api/v1/register/{id}")).await;
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert!(body.get("statusUrl").is_none());
+    let (_, queue) = admin(&app.url, reqwest::Method::GET, "/admin/approvals", None).await;
+    assert_eq!(queue, serde_json::json!([]));
+}
+
+#[tokio::test]
+async fn rejection_gives_the_token_use_back() {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = approval_config(&server);
+    config.tokens = vec![(
+        TOKEN.to_string(),
+        InviteToken {
+            max_uses: Some(1),
+            uses: 0,
+            expires_at: None,
+        },
+    )];
+    let app = TestApp::start(config).await;
+
+    let id = apply(&app, "alice").await;
+    let (_, body) = app.register(&form("bob", PASSWORD, TOKEN)).await;
+    assert_eq!(body["registrationState"], "INVALID_TOKEN");
+
+    let (status, body) = decide(&app, &id, "reject").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["state"], "REJECTED");
+    let (_, body) = app.get(&format!("/api/v1/register/{id}")).await;
+    assert_eq!(body["registrationState"], "REJECTED");
+
+    let (status, _) = decide(&app, &id, "approve").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(synapse.users().is_empty());
+
+    apply(&app, "bob").await;
+}
+
+#[tokio::test]
+async fn queued_username_is_taken() {
+    let (_synapse, app) = setup().await;
+    apply(&app, "alice").await;
+
+    let (status, body) = app.register(&form("alice", PASSWORD, TOKEN)).await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["registrationState"], "USER_EXISTS");
+
+    let (_, body) = app.get("/username_available?username=alice").await;
+    assert_eq!(body["available"], false);
+}
+
+#[tokio::test]
+async fn homeserver_failure_keeps_the_application_pending() {
+    let (synapse, app) = setup().await;
+    let id = apply(&app, "alice").await;
+    synapse.fail_with(500);
+
+    let (status, _) = decide(&app, &id, "approve").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    // The password was put back, so the admin can try again.
+    let (status, _) = decide(&app, &id, "approve").await;
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+
+    let (_, body) = app.get(&format!("/api/v1/register/{id}")).await;
+    assert_eq!(body["registrationState"], "PENDING_APPROVAL");
+}
+
+#[tokio::test]
+async fn homeserver_refusal_decides_the_application() {
+    let (synapse, app) = setup().await;
+    let id = apply(&app, "alice").await;
+    synapse.add_user("alice");
+
+    let (status, body) = decide(&app, &id, "approve").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["state"], "USER_EXISTS");
+    let (_, body) = app.get(&format!("/api/v1/register/{id}")).await;
+    assert_eq!(body["registrationState"], "USER_EXISTS");
+}
+
+#[tokio::test]
+async fn strict_status_codes() {
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = approval_config(&server);
+    config.status_codes = StatusCodes::Strict;
+    let app = TestApp::start(config).await;
+
+    let (status, body) = app.register(&form("alice", PASSWORD, TOKEN)).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let status_url = body["statusUrl"].as_str().unwrap();
+    let (status, _) = app.get(status_url).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+
+    let id = status_url.rsplit('/').next().unwrap();
+    decide(&app, id, "reject").await;
+    let (status, body) = app.get(status_url).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error"]["code"], "REJECTED");
+
+    let (status, _) = app.get("/api/v1/register/unknown").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn admin_api_requires_the_secret() {
+    let (_synapse, app) = setup().await;
+    let id = apply(&app, "alice").await;
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/approvals/{id}/approve", app.url))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn queue_survives_restart_with_the_password_encrypted() {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let path = std::env::temp_dir().join(format!("approval-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let start = || async {
+        let storage = Arc::new(SqliteStorage::open(&path).unwrap());
+        let state = AppState::new(approval_config(&server), storage).unwrap();
+        let url = format!("http://{}", spawn(app(state.clone())).await);
+        (state, url)
+    };
+
+    let (first, url) = start().await;
+    let response = reqwest::Client::new()
+        .post(format!("{url}/registration"))
+        .form(&form("alice", PASSWORD, TOKEN))
+        .send()
+        .await
+        .unwrap();
+    let body: Value = response.json().await.unwrap();
+    let status_url = body["statusUrl"].as_str().unwrap().to_string();
+    drop(first);
+
+    let conn = rusqlite::Connection::open(&path).unwrap();
+    let stored: Vec<u8> = conn
+        .query_row("SELECT password FROM applications", [], |row| row.get(0))
+        .unwrap();
+    assert!(!stored
+        .windows(PASSWORD.len())
+        .any(|window| window == PASSWORD.as_bytes()));
+
+    let (_second, url) = start().await;
+    let id = status_url.rsplit('/').next().unwrap();
+    let (status, body) = admin(
+        &url,
+        reqwest::Method::POST,
+        &format!("/admin/approvals/{id}/approve"),
+        None,
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(synapse.password("alice").as_deref(), Some(PASSWORD));
+
+    let stored: Option<Vec<u8>> = conn
+        .query_row("SELECT password FROM applications", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(stored, None);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn dropped_claim_leaves_the_application_pending() {
+    let (synapse, app) = setup().await;
+    let id = apply(&app, "alice").await;
+    let approvals = app.state.approvals.as_ref().unwrap();
+
+    let claim = approvals.claim(&id).unwrap();
+    assert!(approvals.claim(&id).is_none());
+    let (status, _) = decide(&app, &id, "reject").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    drop(claim);
+
+    let (status, body) = decide(&app, &id, "approve").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["state"], "REGISTERED");
+    assert_eq!(synapse.password("alice").as_deref(), Some(PASSWORD));
+}
+
+#[tokio::test]
+async fn approval_finishes_after_the_admin_hangs_up() {
+    let (synapse, app) = setup().await;
+    let id = apply(&app, "alice").await;
+    synapse.delay_with(Duration::from_millis(300));
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/admin/approvals/{id}/approve", app.url))
+        .bearer_auth(ADMIN_SECRET)
+        .timeout(Duration::from_millis(50))
+        .send()
+        .await;
+    assert!(response.is_err());
+
+    eventually(|| {
+        app.state
+            .approvals
+            .as_ref()
+            .unwrap()
+            .get(&id)
+            .is_some_and(|application| !application.is_pending())
+    })
+    .await;
+    let (_, body) = app.get(&format!("/api/v1/register/{id}")).await;
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert!(synapse.users().contains("alice"));
+}
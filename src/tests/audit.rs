@@ -0,0 +1,159 @@
+use std::path::{Path, PathBuf};
+
+use serde_json::Value;
+
+use super::{config, form, MockSynapse, TestApp, SHARED_SECRET, TOKEN};
+use crate::audit::{AuditConfig, AuditLog};
+
+/// A fresh directory for one test's log files.
+fn log_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("audit-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn lines(path: &Path) -> Vec<Value> {
+    std::fs::read_to_string(path)
+        .unwrap()
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn records_each_attempt_without_the_token() {
+    let dir = log_dir("records");
+    let path = dir.join("audit.jsonl");
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.audit = Some(AuditConfig {
+        path: path.clone(),
+        max_bytes: 1024 * 1024,
+        retention: chrono::Duration::days(30),
+    });
+    let app = TestApp::start(config).await;
+    synapse.add_user("bob");
+
+    app.register(&form("alice", "hunter22", TOKEN)).await;
+    app.register(&form("bob", "hunter22", TOKEN)).await;
+    app.register(&form("carol", "hunter22", "WrongToken")).await;
+
+    let events = lines(&path);
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0]["username"], "alice");
+    assert_eq!(events[0]["outcome"], "REGISTERED");
+    assert_eq!(events[0]["clientIp"], "127.0.0.1");
+    assert_eq!(events[0]["tokenId"], app.state.token_id(TOKEN));
+    assert!(events[0]["upstreamMs"].is_u64());
+    assert!(events[0]["timestamp"].is_string());
+    assert!(events[0]["userAgent"].is_null());
+    assert_eq!(events[1]["outcome"], "USER_EXISTS");
+    assert_eq!(events[2]["outcome"], "INVALID_TOKEN");
+    assert_eq!(events[2]["tokenId"], app.state.token_id("WrongToken"));
+    // Rejected before reaching the homeserver.
+    assert!(events[2].get("upstreamMs").is_none());
+
+    let raw = std::fs::read_to_string(&path).unwrap();
+    assert!(!raw.contains(TOKEN) && !raw.contains("WrongToken"));
+    assert!(!raw.contains("hunter22"));
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn rotates_and_prunes_old_files() {
+    let dir = log_dir("rotates");
+    let path = dir.join("audit.jsonl");
+    let stale = dir.join("audit.jsonl.20000101T000000.000Z");
+    std::fs::write(&stale, "{}\n").unwrap();
+    std::fs::File::options()
+        .write(true)
+        .open(&stale)
+        .unwrap()
+        .set_modified(std::time::SystemTime::UNIX_EPOCH)
+        .unwrap();
+
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.audit = Some(AuditConfig {
+        path: path.clone(),
+        // Every line gets a file of its own.
+        max_bytes: 1,
+        retention: chrono::Duration::days(30),
+    });
+    let app = TestApp::start(config).await;
+    assert!(!stale.exists());
+
+    for name in ["alice", "bob", "carol"] {
+        app.register(&form(name, "hunter22", TOKEN)).await;
+        // Rotated names have millisecond resolution.
+        tokio::time::sleep(std::time::Duration::from_millis(5)).await;
+    }
+
+    let mut rotated: Vec<_> = std::fs::read_dir(&dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|file| file != &path)
+        .collect();
+    rotated.sort();
+    assert_eq!(rotated.len(), 2);
+    assert_eq!(lines(&rotated[0])[0]["username"], "alice");
+    assert_eq!(lines(&rotated[1])[0]["username"], "bob");
+    assert_eq!(lines(&path)[0]["username"], "carol");
+    std::fs::remove_dir_all(dir).unwrap();
+}
+
+#[tokio::test]
+async fn rotates_a_file_from_a_previous_day() {
+    let dir = log_dir("daily");
+    let path = dir.join("audit.jsonl");
+    let yesterday = std::time::SystemTime::now() - std::time::Duration::from_secs(24 * 60 * 60);
+    let backdate = |path: &Path| {
+        std::fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(yesterday)
+            .unwrap();
+    };
+    let rotated = || {
+        std::fs::read_dir(&dir)
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().path() != path)
+            .count()
+    };
+    std::fs::write(&path, "{}\n").unwrap();
+    backdate(&path);
+
+    let log = AuditLog::open(AuditConfig {
+        path: path.clone(),
+        max_bytes: 1024 * 1024,
+        retention: chrono::Duration::days(30),
+    })
+    .unwrap();
+    // Nothing is written to it, so the sweep rotates it.
+    log.sweep().unwrap();
+    assert_eq!(rotated(), 1);
+    assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    log.sweep().unwrap();
+    assert_eq!(rotated(), 1);
+    drop(log);
+
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    std::fs::write(&path, "{}\n").unwrap();
+    backdate(&path);
+    let mut config = config(&server);
+    config.audit = Some(AuditConfig {
+        path: path.clone(),
+        max_bytes: 1024 * 1024,
+        retention: chrono::Duration::days(30),
+    });
+    let app = TestApp::start(config).await;
+    app.register(&form("alice", "hunter22", TOKEN)).await;
+
+    assert_eq!(rotated(), 2);
+    let today = lines(&path);
+    assert_eq!(today.len(), 1);
+    assert_eq!(today[0]["username"], "alice");
+    std::fs::remove_dir_all(dir).unwrap();
+}
@@ -0,0 +1,64 @@
+use reqwest::StatusCode;
+
+use super::{config, MockSynapse, TestApp, SHARED_SECRET};
+use crate::ratelimit::RateLimitPolicy;
+
+async fn setup() -> (MockSynapse, TestApp) {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    (synapse, TestApp::start(config(&server)).await)
+}
+
+#[tokio::test]
+async fn reports_free_and_taken_names() {
+    let (synapse, app) = setup().await;
+    synapse.add_user("bob");
+
+    let (status, body) = app.get("/username_available?username=Alice").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["username"], "alice");
+    assert_eq!(body["available"], true);
+    assert!(body.get("reason").is_none());
+
+    let (_, body) = app.get("/username_available?username=bob").await;
+    assert_eq!(body["available"], false);
+    assert_eq!(body["reason"], "USER_EXISTS");
+
+    assert_eq!(synapse.availability_queries(), ["alice", "bob"]);
+}
+
+#[tokio::test]
+async fn applies_username_rules_before_asking() {
+    let (synapse, app) = setup().await;
+
+    let (_, body) = app.get("/username_available?username=al%20ice").await;
+    assert_eq!(body["available"], false);
+    assert_eq!(body["reason"], "INVALID_USERNAME");
+
+    let (_, body) = app.get("/username_available?username=admin").await;
+    assert_eq!(body["reason"], "USERNAME_RESERVED");
+
+    assert!(synapse.availability_queries().is_empty());
+}
+
+#[tokio::test]
+async fn limits_checks_per_ip() {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.availability_rate_limit = RateLimitPolicy {
+        max_attempts: 2,
+        ..RateLimitPolicy::default()
+    };
+    let app = TestApp::start(config).await;
+
+    for name in ["alice", "bob"] {
+        let (status, _) = app
+            .get(&format!("/username_available?username={name}"))
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    let (status, body) = app.get("/username_available?username=carol").await;
+
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+    assert_eq!(body["reason"], "BLOCKED");
+    assert_eq!(synapse.availability_queries(), ["alice", "bob"]);
+}
@@ -0,0 +1,314 @@
+//! The non-Synapse registration backends against minimal stand-ins for the
+//! endpoints they call.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Path, State},
+    http::{header, HeaderMap, StatusCode},
+    response::{IntoResponse, Json, Response},
+    routing::{get, post},
+    Router,
+};
+use serde_json::{json, Value};
+
+use super::{config, form, spawn, MockSynapse, TestApp, SHARED_SECRET, TOKEN};
+use crate::backend::{BackendConfig, RegisterError};
+
+/// Usernames mapped to passwords, shared between a mock and its test.
+type Accounts = Arc<Mutex<HashMap<String, String>>>;
+
+const CONDUIT_TOKEN: &str = "conduit-registration-token";
+/// A password the MAS stand-in refuses to set.
+const MAS_REJECTED_PASSWORD: &str = "rejected-by-mas";
+/// What the MAS stand-in stores in place of a deactivated user's password.
+const DEACTIVATED: &str = "(deactivated)";
+
+async fn conduit_register(State(accounts): State<Accounts>, Json(body): Json<Value>) -> Response {
+    let auth = &body["auth"];
+    if auth.is_null() {
+        return (
+            StatusCode::UNAUTHORIZED,
+            Json(json!({
+                "session": "uia-session",
+                "flows": [{ "stages": ["m.login.registration_token"] }],
+            })),
+        )
+            .into_response();
+    }
+    if auth["type"] != "m.login.registration_token"
+        || auth["token"] != CONDUIT_TOKEN
+        || auth["session"] != "uia-session"
+    {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+
+    let username = body["username"].as_str().unwrap().to_string();
+    let password = body["password"].as_str().unwrap().to_string();
+    let mut accounts = accounts.lock().unwrap();
+    if accounts.contains_key(&username) {
+        return (
+            StatusCode::BAD_REQUEST,
+            Json(json!({ "errcode": "M_USER_IN_USE", "error": "taken" })),
+        )
+            .into_response();
+    }
+    accounts.insert(username, password);
+    Json(json!({ "user_id": "@someone:localhost" })).into_response()
+}
+
+async fn start_conduit() -> (Accounts, TestApp) {
+    let accounts = Accounts::default();
+    let router = Router::new()
+        .route("/_matrix/client/v3/register", post(conduit_register))
+        .with_state(accounts.clone());
+    let addr = spawn(router).await;
+
+    let mut config = config(&format!("http://{addr}"));
+    config.backend = BackendConfig::Conduit {
+        registration_token: CONDUIT_TOKEN.to_string(),
+    };
+    config.metrics = true;
+    (accounts, TestApp::start(config).await)
+}
+
+fn has_bearer(headers: &HeaderMap, token: &str) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        == Some(&format!("Bearer {token}"))
+}
+
+async fn mas_token(headers: HeaderMap) -> Response {
+    // "mas-client:mas-secret", base64 encoded.
+    let expected = "Basic bWFzLWNsaWVudDptYXMtc2VjcmV0";
+    if headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        != Some(expected)
+    {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Json(json!({ "access_token": "mas-admin", "token_type": "Bearer", "expires_in": 300 }))
+        .into_response()
+}
+
+async fn mas_create_user(
+    State(accounts): State<Accounts>,
+    headers: HeaderMap,
+    Json(body): Json<Value>,
+) -> Response {
+    if !has_bearer(&headers, "mas-admin") {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    let username = body["username"].as_str().unwrap().to_string();
+    let mut accounts = accounts.lock().unwrap();
+    if accounts.contains_key(&username) {
+        return StatusCode::CONFLICT.into_response();
+    }
+    accounts.insert(username.clone(), String::new());
+    (
+        StatusCode::CREATED,
+        Json(json!({ "data": { "type": "user", "id": username, "attributes": {} } })),
+    )
+        .into_response()
+}
+
+async fn mas_set_password(
+    State(accounts): State<Accounts>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+    Json(body): Json<Value>,
+) -> StatusCode {
+    if !has_bearer(&headers, "mas-admin") {
+        return StatusCode::UNAUTHORIZED;
+    }
+    let password = body["password"].as_str().unwrap().to_string();
+    if password == MAS_REJECTED_PASSWORD {
+        return StatusCode::BAD_REQUEST;
+    }
+    match accounts.lock().unwrap().get_mut(&id) {
+        Some(stored) => {
+            *stored = password;
+            StatusCode::NO_CONTENT
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn mas_deactivate(
+    State(accounts): State<Accounts>,
+    headers: HeaderMap,
+    Path(id): Path<String>,
+) -> StatusCode {
+    if !has_bearer(&headers, "mas-admin") {
+        return StatusCode::UNAUTHORIZED;
+    }
+    match accounts.lock().unwrap().get_mut(&id) {
+        Some(stored) => {
+            *stored = DEACTIVATED.to_string();
+            StatusCode::OK
+        }
+        None => StatusCode::NOT_FOUND,
+    }
+}
+
+async fn mas_user_by_username(
+    State(accounts): State<Accounts>,
+    headers: HeaderMap,
+    Path(username): Path<String>,
+) -> Response {
+    if !has_bearer(&headers, "mas-admin") {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    if !accounts.lock().unwrap().contains_key(&username) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    Json(json!({ "data": { "type": "user", "id": username, "attributes": {} } })).into_response()
+}
+
+async fn start_mas() -> (Accounts, TestApp) {
+    let accounts = Accounts::default();
+    let router = Router::new()
+        .route("/oauth2/token", post(mas_token))
+        .route("/api/admin/v1/users", post(mas_create_user))
+        .route(
+            "/api/admin/v1/users/:id/set-password",
+            post(mas_set_password),
+        )
+        .route("/api/admin/v1/users/:id/deactivate", post(mas_deactivate))
+        .route(
+            "/api/admin/v1/users/by-username/:username",
+            get(mas_user_by_username),
+        )
+        .with_state(accounts.clone());
+    let addr = spawn(router).await;
+
+    // Accounts are created in MAS; `MATRIX_SERVER` isn't contacted.
+    let mut config = config("http://127.0.0.1:9");
+    config.backend = BackendConfig::Mas {
+        url: format!("http://{addr}/"),
+        client_id: "mas-client".to_string(),
+        client_secret: "mas-secret".to_string(),
+    };
+    config.metrics = true;
+    (accounts, TestApp::start(config).await)
+}
+
+/// Whether `/metrics` counted `count` account creations.
+async fn observed_registrations(app: &TestApp, count: u32) -> bool {
+    let (_, body) = app.get_text("/metrics").await;
+    body.lines().any(|line| {
+        line == format!(
+            "matrix_registration_upstream_request_duration_seconds_count{{request=\"register\"}} {count}"
+        )
+    })
+}
+
+#[tokio::test]
+async fn dendrite_uses_shared_secret_endpoint() {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.backend = BackendConfig::Dendrite {
+        shared_secret: SHARED_SECRET.to_string(),
+    };
+    let app = TestApp::start(config).await;
+
+    let (_, body) = app.register(&form("alice", "hunter2", TOKEN)).await;
+
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert!(synapse.users().contains("alice"));
+}
+
+#[tokio::test]
+async fn shared_secret_is_checked_without_creating_anyone() {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let app = TestApp::start(config(&server)).await;
+    assert!(app.state.backend.verify_credentials().await.is_ok());
+
+    let (_, server) = MockSynapse::start("some-other-secret").await;
+    let app = TestApp::start(config(&server)).await;
+    assert!(matches!(
+        app.state.backend.verify_credentials().await,
+        Err(RegisterError::SharedSecretRejected(_))
+    ));
+
+    assert!(synapse.users().is_empty());
+}
+
+#[tokio::test]
+async fn conduit_registers_with_registration_token() {
+    let (accounts, app) = start_conduit().await;
+
+    let (_, body) = app.register(&form("alice", "hunter2", TOKEN)).await;
+
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert_eq!(accounts.lock().unwrap()["alice"], "hunter2");
+    // Both auth rounds count as one.
+    assert!(observed_registrations(&app, 1).await);
+}
+
+#[tokio::test]
+async fn conduit_reports_existing_user() {
+    let (accounts, app) = start_conduit().await;
+    accounts
+        .lock()
+        .unwrap()
+        .insert("alice".to_string(), "old".to_string());
+
+    let (_, body) = app.register(&form("alice", "hunter2", TOKEN)).await;
+
+    assert_eq!(body["registrationState"], "USER_EXISTS");
+}
+
+#[tokio::test]
+async fn mas_creates_user_and_sets_password() {
+    let (accounts, app) = start_mas().await;
+
+    let (_, body) = app.register(&form("alice", "hunter2", TOKEN)).await;
+
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert_eq!(accounts.lock().unwrap()["alice"], "hunter2");
+    assert!(observed_registrations(&app, 1).await);
+}
+
+#[tokio::test]
+async fn mas_reports_existing_user() {
+    let (accounts, app) = start_mas().await;
+    accounts
+        .lock()
+        .unwrap()
+        .insert("alice".to_string(), "old".to_string());
+
+    let (_, body) = app.register(&form("alice", "hunter2", TOKEN)).await;
+
+    assert_eq!(body["registrationState"], "USER_EXISTS");
+    assert_eq!(accounts.lock().unwrap()["alice"], "old");
+}
+
+#[tokio::test]
+async fn mas_deactivates_user_when_the_password_is_refused() {
+    let (accounts, app) = start_mas().await;
+
+    let (_, body) = app
+        .register(&form("alice", MAS_REJECTED_PASSWORD, TOKEN))
+        .await;
+
+    assert_eq!(body["registrationState"], "PASSWORD_REJECTED");
+    assert_eq!(accounts.lock().unwrap()["alice"], DEACTIVATED);
+}
+
+#[tokio::test]
+async fn mas_reports_username_availability() {
+    let (accounts, app) = start_mas().await;
+    accounts
+        .lock()
+        .unwrap()
+        .insert("alice".to_string(), "old".to_string());
+
+    let (_, body) = app.get("/username_available?username=alice").await;
+    assert_eq!(body["available"], false);
+    let (_, body) = app.get("/username_available?username=bob").await;
+    assert_eq!(body["available"], true);
+}
@@ -0,0 +1,160 @@
+//! CAPTCHA checks, against a local `siteverify` stub for the hosted providers.
+
+use std::sync::{Arc, Mutex};
+
+use axum::{extract::State, routing::post, Form, Json, Router};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+
+use super::{config, form, spawn, MockSynapse, TestApp, SHARED_SECRET, TOKEN};
+use crate::captcha::{leading_zero_bits, CaptchaConfig, CaptchaProvider};
+
+const SECRET: &str = "captcha-secret";
+const SOLVED: &str = "solved-response";
+
+/// Form bodies the stub was asked to verify.
+type Requests = Arc<Mutex<Vec<Vec<(String, String)>>>>;
+
+async fn siteverify(
+    State(requests): State<Requests>,
+    Form(request): Form<Vec<(String, String)>>,
+) -> Json<Value> {
+    let field = |name: &str| {
+        request
+            .iter()
+            .find(|(key, _)| key == name)
+            .map(|(_, value)| value.clone())
+    };
+    let success =
+        field("secret").as_deref() == Some(SECRET) && field("response").as_deref() == Some(SOLVED);
+    requests.lock().unwrap().push(request);
+    if success {
+        Json(json!({ "success": true }))
+    } else {
+        Json(json!({ "success": false, "error-codes": ["invalid-input-response"] }))
+    }
+}
+
+async fn setup_remote() -> (MockSynapse, Requests, TestApp) {
+    let requests = Requests::default();
+    let stub = Router::new()
+        .route("/siteverify", post(siteverify))
+        .with_state(requests.clone());
+    let stub = spawn(stub).await;
+
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.captcha = Some(CaptchaConfig::Remote {
+        provider: CaptchaProvider::Turnstile,
+        site_key: "site-key".to_string(),
+        secret: SECRET.to_string(),
+        verify_url: format!("http://{stub}/siteverify"),
+    });
+    (synapse, requests, TestApp::start(config).await)
+}
+
+async fn setup_pow() -> (MockSynapse, TestApp) {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.captcha = Some(CaptchaConfig::ProofOfWork { difficulty: 8 });
+    (synapse, TestApp::start(config).await)
+}
+
+/// Brute-forces the puzzle the way the frontend does.
+async fn solve(app: &TestApp) -> String {
+    let (_, body) = app.get("/captcha/challenge").await;
+    let challenge = body["challenge"].as_str().unwrap();
+    let difficulty = body["difficulty"].as_u64().unwrap() as u32;
+    (0u64..)
+        .map(|solution| format!("{challenge}:{solution}"))
+        .find(|answer| leading_zero_bits(&Sha256::digest(answer)) >= difficulty)
+        .unwrap()
+}
+
+#[tokio::test]
+async fn remote_accepts_solved_captcha() {
+    let (synapse, requests, app) = setup_remote().await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    form.push(("captcha", SOLVED));
+
+    let (_, body) = app.register(&form).await;
+
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert!(synapse.users().contains("alice"));
+    let requests = requests.lock().unwrap();
+    assert!(requests[0].contains(&("remoteip".to_string(), "127.0.0.1".to_string())));
+}
+
+#[tokio::test]
+async fn remote_rejects_unsolved_captcha() {
+    let (synapse, requests, app) = setup_remote().await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    form.push(("captcha", "guess"));
+
+    let (_, body) = app.register(&form).await;
+
+    assert_eq!(body["registrationState"], "INVALID_CAPTCHA");
+    assert!(synapse.users().is_empty());
+    assert_eq!(requests.lock().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn missing_captcha_skips_provider() {
+    let (_synapse, requests, app) = setup_remote().await;
+
+    let (_, body) = app.register(&form("alice", "hunter2", TOKEN)).await;
+
+    assert_eq!(body["registrationState"], "INVALID_CAPTCHA");
+    assert!(requests.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn local_checks_come_before_the_provider() {
+    let (_synapse, requests, app) = setup_remote().await;
+
+    let mut form = form("alice", "hunter2", TOKEN);
+    form.push(("captcha", SOLVED));
+    form.retain(|(name, _)| *name != "passwordConfirmation");
+    form.push(("passwordConfirmation", "something-else"));
+    let (_, body) = app.register(&form).await;
+    assert_eq!(body["registrationState"], "INVALID_PASSWORD_VERIFICATION");
+
+    let mut form = super::form("Not Valid!", "hunter2", TOKEN);
+    form.push(("captcha", SOLVED));
+    let (_, body) = app.register(&form).await;
+    assert_eq!(body["registrationState"], "INVALID_USERNAME");
+
+    assert!(requests.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn pow_accepts_solution_once() {
+    let (synapse, app) = setup_pow().await;
+    let answer = solve(&app).await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    form.push(("captcha", &answer));
+
+    let (_, body) = app.register(&form).await;
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert!(synapse.users().contains("alice"));
+
+    let mut replay = super::form("bob", "hunter2", TOKEN);
+    replay.push(("captcha", &answer));
+    let (_, body) = app.register(&replay).await;
+    assert_eq!(body["registrationState"], "INVALID_CAPTCHA");
+}
+
+#[tokio::test]
+async fn pow_rejects_forged_challenge() {
+    let (_synapse, app) = setup_pow().await;
+    let answer = solve(&app).await;
+    // Same puzzle with a later expiry; the signature no longer matches.
+    let (nonce, rest) = answer.split_once('.').unwrap();
+    let forged = format!("{nonce}.9999999999.{}", rest.split_once('.').unwrap().1);
+    let mut form = form("alice", "hunter2", TOKEN);
+    form.push(("captcha", &forged));
+
+    let (_, body) = app.register(&form).await;
+
+    assert_eq!(body["registrationState"], "INVALID_CAPTCHA");
+}
@@ -0,0 +1,189 @@
+use std::net::IpAddr;
+
+use axum::http::{HeaderMap, HeaderValue};
+use ipnet::IpNet;
+
+use crate::client_ip::{client_ip, ForwardedHeader};
+
+const PROXY: &str = "10.0.0.1";
+
+fn ip(raw: &str) -> IpAddr {
+    raw.parse().unwrap()
+}
+
+fn trusted() -> Vec<IpNet> {
+    vec!["10.0.0.0/8".parse().unwrap()]
+}
+
+fn with_headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    for (name, value) in pairs {
+        headers.append(*name, HeaderValue::from_static(value));
+    }
+    headers
+}
+
+#[test]
+fn untrusted_peer_is_the_client() {
+    let headers = with_headers(&[("x-forwarded-for", "1.2.3.4")]);
+    let peer = ip("198.51.100.9");
+    assert_eq!(
+        client_ip(&headers, peer, &trusted(), ForwardedHeader::XForwardedFor),
+        peer
+    );
+    assert_eq!(
+        client_ip(&headers, peer, &[], ForwardedHeader::XForwardedFor),
+        peer
+    );
+}
+
+#[test]
+fn walks_the_chain_right_to_left() {
+    // The client claimed 6.6.6.6; nginx appended the address it saw, then a
+    // second trusted proxy appended the first one.
+    let headers = with_headers(&[("x-forwarded-for", "6.6.6.6, 203.0.113.7, 10.0.0.2")]);
+    assert_eq!(
+        client_ip(
+            &headers,
+            ip(PROXY),
+            &trusted(),
+            ForwardedHeader::XForwardedFor
+        ),
+        ip("203.0.113.7")
+    );
+
+    // Repeated headers are one list, in order.
+    let headers = with_headers(&[
+        ("x-forwarded-for", "6.6.6.6"),
+        ("x-forwarded-for", "203.0.113.7"),
+    ]);
+    assert_eq!(
+        client_ip(
+            &headers,
+            ip(PROXY),
+            &trusted(),
+            ForwardedHeader::XForwardedFor
+        ),
+        ip("203.0.113.7")
+    );
+}
+
+#[test]
+fn only_trusted_hops_all_the_way_gives_the_leftmost() {
+    let headers = with_headers(&[("x-forwarded-for", "10.0.0.3, 10.0.0.2")]);
+    assert_eq!(
+        client_ip(
+            &headers,
+            ip(PROXY),
+            &trusted(),
+            ForwardedHeader::XForwardedFor
+        ),
+        ip("10.0.0.3")
+    );
+}
+
+#[test]
+fn ignores_headers_the_proxy_does_not_set() {
+    // nginx appends to X-Forwarded-For and passes the client's own
+    // Forwarded and X-Real-IP through.
+    let headers = with_headers(&[
+        ("forwarded", "for=1.2.3.4"),
+        ("x-real-ip", "5.6.7.8"),
+        ("x-forwarded-for", "203.0.113.7"),
+    ]);
+    assert_eq!(
+        client_ip(
+            &headers,
+            ip(PROXY),
+            &trusted(),
+            ForwardedHeader::XForwardedFor
+        ),
+        ip("203.0.113.7")
+    );
+
+    // Without the configured header the proxy itself is all we know.
+    let headers = with_headers(&[("forwarded", "for=1.2.3.4")]);
+    assert_eq!(
+        client_ip(
+            &headers,
+            ip(PROXY),
+            &trusted(),
+            ForwardedHeader::XForwardedFor
+        ),
+        ip(PROXY)
+    );
+}
+
+#[test]
+fn reads_forwarded_and_x_real_ip_when_configured() {
+    let headers = with_headers(&[
+        (
+            "forwarded",
+            "for=6.6.6.6, for=\"[2001:db8::7]:4711\";proto=https, for=10.0.0.2",
+        ),
+        ("x-forwarded-for", "1.2.3.4"),
+        ("x-real-ip", "198.51.100.3"),
+    ]);
+    assert_eq!(
+        client_ip(&headers, ip(PROXY), &trusted(), ForwardedHeader::Forwarded),
+        ip("2001:db8::7")
+    );
+    assert_eq!(
+        client_ip(&headers, ip(PROXY), &trusted(), ForwardedHeader::XRealIp),
+        ip("198.51.100.3")
+    );
+}
+
+#[test]
+fn parses_ports_and_bracketed_v6() {
+    for (node, expected) in [
+        ("203.0.113.7:8080", "203.0.113.7"),
+        ("2001:db8::1", "2001:db8::1"),
+        ("[2001:db8::1]", "2001:db8::1"),
+        ("[2001:db8::1]:443", "2001:db8::1"),
+    ] {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-forwarded-for", HeaderValue::from_str(node).unwrap());
+        assert_eq!(
+            client_ip(
+                &headers,
+                ip(PROXY),
+                &trusted(),
+                ForwardedHeader::XForwardedFor
+            ),
+            ip(expected),
+            "{node}"
+        );
+    }
+}
+
+#[test]
+fn stops_at_an_unparsable_hop() {
+    let headers = with_headers(&[("x-forwarded-for", "203.0.113.7, garbage, 10.0.0.2")]);
+    assert_eq!(
+        client_ip(
+            &headers,
+            ip(PROXY),
+            &trusted(),
+            ForwardedHeader::XForwardedFor
+        ),
+        ip("10.0.0.2")
+    );
+}
+
+#[test]
+fn header_names_parse_case_insensitively() {
+    assert_eq!(
+        ForwardedHeader::parse("X-Forwarded-For"),
+        Some(ForwardedHeader::XForwardedFor)
+    );
+    assert_eq!(
+        ForwardedHeader::parse("forwarded"),
+        Some(ForwardedHeader::Forwarded)
+    );
+    assert_eq!(
+        ForwardedHeader::parse("X-Real-IP"),
+        Some(ForwardedHeader::XRealIp)
+    );
+    assert_eq!(ForwardedHeader::parse("cf-connecting-ip"), None);
+}
@@ -0,0 +1,278 @@
+use chrono::Duration;
+use lettre::{AsyncSmtpTransport, Tokio1Executor};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use reqwest::StatusCode;
+use serde_json::json;
+
+use super::mock_synapse::ADMIN_TOKEN;
+use super::smtp_sink::SmtpSink;
+use super::{config, form, MockSynapse, TestApp, SHARED_SECRET, TOKEN};
+use crate::email::EmailConfig;
+use crate::tokens::InviteToken;
+
+static LINK_RE: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"https://register\.example/verify/([A-Za-z0-9]+)").unwrap());
+
+fn email_config(smtp_port: u16) -> EmailConfig {
+    EmailConfig {
+        transport: AsyncSmtpTransport::<Tokio1Executor>::builder_dangerous("127.0.0.1")
+            .port(smtp_port)
+            .build(),
+        from: "Registration <register@example.org>".parse().unwrap(),
+        public_url: "https://register.example/".to_string(),
+        ttl: Duration::hours(1),
+        admin_token: ADMIN_TOKEN.to_string(),
+    }
+}
+
+struct Setup {
+    synapse: MockSynapse,
+    sink: SmtpSink,
+    app: TestApp,
+}
+
+async fn setup(configure: impl FnOnce(&mut crate::AppConfig)) -> Setup {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let (sink, port) = SmtpSink::start().await;
+    let mut config = config(&server);
+    config.email = Some(email_config(port));
+    configure(&mut config);
+    Setup {
+        synapse,
+        sink,
+        app: TestApp::start(config).await,
+    }
+}
+
+fn one_use_token(config: &mut crate::AppConfig) {
+    config.tokens = vec![(
+        TOKEN.to_string(),
+        InviteToken {
+            max_uses: Some(1),
+            uses: 0,
+            expires_at: None,
+        },
+    )];
+}
+
+fn with_email<'a>(form: &mut Vec<(&'a str, &'a str)>, email: &'a str) {
+    form.push(("email", email));
+}
+
+/// The verification code from the only mail the sink received.
+fn code(sink: &SmtpSink) -> String {
+    let received = sink.received();
+    assert_eq!(received.len(), 1);
+    LINK_RE
+        .captures(&received[0].data)
+        .expect("verification link")[1]
+        .to_string()
+}
+
+/// Presses the button on the page the verification link opens.
+async fn confirm(app: &TestApp, path: &str) -> (StatusCode, serde_json::Value) {
+    app.post(path, "application/x-www-form-urlencoded", String::new())
+        .await
+}
+
+#[tokio::test]
+async fn registers_only_after_link_is_confirmed() {
+    let Setup { synapse, sink, app } = setup(|_| {}).await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    with_email(&mut form, "alice@example.org");
+
+    let (_, body) = app.register(&form).await;
+    assert_eq!(body["registrationState"], "VERIFICATION_SENT");
+    assert!(synapse.users().is_empty());
+    assert_eq!(sink.received()[0].recipients, ["alice@example.org"]);
+
+    let path = format!("/verify/{}", code(&sink));
+    // Opening the link, as a mail scanner would, creates nothing.
+    let (status, page) = app.get_text(&path).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(page.contains(r#"<form method="post">"#), "{page}");
+    assert!(synapse.users().is_empty());
+
+    let (status, body) = confirm(&app, &path).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert!(synapse.users().contains("alice"));
+
+    let recorded = synapse.recorded();
+    assert_eq!(recorded.len(), 1);
+    assert_eq!(
+        recorded[0].path,
+        "/_synapse/admin/v2/users/@alice:localhost"
+    );
+    assert_eq!(
+        recorded[0].body,
+        json!({ "threepids": [{ "medium": "email", "address": "alice@example.org" }] })
+    );
+}
+
+#[tokio::test]
+async fn link_works_once() {
+    let Setup { sink, app, .. } = setup(|_| {}).await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    with_email(&mut form, "alice@example.org");
+    app.register(&form).await;
+    let path = format!("/verify/{}", code(&sink));
+
+    confirm(&app, &path).await;
+    let (status, body) = confirm(&app, &path).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["registrationState"], "INVALID_VERIFICATION");
+
+    let (status, body) = app.get(&path).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["registrationState"], "INVALID_VERIFICATION");
+}
+
+#[tokio::test]
+async fn requires_valid_email() {
+    let Setup { sink, app, .. } = setup(|_| {}).await;
+
+    let (_, body) = app.register(&form("alice", "hunter2", TOKEN)).await;
+    assert_eq!(body["registrationState"], "INVALID_EMAIL");
+
+    let mut form = form("alice", "hunter2", TOKEN);
+    with_email(&mut form, "not an address");
+    let (_, body) = app.register(&form).await;
+    assert_eq!(body["registrationState"], "INVALID_EMAIL");
+
+    assert!(sink.received().is_empty());
+}
+
+#[tokio::test]
+async fn holds_username_while_pending() {
+    let Setup { app, .. } = setup(|_| {}).await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    with_email(&mut form, "alice@example.org");
+    app.register(&form).await;
+
+    let (status, body) = app.register(&form).await;
+
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(body["registrationState"], "USER_EXISTS");
+}
+
+#[tokio::test]
+async fn token_use_is_taken_when_link_is_confirmed() {
+    let Setup { synapse, sink, app } = setup(one_use_token).await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    with_email(&mut form, "alice@example.org");
+    app.register(&form).await;
+    assert_eq!(app.state.tokens.get(TOKEN).unwrap().uses, 0);
+
+    let (_, body) = confirm(&app, &format!("/verify/{}", code(&sink))).await;
+
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert_eq!(app.state.tokens.get(TOKEN).unwrap().uses, 1);
+    assert!(synapse.users().contains("alice"));
+}
+
+#[tokio::test]
+async fn token_spent_before_the_link_is_confirmed() {
+    let Setup { synapse, sink, app } = setup(one_use_token).await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    with_email(&mut form, "alice@example.org");
+    app.register(&form).await;
+    app.state.tokens.consume(TOKEN).unwrap();
+
+    let (_, body) = confirm(&app, &format!("/verify/{}", code(&sink))).await;
+
+    assert_eq!(body["registrationState"], "INVALID_TOKEN");
+    assert!(synapse.users().is_empty());
+}
+
+#[tokio::test]
+async fn homeserver_failure_keeps_the_link() {
+    let Setup { synapse, sink, app } = setup(one_use_token).await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    with_email(&mut form, "alice@example.org");
+    app.register(&form).await;
+    let path = format!("/verify/{}", code(&sink));
+    synapse.fail_with(503);
+
+    let (_, body) = confirm(&app, &path).await;
+    assert_eq!(body["registrationState"], "INTERNAL_ERROR");
+    // The token use was given back and the link still works.
+    assert_eq!(app.state.tokens.get(TOKEN).unwrap().uses, 0);
+    let (_, body) = confirm(&app, &path).await;
+    assert_eq!(body["registrationState"], "INTERNAL_ERROR");
+    assert!(app.state.email.as_ref().unwrap().contains(&code(&sink)));
+}
+
+#[tokio::test]
+async fn expired_link_takes_no_token_use() {
+    let Setup { synapse, sink, app } = setup(|config| {
+        one_use_token(config);
+        config.email.as_mut().unwrap().ttl = Duration::zero();
+    })
+    .await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    with_email(&mut form, "alice@example.org");
+    app.register(&form).await;
+
+    let (status, body) = confirm(&app, &format!("/verify/{}", code(&sink))).await;
+
+    assert_eq!(status, StatusCode::GONE);
+    assert_eq!(body["registrationState"], "VERIFICATION_EXPIRED");
+    assert_eq!(app.state.tokens.get(TOKEN).unwrap().uses, 0);
+    assert!(synapse.users().is_empty());
+}
+
+#[tokio::test]
+async fn expired_signups_are_swept() {
+    let Setup { sink, app, .. } = setup(|config| {
+        config.email.as_mut().unwrap().ttl = Duration::zero();
+    })
+    .await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    with_email(&mut form, "alice@example.org");
+    app.register(&form).await;
+
+    app.state.email.as_ref().unwrap().drop_expired();
+
+    let (status, body) = confirm(&app, &format!("/verify/{}", code(&sink))).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["registrationState"], "INVALID_VERIFICATION");
+}
+
+#[tokio::test]
+async fn unsendable_mail_takes_no_token_use() {
+    let Setup { app, .. } = setup(|config| {
+        one_use_token(config);
+        // Nothing listens on port 1.
+        config.email = Some(email_config(1));
+    })
+    .await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    with_email(&mut form, "alice@example.org");
+
+    let (status, body) = app.register(&form).await;
+
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(body["registrationState"], "INTERNAL_ERROR");
+    assert_eq!(app.state.tokens.get(TOKEN).unwrap().uses, 0);
+}
+
+#[tokio::test]
+async fn mail_states_the_link_lifetime() {
+    let Setup { sink, app, .. } = setup(|config| {
+        config.email.as_mut().unwrap().ttl = Duration::minutes(30);
+    })
+    .await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    with_email(&mut form, "alice@example.org");
+
+    app.register(&form).await;
+
+    let received = sink.received();
+    assert!(
+        received[0].data.contains("within 30 minutes"),
+        "{}",
+        received[0].data
+    );
+}
@@ -0,0 +1,100 @@
+use std::path::PathBuf;
+
+use reqwest::{header, StatusCode};
+
+use super::{config, MockSynapse, TestApp, SHARED_SECRET};
+use crate::frontend::{FrontendConfig, StaticSource};
+
+/// A static root with a page, a script, a stylesheet and a file beside it
+/// that must never be served.
+fn static_root() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("frontend-{}", std::process::id()));
+    let root = dir.join("static");
+    std::fs::create_dir_all(root.join("docs")).unwrap();
+    std::fs::write(root.join("index.html"), "<h1>{{ server_name }}</h1>").unwrap();
+    std::fs::write(root.join("docs/index.html"), "docs for {{server_name}}").unwrap();
+    std::fs::write(root.join("config.js"), "const min = {{ pw_length }};").unwrap();
+    std::fs::write(
+        root.join("style.css"),
+        "h1 { content: '{{ server_name }}' }",
+    )
+    .unwrap();
+    std::fs::write(dir.join("secret.txt"), "do not serve").unwrap();
+    root
+}
+
+async fn setup() -> (MockSynapse, TestApp) {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.frontend = Some(FrontendConfig {
+        source: StaticSource::Disk(static_root()),
+        vars: vec![
+            ("server_name".to_string(), "example.org".to_string()),
+            ("pw_length".to_string(), "8".to_string()),
+        ],
+    });
+    (synapse, TestApp::start(config).await)
+}
+
+async fn fetch(app: &TestApp, path: &str) -> (StatusCode, Option<String>, String) {
+    let response = reqwest::get(format!("{}{path}", app.url)).await.unwrap();
+    let status = response.status();
+    let cache_control = response
+        .headers()
+        .get(header::CACHE_CONTROL)
+        .map(|value| value.to_str().unwrap().to_string());
+    (status, cache_control, response.text().await.unwrap())
+}
+
+#[tokio::test]
+async fn serves_templated_files_uncached() {
+    let (_synapse, app) = setup().await;
+
+    let (status, cache, body) = fetch(&app, "/").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(cache.as_deref(), Some("no-cache"));
+    assert_eq!(body, "<h1>example.org</h1>");
+
+    let (_, _, body) = fetch(&app, "/docs/").await;
+    assert_eq!(body, "docs for example.org");
+
+    let (status, cache, body) = fetch(&app, "/config.js").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(cache.as_deref(), Some("no-cache"));
+    assert_eq!(body, "const min = 8;");
+
+    // Other assets are cached and left as they are.
+    let (status, cache, body) = fetch(&app, "/style.css").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(cache.as_deref(), Some("public, max-age=86400"));
+    assert_eq!(body, "h1 { content: '{{ server_name }}' }");
+
+    let (status, _, _) = fetch(&app, "/missing.html").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn refuses_paths_outside_the_static_root() {
+    let (_synapse, app) = setup().await;
+
+    // Encoded so the client doesn't normalise them away before sending.
+    for path in [
+        "/%2e%2e%2fsecret.txt",
+        "/docs%2f%2e%2e%2f%2e%2e%2fsecret.txt",
+        "/%2fetc%2fpasswd",
+        "/.%2findex.html",
+    ] {
+        let (status, _, body) = fetch(&app, path).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{path}");
+        assert!(!body.contains("do not serve"), "{path}");
+    }
+}
+
+#[tokio::test]
+async fn not_mounted_without_a_frontend() {
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let app = TestApp::start(config(&server)).await;
+
+    let (status, _, _) = fetch(&app, "/").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
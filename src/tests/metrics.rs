@@ -0,0 +1,112 @@
+use reqwest::StatusCode;
+
+use super::{config, form, MockSynapse, TestApp, SHARED_SECRET, TOKEN};
+use crate::tokens::{token_id, InviteToken};
+use crate::AppConfig;
+
+fn metrics_config(server: &str) -> AppConfig {
+    let mut config = config(server);
+    config.metrics = true;
+    config
+}
+
+/// The value of the sample named exactly `series`, labels included.
+fn sample(body: &str, series: &str) -> Option<f64> {
+    body.lines()
+        .find_map(|line| line.strip_prefix(series)?.strip_prefix(' ')?.parse().ok())
+}
+
+#[tokio::test]
+async fn counts_outcomes_and_upstream_calls() {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let app = TestApp::start(metrics_config(&server)).await;
+    synapse.add_user("bob");
+
+    app.register(&form("alice", "hunter22", TOKEN)).await;
+    app.register(&form("bob", "hunter22", TOKEN)).await;
+    app.register(&form("carol", "hunter22", "WrongToken")).await;
+
+    let (status, body) = app.get_text("/metrics").await;
+    assert_eq!(status, StatusCode::OK);
+    let outcome = |state: &str| {
+        sample(
+            &body,
+            &format!("matrix_registration_outcomes_total{{state=\"{state}\"}}"),
+        )
+    };
+    assert_eq!(outcome("REGISTERED"), Some(1.0));
+    assert_eq!(outcome("USER_EXISTS"), Some(1.0));
+    assert_eq!(outcome("INVALID_TOKEN"), Some(1.0));
+
+    let histogram = "matrix_registration_upstream_request_duration_seconds";
+    assert_eq!(
+        sample(&body, &format!("{histogram}_count{{request=\"nonce\"}}")),
+        Some(2.0)
+    );
+    assert_eq!(
+        sample(&body, &format!("{histogram}_count{{request=\"register\"}}")),
+        Some(2.0)
+    );
+    assert_eq!(
+        sample(
+            &body,
+            &format!("{histogram}_bucket{{request=\"register\",le=\"+Inf\"}}")
+        ),
+        Some(2.0)
+    );
+}
+
+#[tokio::test]
+async fn reports_blocked_ips_and_token_uses_without_tokens() {
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = metrics_config(&server);
+    config.tokens.push((
+        "Limited".to_string(),
+        InviteToken {
+            max_uses: Some(5),
+            ..InviteToken::unlimited()
+        },
+    ));
+    let app = TestApp::start(config).await;
+
+    app.register(&form("alice", "hunter22", "Limited")).await;
+    for _ in 0..3 {
+        app.register(&form("bob", "hunter22", "WrongToken")).await;
+    }
+
+    let (_, body) = app.get_text("/metrics").await;
+    assert_eq!(sample(&body, "matrix_registration_blocked_ips"), Some(1.0));
+    let id = app.state.token_id("Limited");
+    assert_eq!(
+        sample(
+            &body,
+            &format!("matrix_registration_token_uses{{token=\"{id}\"}}")
+        ),
+        Some(1.0)
+    );
+    assert_eq!(
+        sample(
+            &body,
+            &format!("matrix_registration_token_uses_allowed{{token=\"{id}\"}}")
+        ),
+        Some(5.0)
+    );
+    assert!(!body.contains("Limited"));
+    assert!(!body.contains(TOKEN));
+}
+
+#[tokio::test]
+async fn not_served_when_off() {
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let app = TestApp::start(config(&server)).await;
+
+    let (status, _) = app.get_text("/metrics").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[test]
+fn token_ids_depend_on_the_key() {
+    assert_eq!(token_id("key", "Limited"), token_id("key", "Limited"));
+    assert_ne!(token_id("key", "Limited"), token_id("other-key", "Limited"));
+    assert_eq!(token_id("key", "Limited").len(), 8);
+}
@@ -0,0 +1,344 @@
+//! An in-process stand-in for Synapse's shared-secret registration and
+//! registration tokens admin APIs, plus a recorder for the provisioning calls
+//! made after an account exists.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, Method, StatusCode, Uri},
+    response::{IntoResponse, Json, Response},
+    routing::{get, post, put},
+    Router,
+};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha1::Sha1;
+
+pub const ADMIN_TOKEN: &str = "mock-admin-access-token";
+
+#[derive(Clone)]
+pub struct MockSynapse {
+    inner: Arc<Inner>,
+}
+
+struct Inner {
+    shared_secret: String,
+    nonces: Mutex<HashSet<String>>,
+    users: Mutex<HashSet<String>>,
+    passwords: Mutex<HashMap<String, String>>,
+    fail_with: Mutex<Option<(StatusCode, &'static str)>>,
+    delay: Mutex<Duration>,
+    registration_tokens: Mutex<HashMap<String, RegistrationToken>>,
+    recorded: Mutex<Vec<Recorded>>,
+    availability_queries: Mutex<Vec<String>>,
+}
+
+/// A provisioning request as the mock received it.
+#[derive(Clone, Debug)]
+pub struct Recorded {
+    pub method: Method,
+    /// Still percent-encoded.
+    pub path: String,
+    pub access_token: Option<String>,
+    pub body: serde_json::Value,
+}
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RegistrationToken {
+    pub token: String,
+    pub uses_allowed: Option<u32>,
+    pub pending: u32,
+    pub completed: u32,
+    pub expiry_time: Option<i64>,
+}
+
+#[derive(Deserialize)]
+struct UpdateRegistrationToken {
+    uses_allowed: Option<u32>,
+}
+
+#[derive(Deserialize)]
+struct RegisterRequest {
+    nonce: String,
+    username: String,
+    password: String,
+    admin: bool,
+    mac: String,
+}
+
+impl MockSynapse {
+    /// Starts the mock on an ephemeral port and returns it with its base URL.
+    pub async fn start(shared_secret: &str) -> (Self, String) {
+        let mock = Self {
+            inner: Arc::new(Inner {
+                shared_secret: shared_secret.to_string(),
+                nonces: Mutex::default(),
+                users: Mutex::default(),
+                passwords: Mutex::default(),
+                fail_with: Mutex::default(),
+                delay: Mutex::default(),
+                registration_tokens: Mutex::default(),
+                recorded: Mutex::default(),
+                availability_queries: Mutex::default(),
+            }),
+        };
+        let router = Router::new()
+            .route("/_synapse/admin/v1/register", get(nonce).post(register))
+            .route(
+                "/_synapse/admin/v1/registration_tokens/:token",
+                get(get_registration_token).put(update_registration_token),
+            )
+            .route("/_matrix/client/v3/register/available", get(available))
+            .route("/_synapse/admin/v2/users/:user_id", put(record))
+            .route("/_synapse/admin/v1/join/:room", post(record))
+            .route("/_matrix/client/v3/createRoom", post(record))
+            .route(
+                "/_matrix/client/v3/rooms/:room/send/:event_type/:txn_id",
+                put(record),
+            )
+            .with_state(mock.clone());
+        let addr = super::spawn(router).await;
+        (mock, format!("http://{addr}"))
+    }
+
+    pub fn users(&self) -> HashSet<String> {
+        self.inner.users.lock().unwrap().clone()
+    }
+
+    /// The password `username` was registered with.
+    pub fn password(&self, username: &str) -> Option<String> {
+        self.inner.passwords.lock().unwrap().get(username).cloned()
+    }
+
+    pub fn add_user(&self, username: &str) {
+        self.inner
+            .users
+            .lock()
+            .unwrap()
+            .insert(username.to_string());
+    }
+
+    /// Makes every following register POST fail with `status`.
+    pub fn fail_with(&self, status: u16) {
+        self.reject_with(status, "M_UNKNOWN");
+    }
+
+    /// Makes every following correctly signed register POST fail with
+    /// `status` and `errcode`.
+    pub fn reject_with(&self, status: u16, errcode: &'static str) {
+        *self.inner.fail_with.lock().unwrap() =
+            Some((StatusCode::from_u16(status).unwrap(), errcode));
+    }
+
+    /// Makes every following register POST take `delay` before answering.
+    pub fn delay_with(&self, delay: Duration) {
+        *self.inner.delay.lock().unwrap() = delay;
+    }
+
+    pub fn add_registration_token(&self, token: RegistrationToken) {
+        self.inner
+            .registration_tokens
+            .lock()
+            .unwrap()
+            .insert(token.token.clone(), token);
+    }
+
+    pub fn registration_token(&self, token: &str) -> Option<RegistrationToken> {
+        self.inner
+            .registration_tokens
+            .lock()
+            .unwrap()
+            .get(token)
+            .cloned()
+    }
+
+    /// Usernames asked about on `register/available`, in order.
+    pub fn availability_queries(&self) -> Vec<String> {
+        self.inner.availability_queries.lock().unwrap().clone()
+    }
+
+    pub fn recorded(&self) -> Vec<Recorded> {
+        self.inner.recorded.lock().unwrap().clone()
+    }
+}
+
+async fn nonce(State(mock): State<MockSynapse>) -> Json<serde_json::Value> {
+    let nonce: String = rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(32)
+        .map(char::from)
+        .collect();
+    mock.inner.nonces.lock().unwrap().insert(nonce.clone());
+    Json(json!({ "nonce": nonce }))
+}
+
+async fn register(State(mock): State<MockSynapse>, Json(req): Json<RegisterRequest>) -> Response {
+    let delay = *mock.inner.delay.lock().unwrap();
+    tokio::time::sleep(delay).await;
+    if !mock.inner.nonces.lock().unwrap().remove(&req.nonce) {
+        return matrix_error(StatusCode::BAD_REQUEST, "M_UNKNOWN", "unrecognised nonce");
+    }
+
+    let mut mac = Hmac::<Sha1>::new_from_slice(mock.inner.shared_secret.as_bytes()).unwrap();
+    mac.update(req.nonce.as_bytes());
+    mac.update(b"\x00");
+    mac.update(req.username.as_bytes());
+    mac.update(b"\x00");
+    mac.update(req.password.as_bytes());
+    mac.update(b"\x00");
+    mac.update(if req.admin { b"admin" } else { b"notadmin" });
+    let Ok(presented) = hex::decode(&req.mac) else {
+        return matrix_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "HMAC incorrect");
+    };
+    if mac.verify_slice(&presented).is_err() {
+        return matrix_error(StatusCode::FORBIDDEN, "M_FORBIDDEN", "HMAC incorrect");
+    }
+    if let Some((status, errcode)) = *mock.inner.fail_with.lock().unwrap() {
+        return matrix_error(status, errcode, "induced failure");
+    }
+    if !req
+        .username
+        .bytes()
+        .all(|b| matches!(b, b'a'..=b'z' | b'0'..=b'9' | b'.' | b'_' | b'=' | b'-' | b'/'))
+    {
+        return matrix_error(
+            StatusCode::BAD_REQUEST,
+            "M_INVALID_USERNAME",
+            "User ID can only contain characters a-z, 0-9, or '=_-./'",
+        );
+    }
+
+    if !mock
+        .inner
+        .users
+        .lock()
+        .unwrap()
+        .insert(req.username.clone())
+    {
+        return matrix_error(
+            StatusCode::BAD_REQUEST,
+            "M_USER_IN_USE",
+            "User ID already taken.",
+        );
+    }
+    mock.inner
+        .passwords
+        .lock()
+        .unwrap()
+        .insert(req.username.clone(), req.password);
+    Json(json!({
+        "user_id": format!("@{}:localhost", req.username),
+        "access_token": "mock_access_token",
+        "home_server": "localhost",
+        "device_id": "MOCKDEVICE",
+    }))
+    .into_response()
+}
+
+async fn available(
+    State(mock): State<MockSynapse>,
+    Query(query): Query<HashMap<String, String>>,
+) -> Response {
+    let username = query.get("username").cloned().unwrap_or_default();
+    mock.inner
+        .availability_queries
+        .lock()
+        .unwrap()
+        .push(username.clone());
+    if mock.inner.users.lock().unwrap().contains(&username) {
+        return matrix_error(
+            StatusCode::BAD_REQUEST,
+            "M_USER_IN_USE",
+            "User ID already taken.",
+        );
+    }
+    Json(json!({ "available": true })).into_response()
+}
+
+fn matrix_error(status: StatusCode, errcode: &str, error: &str) -> Response {
+    (status, Json(json!({ "errcode": errcode, "error": error }))).into_response()
+}
+
+fn is_admin(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        == Some(&format!("Bearer {ADMIN_TOKEN}"))
+}
+
+async fn get_registration_token(
+    State(mock): State<MockSynapse>,
+    headers: HeaderMap,
+    Path(token): Path<String>,
+) -> Response {
+    if !is_admin(&headers) {
+        return matrix_error(
+            StatusCode::UNAUTHORIZED,
+            "M_UNKNOWN_TOKEN",
+            "Invalid access token",
+        );
+    }
+    match mock.registration_token(&token) {
+        Some(token) => Json(token).into_response(),
+        None => matrix_error(
+            StatusCode::NOT_FOUND,
+            "M_NOT_FOUND",
+            "No such registration token",
+        ),
+    }
+}
+
+async fn update_registration_token(
+    State(mock): State<MockSynapse>,
+    headers: HeaderMap,
+    Path(token): Path<String>,
+    Json(update): Json<UpdateRegistrationToken>,
+) -> Response {
+    if !is_admin(&headers) {
+        return matrix_error(
+            StatusCode::UNAUTHORIZED,
+            "M_UNKNOWN_TOKEN",
+            "Invalid access token",
+        );
+    }
+    let mut tokens = mock.inner.registration_tokens.lock().unwrap();
+    match tokens.get_mut(&token) {
+        Some(token) => {
+            token.uses_allowed = update.uses_allowed;
+            Json(token.clone()).into_response()
+        }
+        None => matrix_error(
+            StatusCode::NOT_FOUND,
+            "M_NOT_FOUND",
+            "No such registration token",
+        ),
+    }
+}
+
+/// Accepts any provisioning call, remembers it and answers with enough for
+/// the caller to carry on.
+async fn record(
+    State(mock): State<MockSynapse>,
+    method: Method,
+    uri: Uri,
+    headers: HeaderMap,
+    Json(body): Json<serde_json::Value>,
+) -> Json<serde_json::Value> {
+    let access_token = headers
+        .get(header::AUTHORIZATION)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.strip_prefix("Bearer "))
+        .map(str::to_string);
+    mock.inner.recorded.lock().unwrap().push(Recorded {
+        method,
+        path: uri.path().to_string(),
+        access_token,
+        body,
+    });
+    Json(json!({ "room_id": "!dm:localhost", "event_id": "$event" }))
+}
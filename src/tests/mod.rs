@@ -0,0 +1,213 @@
+//! End-to-end tests: the real router talks to [`mock_synapse::MockSynapse`]
+//! over HTTP on loopback.
+
+mod admin;
+mod api;
+mod approval;
+mod audit;
+mod availability;
+mod backends;
+mod captcha;
+mod client_ip;
+mod email;
+mod frontend;
+mod metrics;
+mod mock_synapse;
+mod notify;
+mod provision;
+mod pwned;
+mod rate_limit;
+mod registration;
+mod smtp_sink;
+mod status_codes;
+mod storage;
+mod synapse_tokens;
+mod webhooks;
+
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::Router;
+use reqwest::StatusCode;
+use serde_json::Value;
+use tokio::net::TcpListener;
+
+use crate::backend::BackendConfig;
+use crate::client_ip::ForwardedHeader;
+use crate::password::PasswordPolicy;
+use crate::ratelimit::RateLimitPolicy;
+use crate::storage::{MemoryStorage, Storage};
+use crate::tokens::InviteToken;
+use crate::username::UsernamePolicy;
+use crate::{app, AppConfig, AppState, StatusCodes, StorageConfig, TokenBackend};
+
+pub use mock_synapse::MockSynapse;
+
+pub const SHARED_SECRET: &str = "test-shared-secret";
+pub const TOKEN: &str = "InviteToken";
+pub const ADMIN_SECRET: &str = "admin-secret";
+
+/// Serves `router` on an ephemeral loopback port.
+pub async fn spawn(router: Router) -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            router.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+        .unwrap();
+    });
+    addr
+}
+
+/// Polls `check` until it holds or a couple of seconds have passed.
+pub async fn eventually(check: impl Fn() -> bool) {
+    for _ in 0..200 {
+        if check() {
+            return;
+        }
+        tokio::time::sleep(Duration::from_millis(10)).await;
+    }
+    panic!("condition not met in time");
+}
+
+pub fn config(server: &str) -> AppConfig {
+    AppConfig {
+        tokens: vec![(TOKEN.to_string(), InviteToken::unlimited())],
+        token_backend: TokenBackend::Local,
+        token_id_key: "token-id-key".to_string(),
+        server: server.to_string(),
+        server_name: "localhost".to_string(),
+        username: UsernamePolicy::new("localhost"),
+        backend: BackendConfig::Synapse {
+            shared_secret: SHARED_SECRET.to_string(),
+        },
+        bind_addr: "127.0.0.1:0".parse().unwrap(),
+        storage: StorageConfig::Memory,
+        admin_secret: None,
+        allowlist: Vec::new(),
+        trusted_proxies: Vec::new(),
+        forwarded_header: ForwardedHeader::XForwardedFor,
+        rate_limit: RateLimitPolicy::default(),
+        availability_rate_limit: RateLimitPolicy {
+            max_attempts: 20,
+            window: chrono::Duration::minutes(10),
+            count_successful: true,
+        },
+        password: PasswordPolicy::default(),
+        pwned: None,
+        frontend: None,
+        provision: None,
+        email: None,
+        captcha: None,
+        status_codes: StatusCodes::Legacy,
+        metrics: false,
+        audit: None,
+        webhooks: None,
+        notify: None,
+        approval: None,
+    }
+}
+
+pub struct TestApp {
+    pub state: AppState,
+    pub url: String,
+    client: reqwest::Client,
+}
+
+impl TestApp {
+    pub async fn start(config: AppConfig) -> Self {
+        Self::start_with_storage(config, Arc::new(MemoryStorage)).await
+    }
+
+    pub async fn start_with_storage(config: AppConfig, storage: Arc<dyn Storage>) -> Self {
+        let state = AppState::new(config, storage).unwrap();
+        let addr = spawn(app(state.clone())).await;
+        Self {
+            state,
+            url: format!("http://{addr}"),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub async fn register(&self, form: &[(&str, &str)]) -> (StatusCode, Value) {
+        let response = self
+            .client
+            .post(format!("{}/registration", self.url))
+            .form(form)
+            .send()
+            .await
+            .unwrap();
+        let status = response.status();
+        (status, response.json().await.unwrap())
+    }
+
+    /// Posts `body` to `path` as-is with the given `Content-Type`.
+    pub async fn post(&self, path: &str, content_type: &str, body: String) -> (StatusCode, Value) {
+        let response = self
+            .client
+            .post(format!("{}{path}", self.url))
+            .header(reqwest::header::CONTENT_TYPE, content_type)
+            .body(body)
+            .send()
+            .await
+            .unwrap();
+        let status = response.status();
+        (status, response.json().await.unwrap())
+    }
+
+    pub async fn get_text(&self, path: &str) -> (StatusCode, String) {
+        let response = self
+            .client
+            .get(format!("{}{path}", self.url))
+            .send()
+            .await
+            .unwrap();
+        let status = response.status();
+        (status, response.text().await.unwrap())
+    }
+
+    pub async fn get(&self, path: &str) -> (StatusCode, Value) {
+        let response = self
+            .client
+            .get(format!("{}{path}", self.url))
+            .send()
+            .await
+            .unwrap();
+        let status = response.status();
+        (status, response.json().await.unwrap())
+    }
+}
+
+/// Sends an admin request to `path` on the app at `url`, with `body` as
+/// JSON; an empty answer reads as `null`.
+pub async fn admin(
+    url: &str,
+    method: reqwest::Method,
+    path: &str,
+    body: Option<Value>,
+) -> (StatusCode, Value) {
+    let mut request = reqwest::Client::new()
+        .request(method, format!("{url}{path}"))
+        .bearer_auth(ADMIN_SECRET);
+    if let Some(body) = body {
+        request = request.json(&body);
+    }
+    let response = request.send().await.unwrap();
+    let status = response.status();
+    let text = response.text().await.unwrap();
+    (status, serde_json::from_str(&text).unwrap_or(Value::Null))
+}
+
+/// A complete, valid registration form for `username`.
+pub fn form<'a>(username: &'a str, password: &'a str, token: &'a str) -> Vec<(&'a str, &'a str)> {
+    vec![
+        ("username", username),
+        ("password", password),
+        ("passwordConfirmation", password),
+        ("token", token),
+    ]
+}
@@ -0,0 +1,67 @@
+use super::{config, eventually, form, MockSynapse, TestApp, SHARED_SECRET, TOKEN};
+use crate::notify::NotifyConfig;
+
+const BOT_TOKEN: &str = "notify-bot-token";
+const ROOM: &str = "!mods:localhost";
+
+async fn setup() -> (MockSynapse, TestApp) {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.notify = Some(NotifyConfig {
+        bot_token: BOT_TOKEN.to_string(),
+        room_id: ROOM.to_string(),
+    });
+    (synapse, TestApp::start(config).await)
+}
+
+/// Bodies of the messages the bot sent to the moderation room.
+fn notices(synapse: &MockSynapse) -> Vec<String> {
+    synapse
+        .recorded()
+        .into_iter()
+        .filter(|call| {
+            call.path
+                .starts_with("/_matrix/client/v3/rooms/!mods:localhost/send/m.room.message/")
+        })
+        .inspect(|call| {
+            assert_eq!(call.access_token.as_deref(), Some(BOT_TOKEN));
+            assert_eq!(call.body["msgtype"], "m.notice");
+        })
+        .map(|call| call.body["body"].as_str().unwrap().to_string())
+        .collect()
+}
+
+#[tokio::test]
+async fn announces_new_accounts() {
+    let (synapse, app) = setup().await;
+
+    let (_, body) = app.register(&form("alice", "hunter22", TOKEN)).await;
+    assert_eq!(body["registrationState"], "REGISTERED");
+
+    eventually(|| notices(&synapse).len() == 1).await;
+    let notice = &notices(&synapse)[0];
+    assert!(notice.contains("@alice:localhost"), "{notice}");
+    assert!(notice.contains("127.0.0.1"), "{notice}");
+    assert!(notice.contains(&app.state.token_id(TOKEN)), "{notice}");
+    assert!(!notice.contains(TOKEN), "{notice}");
+}
+
+#[tokio::test]
+async fn summarises_a_block_once() {
+    let (synapse, app) = setup().await;
+
+    for _ in 0..5 {
+        app.register(&form("alice", "hunter22", "WrongToken")).await;
+    }
+
+    eventually(|| !notices(&synapse).is_empty()).await;
+    // Give any duplicate a chance to show up.
+    tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    let notices = notices(&synapse);
+    assert_eq!(notices.len(), 1, "{notices:?}");
+    assert!(
+        notices[0].contains("Blocked 127.0.0.1 after 3"),
+        "{}",
+        notices[0]
+    );
+}
@@ -0,0 +1,95 @@
+use axum::http::Method;
+use serde_json::json;
+
+use super::mock_synapse::ADMIN_TOKEN;
+use super::{config, form, MockSynapse, TestApp, SHARED_SECRET, TOKEN};
+use crate::provision::{ProvisionConfig, WelcomeMessage};
+
+async fn setup(provision: ProvisionConfig) -> (MockSynapse, TestApp) {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.provision = Some(provision);
+    (synapse, TestApp::start(config).await)
+}
+
+#[tokio::test]
+async fn sets_profile_joins_rooms_and_welcomes() {
+    let (synapse, app) = setup(ProvisionConfig {
+        admin_token: ADMIN_TOKEN.to_string(),
+        set_display_name: true,
+        default_avatar: Some("mxc://localhost/avatar".to_string()),
+        auto_join: vec!["#lobby:localhost".to_string()],
+        welcome: Some(WelcomeMessage {
+            bot_token: "bot-token".to_string(),
+            body: "Welcome!".to_string(),
+        }),
+    })
+    .await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    form.push(("displayName", "Alice"));
+
+    let (_, body) = app.register(&form).await;
+    assert_eq!(body["registrationState"], "REGISTERED");
+
+    let recorded = synapse.recorded();
+    assert_eq!(recorded.len(), 4);
+
+    assert_eq!(recorded[0].method, Method::PUT);
+    assert_eq!(
+        recorded[0].path,
+        "/_synapse/admin/v2/users/@alice:localhost"
+    );
+    assert_eq!(recorded[0].access_token.as_deref(), Some(ADMIN_TOKEN));
+    assert_eq!(
+        recorded[0].body,
+        json!({ "displayname": "Alice", "avatar_url": "mxc://localhost/avatar" })
+    );
+
+    assert_eq!(
+        recorded[1].path,
+        "/_synapse/admin/v1/join/%23lobby:localhost"
+    );
+    assert_eq!(recorded[1].body, json!({ "user_id": "@alice:localhost" }));
+
+    assert_eq!(recorded[2].path, "/_matrix/client/v3/createRoom");
+    assert_eq!(recorded[2].access_token.as_deref(), Some("bot-token"));
+    assert_eq!(recorded[2].body["invite"], json!(["@alice:localhost"]));
+
+    assert!(recorded[3]
+        .path
+        .starts_with("/_matrix/client/v3/rooms/!dm:localhost/send/m.room.message/"));
+    assert_eq!(recorded[3].body["body"], "Welcome!");
+}
+
+#[tokio::test]
+async fn ignores_display_name_unless_enabled() {
+    let (synapse, app) = setup(ProvisionConfig {
+        admin_token: ADMIN_TOKEN.to_string(),
+        auto_join: vec!["!room:localhost".to_string()],
+        ..Default::default()
+    })
+    .await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    form.push(("displayName", "Alice"));
+
+    app.register(&form).await;
+
+    let recorded = synapse.recorded();
+    assert_eq!(recorded.len(), 1);
+    assert_eq!(recorded[0].path, "/_synapse/admin/v1/join/!room:localhost");
+}
+
+#[tokio::test]
+async fn skips_provisioning_when_registration_fails() {
+    let (synapse, app) = setup(ProvisionConfig {
+        admin_token: ADMIN_TOKEN.to_string(),
+        auto_join: vec!["!room:localhost".to_string()],
+        ..Default::default()
+    })
+    .await;
+    synapse.add_user("alice");
+
+    app.register(&form("alice", "hunter2", TOKEN)).await;
+
+    assert!(synapse.recorded().is_empty());
+}
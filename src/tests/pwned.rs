@@ -0,0 +1,118 @@
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Path, State},
+    routing::get,
+    Router,
+};
+use sha1::{Digest, Sha1};
+
+use super::{config, form, spawn, MockSynapse, TestApp, SHARED_SECRET, TOKEN};
+use crate::pwned::PwnedPasswords;
+
+const PWNED: &str = "hunter2";
+
+fn sha1(password: &str) -> String {
+    hex::encode_upper(Sha1::digest(password.as_bytes()))
+}
+
+/// Range API stand-in that knows one breached password and pads its answers
+/// with a zero-count entry for `hunter3`.
+async fn range(
+    State(prefixes): State<Arc<Mutex<Vec<String>>>>,
+    Path(prefix): Path<String>,
+) -> String {
+    prefixes.lock().unwrap().push(prefix.clone());
+    let mut body = String::from("0018A45C4D1DEF81644B54AB7F969B88D65:1\r\n");
+    for (password, count) in [(PWNED, 17), ("hunter3", 0)] {
+        let hash = sha1(password);
+        if hash.starts_with(&prefix) {
+            body.push_str(&format!("{}:{count}\r\n", &hash[5..]));
+        }
+    }
+    body
+}
+
+async fn setup(pwned: PwnedPasswords) -> (MockSynapse, TestApp) {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.pwned = Some(pwned);
+    (synapse, TestApp::start(config).await)
+}
+
+async fn range_api() -> (Arc<Mutex<Vec<String>>>, PwnedPasswords) {
+    let prefixes = Arc::default();
+    let router = Router::new()
+        .route("/range/:prefix", get(range))
+        .with_state(Arc::clone(&prefixes));
+    let addr = spawn(router).await;
+    let pwned = PwnedPasswords::Api {
+        base_url: format!("http://{addr}/"),
+    };
+    (prefixes, pwned)
+}
+
+#[tokio::test]
+async fn api_rejects_breached_password() {
+    let (prefixes, pwned) = range_api().await;
+    let (synapse, app) = setup(pwned).await;
+
+    let (_, body) = app.register(&form("alice", PWNED, TOKEN)).await;
+
+    assert_eq!(body["registrationState"], "PASSWORD_COMPROMISED");
+    assert!(synapse.users().is_empty());
+    assert_eq!(*prefixes.lock().unwrap(), [&sha1(PWNED)[..5]]);
+}
+
+#[tokio::test]
+async fn api_ignores_padding() {
+    let (_, pwned) = range_api().await;
+    let (synapse, app) = setup(pwned).await;
+
+    let (_, body) = app.register(&form("alice", "hunter3", TOKEN)).await;
+
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert!(synapse.users().contains("alice"));
+}
+
+#[tokio::test]
+async fn api_outage_lets_password_through() {
+    let (_, app) = setup(PwnedPasswords::Api {
+        base_url: "http://127.0.0.1:1".to_string(),
+    })
+    .await;
+
+    let (_, body) = app.register(&form("alice", PWNED, TOKEN)).await;
+
+    assert_eq!(body["registrationState"], "REGISTERED");
+}
+
+#[tokio::test]
+async fn hash_file_finds_every_entry() {
+    let mut hashes: Vec<String> = (0..500).map(|i| sha1(&format!("breached{i}"))).collect();
+    hashes.sort();
+    let path = std::env::temp_dir().join(format!("pwned-{}.txt", std::process::id()));
+    let lines: String = hashes
+        .iter()
+        .enumerate()
+        .map(|(i, hash)| format!("{hash}:{}\r\n", i + 1))
+        .collect();
+    std::fs::write(&path, lines).unwrap();
+    let pwned = PwnedPasswords::HashFile(path.clone());
+    let client = reqwest::Client::new();
+
+    for i in 0..500 {
+        assert!(pwned
+            .is_pwned(&client, &format!("breached{i}"))
+            .await
+            .unwrap());
+    }
+    for i in 0..50 {
+        assert!(!pwned.is_pwned(&client, &format!("safe{i}")).await.unwrap());
+    }
+
+    let (_, app) = setup(pwned).await;
+    let (_, body) = app.register(&form("alice", "breached7", TOKEN)).await;
+    assert_eq!(body["registrationState"], "PASSWORD_COMPROMISED");
+    std::fs::remove_file(path).unwrap();
+}
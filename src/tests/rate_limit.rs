@@ -0,0 +1,91 @@
+use chrono::{Duration, Utc};
+
+use super::{config, form, MockSynapse, TestApp, SHARED_SECRET, TOKEN};
+use crate::ratelimit::{Attempt, RateLimitPolicy};
+
+#[test]
+fn hits_outside_the_window_stop_counting() {
+    let policy = RateLimitPolicy {
+        max_attempts: 2,
+        window: Duration::hours(1),
+        count_successful: true,
+    };
+    let start = Utc::now();
+    let mut attempt = Attempt::default();
+    attempt.record(&policy, start);
+    attempt.record(&policy, start + Duration::minutes(30));
+
+    let now = start + Duration::minutes(45);
+    assert_eq!(attempt.count(&policy, now), 2);
+    // Lifts when the first hit is an hour old.
+    assert_eq!(
+        attempt.blocked_for(&policy, now),
+        Some(Duration::minutes(15))
+    );
+
+    let now = start + Duration::minutes(61);
+    assert_eq!(attempt.count(&policy, now), 1);
+    assert_eq!(attempt.blocked_for(&policy, now), None);
+    assert!(attempt.prune(&policy, now));
+    assert_eq!(attempt.hits().len(), 1);
+    assert!(!attempt.prune(&policy, now));
+}
+
+#[test]
+fn only_the_last_max_attempts_are_kept() {
+    let policy = RateLimitPolicy::default();
+    let now = Utc::now();
+    let mut attempt = Attempt::default();
+    for i in 0..5 {
+        attempt.record(&policy, now + Duration::seconds(i));
+    }
+    assert_eq!(attempt.hits().len(), 3);
+    assert_eq!(attempt.hits()[0], now + Duration::seconds(2));
+}
+
+#[tokio::test]
+async fn allowlisted_ip_is_never_blocked() {
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.allowlist = vec!["127.0.0.0/8".parse().unwrap()];
+    let app = TestApp::start(config).await;
+
+    for _ in 0..5 {
+        let (_, body) = app.register(&form("alice", "hunter22", "wrong")).await;
+        assert_eq!(body["registrationState"], "INVALID_TOKEN");
+    }
+    let (_, body) = app.register(&form("alice", "hunter22", TOKEN)).await;
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert!(app.state.attempts.is_empty());
+}
+
+#[tokio::test]
+async fn successful_registrations_count_by_default() {
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.rate_limit.max_attempts = 1;
+    let app = TestApp::start(config).await;
+
+    let (_, body) = app.register(&form("alice", "hunter22", TOKEN)).await;
+    assert_eq!(body["registrationState"], "REGISTERED");
+    let (_, body) = app.register(&form("bob", "hunter22", TOKEN)).await;
+    assert_eq!(body["registrationState"], "BLOCKED");
+}
+
+#[tokio::test]
+async fn successful_registrations_can_be_left_uncounted() {
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.rate_limit.max_attempts = 1;
+    config.rate_limit.count_successful = false;
+    let app = TestApp::start(config).await;
+
+    for username in ["alice", "bob", "carol"] {
+        let (_, body) = app.register(&form(username, "hunter22", TOKEN)).await;
+        assert_eq!(body["registrationState"], "REGISTERED");
+    }
+    // Failures still count.
+    app.register(&form("dave", "hunter22", "wrong")).await;
+    let (_, body) = app.register(&form("dave", "hunter22", TOKEN)).await;
+    assert_eq!(body["registrationState"], "BLOCKED");
+}
@@ -0,0 +1,301 @@
+use chrono::{Duration, Utc};
+use regex::Regex;
+use reqwest::StatusCode;
+
+use super::{config, form, MockSynapse, TestApp, SHARED_SECRET, TOKEN};
+use crate::backend::calculate_mac;
+use crate::password::PasswordPolicy;
+use crate::tokens::InviteToken;
+
+async fn setup() -> (MockSynapse, TestApp) {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let app = TestApp::start(config(&server)).await;
+    (synapse, app)
+}
+
+fn state(body: &serde_json::Value) -> &str {
+    body["registrationState"].as_str().unwrap()
+}
+
+#[test]
+fn mac_matches_synapse_reference() {
+    assert_eq!(
+        calculate_mac("abcdef", "alice", "hunter2", "shared"),
+        "f9e26799312349317df0cb966e53482e5987de15"
+    );
+}
+
+#[tokio::test]
+async fn registers_user() {
+    let (synapse, app) = setup().await;
+
+    let (status, body) = app.register(&form("alice", "hunter2", TOKEN)).await;
+
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(state(&body), "REGISTERED");
+    assert_eq!(body["username"], "alice");
+    assert!(synapse.users().contains("alice"));
+}
+
+#[tokio::test]
+async fn rejects_empty_fields() {
+    let (_synapse, app) = setup().await;
+
+    let (_, body) = app.register(&form("", "hunter2", TOKEN)).await;
+    assert_eq!(state(&body), "INVALID_USERNAME");
+    let (_, body) = app.register(&form("alice", "", TOKEN)).await;
+    assert_eq!(state(&body), "INVALID_PASSWORD");
+    let (_, body) = app.register(&form("alice", "hunter2", "")).await;
+    assert_eq!(state(&body), "INVALID_TOKEN");
+}
+
+#[tokio::test]
+async fn rejects_mismatched_confirmation() {
+    let (_synapse, app) = setup().await;
+    let mut form = form("alice", "hunter2", TOKEN);
+    form[2].1 = "hunter3";
+
+    let (_, body) = app.register(&form).await;
+
+    assert_eq!(state(&body), "INVALID_PASSWORD_VERIFICATION");
+}
+
+#[tokio::test]
+async fn rejects_invalid_username_or_password() {
+    let (synapse, app) = setup().await;
+
+    let (_, body) = app.register(&form("al ice", "hunter2", TOKEN)).await;
+    assert_eq!(state(&body), "INVALID_USERNAME");
+    let (_, body) = app.register(&form("alice", "pw", TOKEN)).await;
+    assert_eq!(state(&body), "PASSWORD_TOO_SHORT");
+    let (_, body) = app.register(&form("alice", "hunt er2", TOKEN)).await;
+    assert_eq!(state(&body), "PASSWORD_HAS_WHITESPACE");
+    assert!(synapse.users().is_empty());
+}
+
+#[tokio::test]
+async fn username_in_password_only_rejected_when_configured() {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let app = TestApp::start(config(&server)).await;
+    let (_, body) = app.register(&form("alice", "xAlice1", TOKEN)).await;
+    assert_eq!(state(&body), "REGISTERED");
+
+    let mut config = config(&server);
+    config.password.reject_username = true;
+    let app = TestApp::start(config).await;
+    let (_, body) = app.register(&form("bob", "xBob1", TOKEN)).await;
+    assert_eq!(state(&body), "PASSWORD_CONTAINS_USERNAME");
+    assert!(!synapse.users().contains("bob"));
+}
+
+#[tokio::test]
+async fn normalizes_localpart() {
+    let (synapse, app) = setup().await;
+
+    let (_, body) = app.register(&form("Alice.Smith_2", "hunter2", TOKEN)).await;
+
+    assert_eq!(state(&body), "REGISTERED");
+    assert_eq!(body["username"], "alice.smith_2");
+    assert!(synapse.users().contains("alice.smith_2"));
+}
+
+#[tokio::test]
+async fn enforces_localpart_rules() {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.username.reserved_patterns = vec![Regex::new("^matrix-").unwrap()];
+    let app = TestApp::start(config).await;
+
+    for (username, expected) in [
+        ("alice!", "INVALID_USERNAME"),
+        ("_bridge", "INVALID_USERNAME"),
+        ("12345", "INVALID_USERNAME"),
+        (&"a".repeat(250)[..], "USERNAME_TOO_LONG"),
+        ("Admin", "USERNAME_RESERVED"),
+        ("matrix-bot", "USERNAME_RESERVED"),
+    ] {
+        let (_, body) = app.register(&form(username, "hunter2", TOKEN)).await;
+        assert_eq!(state(&body), expected, "{username}");
+    }
+    assert!(synapse.users().is_empty());
+}
+
+#[tokio::test]
+async fn reports_which_password_rule_failed() {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.password = PasswordPolicy {
+        min_length: 8,
+        max_length: Some(64),
+        require_digit: true,
+        require_uppercase: true,
+        require_lowercase: true,
+        require_symbol: true,
+        min_score: Some(3),
+        reject_username: true,
+    };
+    let app = TestApp::start(config).await;
+
+    for (password, expected) in [
+        ("Sh0rt!", "PASSWORD_TOO_SHORT"),
+        (&"Aa1!".repeat(17)[..], "PASSWORD_TOO_LONG"),
+        ("NoDigits!!", "PASSWORD_MISSING_DIGIT"),
+        ("lower1case!", "PASSWORD_MISSING_UPPERCASE"),
+        ("UPPER1CASE!", "PASSWORD_MISSING_LOWERCASE"),
+        ("NoSymbol12", "PASSWORD_MISSING_SYMBOL"),
+        ("Password1!", "PASSWORD_TOO_WEAK"),
+        ("Correct-Horse-7-Battery", "REGISTERED"),
+    ] {
+        let (_, body) = app.register(&form("alice", password, TOKEN)).await;
+        assert_eq!(state(&body), expected, "{password}");
+    }
+    assert!(synapse.users().contains("alice"));
+}
+
+#[tokio::test]
+async fn rejects_unknown_token() {
+    let (synapse, app) = setup().await;
+
+    let (_, body) = app.register(&form("alice", "hunter2", "WrongToken")).await;
+
+    assert_eq!(state(&body), "INVALID_TOKEN");
+    assert!(synapse.users().is_empty());
+}
+
+#[tokio::test]
+async fn rejects_exhausted_token() {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.tokens = vec![(
+        "OneShot".to_string(),
+        InviteToken {
+            max_uses: Some(1),
+            uses: 0,
+            expires_at: None,
+        },
+    )];
+    let app = TestApp::start(config).await;
+
+    let (_, body) = app.register(&form("alice", "hunter2", "OneShot")).await;
+    assert_eq!(state(&body), "REGISTERED");
+    let (_, body) = app.register(&form("bob", "hunter2", "OneShot")).await;
+    assert_eq!(state(&body), "INVALID_TOKEN");
+    assert!(!synapse.users().contains("bob"));
+}
+
+#[tokio::test]
+async fn rejects_expired_token() {
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.tokens = vec![(
+        "Stale".to_string(),
+        InviteToken {
+            max_uses: None,
+            uses: 0,
+            expires_at: Some(Utc::now() - Duration::minutes(1)),
+        },
+    )];
+    let app = TestApp::start(config).await;
+
+    let (_, body) = app.register(&form("alice", "hunter2", "Stale")).await;
+
+    assert_eq!(state(&body), "TOKEN_EXPIRED");
+}
+
+#[tokio::test]
+async fn reports_existing_user() {
+    let (synapse, app) = setup().await;
+    synapse.add_user("alice");
+
+    let (status, body) = app.register(&form("alice", "hunter2", TOKEN)).await;
+
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(state(&body), "USER_EXISTS");
+}
+
+#[tokio::test]
+async fn blocks_after_too_many_attempts() {
+    let (_synapse, app) = setup().await;
+
+    for _ in 0..3 {
+        let (_, body) = app.register(&form("alice", "hunter2", "WrongToken")).await;
+        assert_eq!(state(&body), "INVALID_TOKEN");
+    }
+    let (_, body) = app.register(&form("alice", "hunter2", TOKEN)).await;
+
+    assert_eq!(state(&body), "BLOCKED");
+}
+
+#[tokio::test]
+async fn upstream_failure_is_internal_error_and_refunds_token() {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.tokens = vec![(
+        "OneShot".to_string(),
+        InviteToken {
+            max_uses: Some(1),
+            uses: 0,
+            expires_at: None,
+        },
+    )];
+    let app = TestApp::start(config).await;
+    synapse.fail_with(503);
+
+    let (status, body) = app.register(&form("alice", "hunter2", "OneShot")).await;
+
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(state(&body), "INTERNAL_ERROR");
+    assert_eq!(app.state.tokens.get("OneShot").unwrap().uses, 0);
+}
+
+#[tokio::test]
+async fn wrong_shared_secret_is_internal_error() {
+    let (synapse, server) = MockSynapse::start("some-other-secret").await;
+    let app = TestApp::start(config(&server)).await;
+
+    let (status, body) = app.register(&form("alice", "hunter2", TOKEN)).await;
+
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(state(&body), "INTERNAL_ERROR");
+    assert!(synapse.users().is_empty());
+}
+
+#[tokio::test]
+async fn unreachable_upstream_is_internal_error() {
+    // Nothing listens on the discard port on loopback.
+    let app = TestApp::start(config("http://127.0.0.1:9")).await;
+
+    let (status, body) = app.register(&form("alice", "hunter2", TOKEN)).await;
+
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+    assert_eq!(state(&body), "INTERNAL_ERROR");
+}
+
+#[tokio::test]
+async fn homeserver_errcodes_map_to_states() {
+    for (errcode, expected) in [
+        ("M_INVALID_USERNAME", "INVALID_USERNAME"),
+        ("M_EXCLUSIVE", "USERNAME_EXCLUSIVE"),
+        ("M_WEAK_PASSWORD", "PASSWORD_REJECTED"),
+        // A 400 that isn't about the user existing is no longer USER_EXISTS.
+        ("M_UNKNOWN", "INTERNAL_ERROR"),
+    ] {
+        let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+        let mut config = config(&server);
+        config.tokens = vec![(
+            "OneShot".to_string(),
+            InviteToken {
+                max_uses: Some(1),
+                uses: 0,
+                expires_at: None,
+            },
+        )];
+        let app = TestApp::start(config).await;
+        synapse.reject_with(400, errcode);
+
+        let (_, body) = app.register(&form("alice", "hunter2", "OneShot")).await;
+
+        assert_eq!(state(&body), expected, "{errcode}");
+        assert_eq!(app.state.tokens.get("OneShot").unwrap().uses, 0);
+    }
+}
@@ -0,0 +1,77 @@
+//! Just enough of an SMTP server to accept and keep whatever is sent to it.
+
+use std::sync::{Arc, Mutex};
+
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+/// A message as it arrived, headers and body undecoded.
+#[derive(Clone, Debug)]
+pub struct Received {
+    pub recipients: Vec<String>,
+    pub data: String,
+}
+
+#[derive(Clone, Default)]
+pub struct SmtpSink {
+    received: Arc<Mutex<Vec<Received>>>,
+}
+
+impl SmtpSink {
+    /// Listens on an ephemeral loopback port and returns it.
+    pub async fn start() -> (Self, u16) {
+        let sink = Self::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let received = sink.received.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(session(stream, received.clone()));
+            }
+        });
+        (sink, port)
+    }
+
+    pub fn received(&self) -> Vec<Received> {
+        self.received.lock().unwrap().clone()
+    }
+}
+
+async fn session(stream: TcpStream, received: Arc<Mutex<Vec<Received>>>) {
+    let (reader, mut writer) = stream.into_split();
+    let mut lines = BufReader::new(reader).lines();
+    let mut recipients = Vec::new();
+    writer.write_all(b"220 localhost ESMTP\r\n").await.unwrap();
+
+    while let Ok(Some(line)) = lines.next_line().await {
+        let command = line.to_ascii_uppercase();
+        let reply: &[u8] = if command.starts_with("EHLO") || command.starts_with("HELO") {
+            b"250 localhost\r\n"
+        } else if let Some(to) = command.strip_prefix("RCPT TO:") {
+            recipients.push(to.trim_matches(['<', '>', ' ']).to_ascii_lowercase());
+            b"250 OK\r\n"
+        } else if command == "DATA" {
+            writer.write_all(b"354 go ahead\r\n").await.unwrap();
+            let mut data = String::new();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if line == "." {
+                    break;
+                }
+                data.push_str(line.strip_prefix('.').unwrap_or(&line));
+                data.push('\n');
+            }
+            received.lock().unwrap().push(Received {
+                recipients: std::mem::take(&mut recipients),
+                data,
+            });
+            b"250 OK\r\n"
+        } else if command == "QUIT" {
+            writer.write_all(b"221 bye\r\n").await.unwrap();
+            return;
+        } else {
+            b"250 OK\r\n"
+        };
+        writer.write_all(reply).await.unwrap();
+    }
+}
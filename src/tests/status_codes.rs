@@ -0,0 +1,104 @@
+use reqwest::StatusCode;
+
+use super::{config, form, MockSynapse, TestApp, SHARED_SECRET, TOKEN};
+use crate::{Outcome, RegistrationState, StatusCodes};
+
+async fn setup() -> (MockSynapse, TestApp) {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.status_codes = StatusCodes::Strict;
+    (synapse, TestApp::start(config).await)
+}
+
+#[tokio::test]
+async fn maps_states_to_statuses() {
+    let (synapse, app) = setup().await;
+    synapse.add_user("bob");
+
+    let (status, body) = app.register(&form("alice", "hunter22", TOKEN)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["registrationState"], "REGISTERED");
+
+    let (status, body) = app.register(&form("al ice", "hunter22", TOKEN)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["registrationState"], "INVALID_USERNAME");
+
+    let (status, body) = app.register(&form("carol", "hi", TOKEN)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["registrationState"], "PASSWORD_TOO_SHORT");
+
+    let (status, body) = app.register(&form("bob", "hunter22", TOKEN)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert_eq!(body["registrationState"], "USER_EXISTS");
+
+    let (status, body) = app.register(&form("carol", "hunter22", "WrongToken")).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["registrationState"], "INVALID_TOKEN");
+}
+
+#[tokio::test]
+async fn upstream_failure_is_bad_gateway() {
+    let (synapse, app) = setup().await;
+    synapse.fail_with(503);
+
+    let (status, body) = app.register(&form("alice", "hunter22", TOKEN)).await;
+
+    assert_eq!(status, StatusCode::BAD_GATEWAY);
+    assert_eq!(body["registrationState"], "INTERNAL_ERROR");
+}
+
+#[test]
+fn local_failure_is_internal_server_error() {
+    let local = Outcome::new(RegistrationState::InternalError, "alice");
+    let upstream = Outcome::upstream_failure("alice");
+
+    assert_eq!(local.status(StatusCodes::Strict).as_u16(), 500);
+    assert_eq!(upstream.status(StatusCodes::Strict).as_u16(), 502);
+    assert_eq!(local.status(StatusCodes::Legacy).as_u16(), 500);
+    assert_eq!(upstream.status(StatusCodes::Legacy).as_u16(), 500);
+}
+
+#[tokio::test]
+async fn blocked_is_429_with_retry_after() {
+    let (_synapse, app) = setup().await;
+    for _ in 0..3 {
+        app.register(&form("alice", "hunter22", "WrongToken")).await;
+    }
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/registration", app.url))
+        .form(&form("alice", "hunter22", TOKEN))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    let retry_after: u64 = response.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let window = app.state.config.rate_limit.window.num_seconds() as u64;
+    assert!(retry_after > 0 && retry_after <= window, "{retry_after}");
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["registrationState"], "BLOCKED");
+}
+
+#[tokio::test]
+async fn legacy_mode_keeps_blocked_at_200() {
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let app = TestApp::start(config(&server)).await;
+    for _ in 0..3 {
+        app.register(&form("alice", "hunter22", "WrongToken")).await;
+    }
+
+    let response = reqwest::Client::new()
+        .post(format!("{}/registration", app.url))
+        .form(&form("alice", "hunter22", TOKEN))
+        .send()
+        .await
+        .unwrap();
+
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers().get("retry-after").is_none());
+}
@@ -0,0 +1,173 @@
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use chrono::{DateTime, Duration, Utc};
+use rusqlite::Connection;
+
+use super::{config, form, MockSynapse, TestApp, SHARED_SECRET, TOKEN};
+use crate::ratelimit::Attempt;
+use crate::storage::{Registration, SqliteStorage, Storage};
+use crate::tokens::InviteToken;
+
+/// A fresh database path that doesn't exist yet.
+fn db_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("storage-{name}-{}.db", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// SQLite keeps whole seconds.
+fn seconds_ago(secs: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp(Utc::now().timestamp() - secs, 0).unwrap()
+}
+
+#[test]
+fn data_survives_reopening() {
+    let path = db_path("roundtrip");
+    let ip: IpAddr = "203.0.113.7".parse().unwrap();
+    let hits = vec![seconds_ago(60), seconds_ago(30)];
+    let invite = InviteToken {
+        max_uses: Some(5),
+        uses: 2,
+        expires_at: Some(seconds_ago(-3600)),
+    };
+    {
+        let storage = SqliteStorage::open(&path).unwrap();
+        storage
+            .save_attempt(ip, &Attempt::from_hits(hits.clone()))
+            .unwrap();
+        storage.save_token("party", &invite).unwrap();
+        storage
+            .save_token("gone", &InviteToken::unlimited())
+            .unwrap();
+        storage.revoke_token("gone").unwrap();
+        storage
+            .record_registration(&Registration {
+                username: "alice".to_string(),
+                registered_at: seconds_ago(10),
+                token: "party".to_string(),
+                client_ip: ip,
+            })
+            .unwrap();
+    }
+
+    let storage = SqliteStorage::open(&path).unwrap();
+    let attempts = storage.load_attempts().unwrap();
+    assert_eq!(attempts.len(), 1);
+    assert_eq!(attempts[0].0, ip);
+    assert_eq!(attempts[0].1.hits(), &hits[..]);
+
+    let tokens = storage.load_tokens().unwrap();
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(tokens[0].0, "party");
+    assert_eq!(tokens[0].1.max_uses, Some(5));
+    assert_eq!(tokens[0].1.uses, 2);
+    assert_eq!(tokens[0].1.expires_at, invite.expires_at);
+    assert_eq!(storage.load_revoked_tokens().unwrap(), ["gone"]);
+    storage
+        .save_token("gone", &InviteToken::unlimited())
+        .unwrap();
+    assert!(storage.load_revoked_tokens().unwrap().is_empty());
+    assert_eq!(storage.load_tokens().unwrap().len(), 2);
+
+    let conn = Connection::open(&path).unwrap();
+    let ledger: (String, String, String) = conn
+        .query_row(
+            "SELECT username, token, client_ip FROM registrations",
+            [],
+            |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+        )
+        .unwrap();
+    assert_eq!(
+        ledger,
+        ("alice".to_string(), "party".to_string(), ip.to_string())
+    );
+
+    storage.clear_attempts().unwrap();
+    assert!(storage.load_attempts().unwrap().is_empty());
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn migrates_count_and_last_to_hits() {
+    let path = db_path("migration");
+    let last = seconds_ago(120);
+    {
+        // The schema from before the sliding window, at user_version 0.
+        let conn = Connection::open(&path).unwrap();
+        conn.execute_batch(
+            "CREATE TABLE attempts (
+                 ip TEXT PRIMARY KEY,
+                 count INTEGER NOT NULL,
+                 last INTEGER NOT NULL
+             );",
+        )
+        .unwrap();
+        conn.execute(
+            "INSERT INTO attempts (ip, count, last) VALUES ('198.51.100.1', 2, ?1)",
+            [last.timestamp()],
+        )
+        .unwrap();
+    }
+
+    let storage = SqliteStorage::open(&path).unwrap();
+    let attempts = storage.load_attempts().unwrap();
+    assert_eq!(attempts.len(), 1);
+    assert_eq!(attempts[0].0, "198.51.100.1".parse::<IpAddr>().unwrap());
+    assert_eq!(attempts[0].1.hits(), &[last, last]);
+
+    // Saving writes the new column, and opening again doesn't migrate twice.
+    let attempt = Attempt::from_hits(vec![last, last + Duration::seconds(1)]);
+    storage
+        .save_attempt("198.51.100.1".parse().unwrap(), &attempt)
+        .unwrap();
+    drop(storage);
+    let storage = SqliteStorage::open(&path).unwrap();
+    assert_eq!(storage.load_attempts().unwrap()[0].1.hits(), attempt.hits());
+    let version: u32 = Connection::open(&path)
+        .unwrap()
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .unwrap();
+    assert_eq!(version, 2);
+    let _ = std::fs::remove_file(&path);
+}
+
+#[tokio::test]
+async fn config_tokens_keep_their_uses_and_take_new_limits() {
+    let path = db_path("config-tokens");
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.tokens = vec![(
+        TOKEN.to_string(),
+        InviteToken {
+            max_uses: Some(2),
+            ..InviteToken::unlimited()
+        },
+    )];
+    let app = TestApp::start_with_storage(
+        config.clone(),
+        Arc::new(SqliteStorage::open(&path).unwrap()),
+    )
+    .await;
+    let (_, body) = app.register(&form("alice", "hunter22", TOKEN)).await;
+    assert_eq!(body["registrationState"], "REGISTERED");
+    drop(app);
+
+    let expires_at = seconds_ago(-3600);
+    config.tokens[0].1 = InviteToken {
+        max_uses: Some(5),
+        uses: 0,
+        expires_at: Some(expires_at),
+    };
+    let app =
+        TestApp::start_with_storage(config, Arc::new(SqliteStorage::open(&path).unwrap())).await;
+
+    let invite = app.state.tokens.get(TOKEN).unwrap();
+    assert_eq!(invite.uses, 1);
+    assert_eq!(invite.max_uses, Some(5));
+    assert_eq!(invite.expires_at, Some(expires_at));
+    let stored = SqliteStorage::open(&path).unwrap().load_tokens().unwrap();
+    assert_eq!(stored[0].1.max_uses, Some(5));
+    let _ = std::fs::remove_file(&path);
+}
@@ -0,0 +1,131 @@
+use chrono::{Duration, Utc};
+
+use super::mock_synapse::{RegistrationToken, ADMIN_TOKEN};
+use reqwest::{Method, StatusCode};
+
+use super::{admin, config, form, MockSynapse, TestApp, ADMIN_SECRET, SHARED_SECRET};
+use crate::TokenBackend;
+
+async fn setup() -> (MockSynapse, TestApp) {
+    let (synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.tokens = Vec::new();
+    config.token_backend = TokenBackend::Synapse {
+        admin_token: ADMIN_TOKEN.to_string(),
+    };
+    config.admin_secret = Some(ADMIN_SECRET.to_string());
+    (synapse, TestApp::start(config).await)
+}
+
+fn token(name: &str, uses_allowed: Option<u32>) -> RegistrationToken {
+    RegistrationToken {
+        token: name.to_string(),
+        uses_allowed,
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn consumes_synapse_token() {
+    let (synapse, app) = setup().await;
+    synapse.add_registration_token(token("Limited", Some(2)));
+
+    let (_, body) = app.register(&form("alice", "hunter2", "Limited")).await;
+
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert_eq!(
+        synapse.registration_token("Limited").unwrap().uses_allowed,
+        Some(1)
+    );
+}
+
+#[tokio::test]
+async fn unlimited_synapse_token_is_left_alone() {
+    let (synapse, app) = setup().await;
+    synapse.add_registration_token(token("Open", None));
+
+    let (_, body) = app.register(&form("alice", "hunter2", "Open")).await;
+
+    assert_eq!(body["registrationState"], "REGISTERED");
+    assert_eq!(
+        synapse.registration_token("Open").unwrap().uses_allowed,
+        None
+    );
+}
+
+#[tokio::test]
+async fn rejects_unknown_and_exhausted_synapse_tokens() {
+    let (synapse, app) = setup().await;
+    synapse.add_registration_token(RegistrationToken {
+        completed: 1,
+        ..token("Spent", Some(1))
+    });
+
+    let (_, body) = app.register(&form("alice", "hunter2", "Spent")).await;
+    assert_eq!(body["registrationState"], "INVALID_TOKEN");
+    let (_, body) = app.register(&form("alice", "hunter2", "Missing")).await;
+    assert_eq!(body["registrationState"], "INVALID_TOKEN");
+    assert!(synapse.users().is_empty());
+}
+
+#[tokio::test]
+async fn rejects_expired_synapse_token() {
+    let (synapse, app) = setup().await;
+    synapse.add_registration_token(RegistrationToken {
+        expiry_time: Some((Utc::now() - Duration::minutes(1)).timestamp_millis()),
+        ..token("Stale", None)
+    });
+
+    let (_, body) = app.register(&form("alice", "hunter2", "Stale")).await;
+
+    assert_eq!(body["registrationState"], "TOKEN_EXPIRED");
+}
+
+#[tokio::test]
+async fn refunds_synapse_token_when_registration_fails() {
+    let (synapse, app) = setup().await;
+    synapse.add_registration_token(token("Limited", Some(1)));
+    synapse.add_user("alice");
+
+    let (_, body) = app.register(&form("alice", "hunter2", "Limited")).await;
+
+    assert_eq!(body["registrationState"], "USER_EXISTS");
+    assert_eq!(
+        synapse.registration_token("Limited").unwrap().uses_allowed,
+        Some(1)
+    );
+}
+
+#[tokio::test]
+async fn token_locks_are_dropped_after_use() {
+    let (synapse, app) = setup().await;
+    synapse.add_registration_token(token("Limited", Some(5)));
+
+    app.register(&form("alice", "hunter2", "Limited")).await;
+    for guess in ["Guess1", "Guess2", "Guess3"] {
+        app.register(&form("bob", "hunter2", guess)).await;
+    }
+    synapse.add_user("carol");
+    app.register(&form("carol", "hunter2", "Limited")).await;
+
+    let tokens = app.state.synapse_tokens.as_ref().unwrap();
+    assert_eq!(tokens.locked_tokens(), 0);
+}
+
+#[tokio::test]
+async fn local_token_admin_routes_are_not_mounted() {
+    let (_synapse, app) = setup().await;
+
+    let (status, _) = admin(&app.url, Method::GET, "/admin/tokens", None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = admin(
+        &app.url,
+        Method::POST,
+        "/admin/tokens",
+        Some(serde_json::json!({ "token": "party" })),
+    )
+    .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = admin(&app.url, Method::GET, "/admin/attempts", None).await;
+    assert_eq!(status, StatusCode::OK);
+}
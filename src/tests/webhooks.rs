@@ -0,0 +1,151 @@
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use axum::{
+    extract::State,
+    http::{HeaderMap, StatusCode},
+    routing::post,
+    Router,
+};
+use hmac::{Hmac, Mac};
+use serde_json::Value;
+use sha2::Sha256;
+
+use super::{config, eventually, form, spawn, MockSynapse, TestApp, SHARED_SECRET, TOKEN};
+use crate::webhooks::{WebhookConfig, SIGNATURE_HEADER};
+use crate::RegistrationState;
+
+const SECRET: &str = "webhook-secret";
+
+/// Signature header and body of each accepted delivery.
+type Deliveries = Arc<Mutex<Vec<(String, Vec<u8>)>>>;
+
+/// Deliveries a receiver accepted, and how many more it should refuse first.
+#[derive(Clone, Default)]
+struct Receiver {
+    received: Deliveries,
+    attempts: Arc<Mutex<u32>>,
+    refuse: Arc<Mutex<u32>>,
+}
+
+async fn receive(
+    State(receiver): State<Receiver>,
+    headers: HeaderMap,
+    body: axum::body::Bytes,
+) -> StatusCode {
+    *receiver.attempts.lock().unwrap() += 1;
+    let mut refuse = receiver.refuse.lock().unwrap();
+    if *refuse > 0 {
+        *refuse -= 1;
+        return StatusCode::SERVICE_UNAVAILABLE;
+    }
+    let signature = headers[SIGNATURE_HEADER].to_str().unwrap().to_string();
+    receiver
+        .received
+        .lock()
+        .unwrap()
+        .push((signature, body.to_vec()));
+    StatusCode::NO_CONTENT
+}
+
+async fn start_receiver(refuse: u32) -> (Receiver, String) {
+    let receiver = Receiver::default();
+    *receiver.refuse.lock().unwrap() = refuse;
+    let router = Router::new()
+        .route("/hook", post(receive))
+        .with_state(receiver.clone());
+    let addr = spawn(router).await;
+    (receiver, format!("http://{addr}/hook"))
+}
+
+fn webhook_config(url: String, dead_letter: Option<PathBuf>) -> WebhookConfig {
+    WebhookConfig {
+        urls: vec![url],
+        secret: SECRET.to_string(),
+        events: vec![
+            RegistrationState::Registered,
+            RegistrationState::Blocked,
+            RegistrationState::InvalidToken,
+        ],
+        max_attempts: 3,
+        retry_delay: Duration::from_millis(10),
+        dead_letter,
+    }
+}
+
+#[tokio::test]
+async fn posts_signed_payloads_for_chosen_outcomes() {
+    let (receiver, url) = start_receiver(0).await;
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.webhooks = Some(webhook_config(url, None));
+    let app = TestApp::start(config).await;
+
+    app.register(&form("alice", "hunter22", TOKEN)).await;
+    // Not one of the configured events.
+    app.register(&form("bob", "hi", TOKEN)).await;
+    app.register(&form("carol", "hunter22", "WrongToken")).await;
+
+    eventually(|| receiver.received.lock().unwrap().len() == 2).await;
+    let mut payloads: Vec<Value> = Vec::new();
+    for (signature, body) in receiver.received.lock().unwrap().iter() {
+        let mut mac = Hmac::<Sha256>::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(body);
+        assert_eq!(
+            signature,
+            &format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+        );
+        payloads.push(serde_json::from_slice(body).unwrap());
+    }
+    payloads.sort_by_key(|payload| payload["username"].as_str().unwrap().to_string());
+
+    assert_eq!(payloads[0]["outcome"], "REGISTERED");
+    assert_eq!(payloads[0]["username"], "alice");
+    assert_eq!(payloads[0]["userId"], "@alice:localhost");
+    assert_eq!(payloads[0]["tokenId"], app.state.token_id(TOKEN));
+    assert!(payloads[0]["id"].is_string());
+    assert_eq!(payloads[1]["outcome"], "INVALID_TOKEN");
+    assert!(payloads[1].get("userId").is_none());
+    for (_, body) in receiver.received.lock().unwrap().iter() {
+        let raw = String::from_utf8_lossy(body);
+        assert!(!raw.contains(TOKEN) && !raw.contains("hunter22"));
+    }
+}
+
+#[tokio::test]
+async fn retries_until_delivered() {
+    let (receiver, url) = start_receiver(2).await;
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.webhooks = Some(webhook_config(url, None));
+    let app = TestApp::start(config).await;
+
+    app.register(&form("alice", "hunter22", TOKEN)).await;
+
+    eventually(|| receiver.received.lock().unwrap().len() == 1).await;
+    assert_eq!(*receiver.attempts.lock().unwrap(), 3);
+}
+
+#[tokio::test]
+async fn writes_dead_letter_after_last_attempt() {
+    let dead_letter =
+        std::env::temp_dir().join(format!("webhook-dead-{}.jsonl", std::process::id()));
+    let _ = std::fs::remove_file(&dead_letter);
+    let (receiver, url) = start_receiver(u32::MAX).await;
+    let (_synapse, server) = MockSynapse::start(SHARED_SECRET).await;
+    let mut config = config(&server);
+    config.webhooks = Some(webhook_config(url.clone(), Some(dead_letter.clone())));
+    let app = TestApp::start(config).await;
+
+    app.register(&form("alice", "hunter22", TOKEN)).await;
+
+    eventually(|| dead_letter.exists()).await;
+    assert_eq!(*receiver.attempts.lock().unwrap(), 3);
+    let letter: Value =
+        serde_json::from_str(std::fs::read_to_string(&dead_letter).unwrap().trim()).unwrap();
+    assert_eq!(letter["url"], url);
+    assert_eq!(letter["attempts"], 3);
+    assert_eq!(letter["payload"]["username"], "alice");
+    std::fs::remove_file(dead_letter).unwrap();
+}
@@ -0,0 +1,162 @@
+use chrono::{DateTime, Utc};
+use dashmap::DashMap;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use thiserror::Error;
+
+/// A single invite token with its own usage budget and expiry.
+#[derive(Clone, Debug)]
+pub struct InviteToken {
+    /// `None` means the token can be used an unlimited number of times.
+    pub max_uses: Option<u32>,
+    pub uses: u32,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl InviteToken {
+    pub fn unlimited() -> Self {
+        Self {
+            max_uses: None,
+            uses: 0,
+            expires_at: None,
+        }
+    }
+
+    fn check(&self, now: DateTime<Utc>) -> Result<(), TokenError> {
+        if self.expires_at.is_some_and(|expires_at| expires_at <= now) {
+            return Err(TokenError::Expired);
+        }
+        if self.max_uses.is_some_and(|max_uses| self.uses >= max_uses) {
+            return Err(TokenError::Exhausted);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Error, PartialEq, Eq)]
+pub enum TokenError {
+    #[error("unknown token")]
+    Unknown,
+    #[error("token has no uses left")]
+    Exhausted,
+    #[error("token has expired")]
+    Expired,
+    #[error("token lookup failed: {0}")]
+    Upstream(String),
+}
+
+#[derive(Default)]
+pub struct TokenStore {
+    tokens: DashMap<String, InviteToken>,
+}
+
+impl TokenStore {
+    pub fn new(tokens: impl IntoIterator<Item = (String, InviteToken)>) -> Self {
+        Self {
+            tokens: tokens.into_iter().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.tokens.is_empty()
+    }
+
+    pub fn list(&self) -> Vec<(String, InviteToken)> {
+        self.tokens
+            .iter()
+            .map(|entry| (entry.key().clone(), entry.value().clone()))
+            .collect()
+    }
+
+    pub fn get(&self, token: &str) -> Option<InviteToken> {
+        self.tokens.get(token).map(|entry| entry.clone())
+    }
+
+    /// Adds a new token; returns `false` without touching anything if it
+    /// already exists.
+    pub fn insert(&self, token: String, invite: InviteToken) -> bool {
+        match self.tokens.entry(token) {
+            dashmap::mapref::entry::Entry::Occupied(_) => false,
+            dashmap::mapref::entry::Entry::Vacant(slot) => {
+                slot.insert(invite);
+                true
+            }
+        }
+    }
+
+    /// Applies `update` to an existing token and returns the new value.
+    pub fn update(
+        &self,
+        token: &str,
+        update: impl FnOnce(&mut InviteToken),
+    ) -> Option<InviteToken> {
+        let mut entry = self.tokens.get_mut(token)?;
+        update(&mut entry);
+        Some(entry.clone())
+    }
+
+    pub fn remove(&self, token: &str) -> Option<InviteToken> {
+        self.tokens.remove(token).map(|(_, invite)| invite)
+    }
+
+    /// Checks the token and takes one use from it while holding the entry lock,
+    /// so two concurrent registrations can't both spend the last use. Returns
+    /// the updated token so the caller can persist it.
+    pub fn consume(&self, token: &str) -> Result<InviteToken, TokenError> {
+        let mut entry = self.tokens.get_mut(token).ok_or(TokenError::Unknown)?;
+        entry.check(Utc::now())?;
+        entry.uses += 1;
+        Ok(entry.clone())
+    }
+
+    /// Whether `token` could be spent right now, without spending it.
+    pub fn check(&self, token: &str) -> Result<(), TokenError> {
+        self.tokens
+            .get(token)
+            .ok_or(TokenError::Unknown)?
+            .check(Utc::now())
+    }
+
+    /// Gives back a use taken by [`TokenStore::consume`] when the registration
+    /// itself failed.
+    pub fn refund(&self, token: &str) -> Option<InviteToken> {
+        let mut entry = self.tokens.get_mut(token)?;
+        entry.uses = entry.uses.saturating_sub(1);
+        Some(entry.clone())
+    }
+}
+
+/// A short, stable name for `token` that can be shown where the token itself
+/// must not be: the first 8 hex characters of its HMAC-SHA256 under `key`, so
+/// guessed tokens can't be checked against it without the key.
+pub fn token_id(key: &str, token: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length");
+    mac.update(token.as_bytes());
+    hex::encode(&mac.finalize().into_bytes()[..4])
+}
+
+/// Parses a `MATRIX_TOKENS` entry of the form `token[:max_uses[:expires_at]]`,
+/// where `expires_at` is an RFC 3339 timestamp and an empty `max_uses` means
+/// unlimited.
+pub fn parse_token_spec(spec: &str) -> Option<(String, InviteToken)> {
+    let mut parts = spec.trim().splitn(3, ':');
+    let token = parts.next().filter(|t| !t.is_empty())?.to_string();
+    let max_uses = match parts.next() {
+        None | Some("") => None,
+        Some(raw) => Some(raw.parse().ok()?),
+    };
+    let expires_at = match parts.next() {
+        None | Some("") => None,
+        Some(raw) => Some(DateTime::parse_from_rfc3339(raw).ok()?.with_timezone(&Utc)),
+    };
+
+    Some((
+        token,
+        InviteToken {
+            max_uses,
+            uses: 0,
+            expires_at,
+        },
+    ))
+}
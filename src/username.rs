@@ -0,0 +1,85 @@
+use once_cell::sync::Lazy;
+use regex::Regex;
+use thiserror::Error;
+
+/// The localpart grammar from the Matrix spec's user identifier rules.
+static LOCALPART_RE: Lazy<Regex> = Lazy::new(|| Regex::new(r"^[a-z0-9._=/-]+$").unwrap());
+
+/// Names nobody should be able to register by default.
+pub const DEFAULT_RESERVED: &[&str] = &[
+    "abuse",
+    "admin",
+    "administrator",
+    "hostmaster",
+    "mod",
+    "moderator",
+    "noreply",
+    "postmaster",
+    "root",
+    "security",
+    "server",
+    "support",
+    "synapse",
+    "system",
+    "webmaster",
+];
+
+#[derive(Clone, Debug)]
+pub struct UsernamePolicy {
+    /// Longest localpart that keeps `@localpart:server_name` within the
+    /// spec's 255 byte limit.
+    pub max_length: usize,
+    /// Exact localparts that are refused, lowercase.
+    pub reserved: Vec<String>,
+    /// Refused when any of them matches the normalized localpart.
+    pub reserved_patterns: Vec<Regex>,
+}
+
+#[derive(Clone, Copy, Debug, Error, PartialEq)]
+pub enum UsernameError {
+    #[error("username contains characters not allowed in a localpart")]
+    Invalid,
+    #[error("username is too long")]
+    TooLong,
+    #[error("username is reserved")]
+    Reserved,
+}
+
+impl UsernamePolicy {
+    pub fn new(server_name: &str) -> Self {
+        Self {
+            max_length: 255usize.saturating_sub(server_name.len() + 2),
+            reserved: DEFAULT_RESERVED
+                .iter()
+                .map(|name| name.to_string())
+                .collect(),
+            reserved_patterns: Vec::new(),
+        }
+    }
+
+    /// Lowercases `username` the way the homeserver would and checks the
+    /// result, which is what gets registered.
+    pub fn normalize(&self, username: &str) -> Result<String, UsernameError> {
+        let localpart = username.trim().to_lowercase();
+        // Synapse keeps a leading underscore for application services and
+        // all-digit localparts for guests.
+        if !LOCALPART_RE.is_match(&localpart)
+            || localpart.starts_with('_')
+            || localpart.bytes().all(|b| b.is_ascii_digit())
+        {
+            return Err(UsernameError::Invalid);
+        }
+        if localpart.len() > self.max_length {
+            return Err(UsernameError::TooLong);
+        }
+        if self.reserved.contains(&localpart)
+            || self
+                .reserved_patterns
+                .iter()
+                .any(|pattern| pattern.is_match(&localpart))
+        {
+            return Err(UsernameError::Reserved);
+        }
+        Ok(localpart)
+    }
+}
@@ -0,0 +1,199 @@
+//! HMAC-signed JSON callbacks fired after registration outcomes.
+
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng};
+use reqwest::Client;
+use serde::Serialize;
+use sha2::Sha256;
+use tracing::{error, warn};
+
+use crate::audit::AuditEvent;
+use crate::RegistrationState;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Header carrying `sha256=<hex HMAC-SHA256 of the body>`.
+pub const SIGNATURE_HEADER: &str = "X-Registration-Signature-256";
+
+/// Longest wait between two tries of one delivery.
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Clone, Debug)]
+pub struct WebhookConfig {
+    pub urls: Vec<String>,
+    pub secret: String,
+    /// Outcomes that fire the hooks.
+    pub events: Vec<RegistrationState>,
+    /// Tries per delivery, the first included.
+    pub max_attempts: u32,
+    /// Wait before the first retry; it doubles after every failure.
+    pub retry_delay: Duration,
+    /// Where deliveries that ran out of tries are appended, one JSON object
+    /// per line.
+    pub dead_letter: Option<PathBuf>,
+}
+
+/// What is posted to every hook.
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct Payload {
+    /// Stays the same across retries, so receivers can drop duplicates.
+    id: String,
+    /// Set for `REGISTERED`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    user_id: Option<String>,
+    #[serde(flatten)]
+    event: AuditEvent,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+struct DeadLetter<'a> {
+    url: &'a str,
+    failed_at: DateTime<Utc>,
+    attempts: u32,
+    error: String,
+    payload: serde_json::Value,
+}
+
+pub struct Webhooks {
+    config: WebhookConfig,
+    client: Client,
+}
+
+impl Webhooks {
+    pub fn new(config: WebhookConfig, client: Client) -> Self {
+        Self { config, client }
+    }
+
+    /// Sends `event` to every hook in the background if its outcome is one
+    /// they want.
+    pub fn dispatch(&self, event: AuditEvent, user_id: Option<String>) {
+        if !self.config.events.contains(&event.outcome) {
+            return;
+        }
+        let payload = Payload {
+            id: rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(24)
+                .map(char::from)
+                .collect(),
+            user_id,
+            event,
+        };
+        let body = match serde_json::to_vec(&payload) {
+            Ok(body) => body,
+            Err(err) => {
+                error!("failed to encode webhook payload: {err}");
+                return;
+            }
+        };
+        let signature = self.sign(&body);
+        for url in &self.config.urls {
+            let delivery = Delivery {
+                url: url.clone(),
+                body: body.clone(),
+                signature: signature.clone(),
+                config: self.config.clone(),
+                client: self.client.clone(),
+            };
+            tokio::spawn(delivery.run());
+        }
+    }
+
+    fn sign(&self, body: &[u8]) -> String {
+        let mut mac =
+            HmacSha256::new_from_slice(self.config.secret.as_bytes()).expect("hmac can take key");
+        mac.update(body);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+}
+
+struct Delivery {
+    url: String,
+    body: Vec<u8>,
+    signature: String,
+    config: WebhookConfig,
+    client: Client,
+}
+
+impl Delivery {
+    async fn run(self) {
+        let mut delay = self.config.retry_delay;
+        let mut attempt = 1;
+        loop {
+            let error = match self.send().await {
+                Ok(()) => return,
+                Err(err) => err,
+            };
+            if attempt >= self.config.max_attempts {
+                error!(
+                    "webhook to {} failed {attempt} times, giving up: {error}",
+                    self.url
+                );
+                self.dead_letter(attempt, error);
+                return;
+            }
+            warn!(
+                "webhook to {} failed (attempt {attempt}), retrying in {delay:?}: {error}",
+                self.url
+            );
+            tokio::time::sleep(delay).await;
+            delay = (delay * 2).min(MAX_BACKOFF);
+            attempt += 1;
+        }
+    }
+
+    async fn send(&self) -> Result<(), String> {
+        let response = self
+            .client
+            .post(&self.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, &self.signature)
+            .timeout(Duration::from_secs(10))
+            .body(self.body.clone())
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        if response.status().is_success() {
+            Ok(())
+        } else {
+            Err(format!("status {}", response.status()))
+        }
+    }
+
+    fn dead_letter(&self, attempts: u32, error: String) {
+        let Some(path) = &self.config.dead_letter else {
+            return;
+        };
+        let letter = DeadLetter {
+            url: &self.url,
+            failed_at: Utc::now(),
+            attempts,
+            error,
+            payload: serde_json::from_slice(&self.body).unwrap_or_default(),
+        };
+        let result = serde_json::to_vec(&letter)
+            .map_err(std::io::Error::from)
+            .and_then(|mut line| {
+                line.push(b'\n');
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?
+                    .write_all(&line)
+            });
+        if let Err(err) = result {
+            error!(
+                "failed to write webhook dead letter to {}: {err}",
+                path.display()
+            );
+        }
+    }
+}
@@ -4,6 +4,7 @@ What this script does:
   - confirm password validator needs javascript, otherwise always valid as long as not empty
   - set token with ?token query parameter
   - set custom validity messages
+  - render the captcha widget, or solve the proof-of-work puzzle, if one is configured
 */
 
 // see https://stackoverflow.com/a/3028037
@@ -32,11 +33,31 @@ var passwordConfirmation = document.getElementById("passwordConfirmation");
 var token = document.getElementById("token");
 username.addEventListener("input", function (event) {
     if (username.validity.patternMismatch) {
-        username.setCustomValidity("only allowed is a-z and 0-9 for user chars");
+        username.setCustomValidity("only a-z, 0-9 and . _ = - / are allowed for user chars");
     } else {
         username.setCustomValidity("");
     }
 });
+// ask the server whether the username is still free once the user leaves the field
+username.addEventListener("change", function (event) {
+    username.setCustomValidity("");
+    if (!username.value) {
+        return;
+    }
+    fetch("username_available?username=" + encodeURIComponent(username.value))
+        .then(response => response.json())
+        .then(function (result) {
+            if (result.reason === "USER_EXISTS") {
+                username.setCustomValidity("this username is already taken");
+            } else if (result.reason === "USERNAME_RESERVED") {
+                username.setCustomValidity("this username is reserved");
+            }
+            username.reportValidity();
+        })
+        .catch(function () {
+        });
+});
+
 token.addEventListener("input", function (event) {
     if (token.validity.typeMismatch) {
         token.setCustomValidity("case-sensitive, e.g: SardineImpactReport");
@@ -63,6 +84,55 @@ function validatePassword() {
 password.onchange = validatePassword;
 passwordConfirmation.onkeyup = validatePassword;
 
+// captcha widgets; their answer is sent as the "captcha" field
+var captcha = document.getElementById("captcha");
+const CAPTCHA_WIDGETS = {
+    hcaptcha: {script: "https://js.hcaptcha.com/1/api.js", class: "h-captcha", field: "h-captcha-response", api: "hcaptcha"},
+    turnstile: {script: "https://challenges.cloudflare.com/turnstile/v0/api.js", class: "cf-turnstile", field: "cf-turnstile-response", api: "turnstile"},
+    recaptcha: {script: "https://www.google.com/recaptcha/api.js", class: "g-recaptcha", field: "g-recaptcha-response", api: "grecaptcha"},
+};
+const captchaWidget = CAPTCHA_WIDGETS[captcha.dataset.provider];
+if (captchaWidget) {
+    captcha.classList.add(captchaWidget.class);
+    let script = document.createElement("script");
+    script.src = captchaWidget.script;
+    script.async = true;
+    document.head.appendChild(script);
+}
+
+// a widget answer only verifies once, so every submission needs a fresh one
+function resetCaptcha() {
+    if (captchaWidget && window[captchaWidget.api]) {
+        window[captchaWidget.api].reset();
+    }
+}
+
+function leadingZeroBits(bytes) {
+    let bits = 0;
+    for (const byte of bytes) {
+        if (byte === 0) {
+            bits += 8;
+            continue;
+        }
+        bits += Math.clz32(byte) - 24;
+        break;
+    }
+    return bits;
+}
+
+// fetch a puzzle from the server and find an answer whose sha-256 has enough leading zero bits
+async function solveProofOfWork() {
+    let puzzle = await (await fetch("captcha/challenge")).json();
+    let encoder = new TextEncoder();
+    for (let solution = 0; ; solution++) {
+        let answer = puzzle.challenge + ":" + solution;
+        let hash = new Uint8Array(await crypto.subtle.digest("SHA-256", encoder.encode(answer)));
+        if (leadingZeroBits(hash) >= puzzle.difficulty) {
+            return answer;
+        }
+    }
+}
+
 function showError(message, dialog) {
     document.getElementById("error_message").innerHTML = message;
     document.getElementById("error_dialog").innerHTML = dialog;
@@ -71,6 +141,22 @@ function showError(message, dialog) {
     hideOnClickOutside(error);
 }
 
+// which password rule was broken, by registrationState
+const PASSWORD_ERRORS = {
+    INVALID_PASSWORD: "Please enter a password.",
+    PASSWORD_TOO_SHORT: "The password must be at least {{ pw_length }} characters long.",
+    PASSWORD_TOO_LONG: "The password is too long.",
+    PASSWORD_HAS_WHITESPACE: "The password must not contain whitespace.",
+    PASSWORD_MISSING_DIGIT: "The password must contain a digit.",
+    PASSWORD_MISSING_UPPERCASE: "The password must contain an uppercase letter.",
+    PASSWORD_MISSING_LOWERCASE: "The password must contain a lowercase letter.",
+    PASSWORD_MISSING_SYMBOL: "The password must contain a symbol.",
+    PASSWORD_CONTAINS_USERNAME: "The password must not contain the username.",
+    PASSWORD_TOO_WEAK: "The password is too easy to guess.",
+    PASSWORD_COMPROMISED: "This password appeared in a data breach, please choose another one.",
+    PASSWORD_REJECTED: "The server's password rules refused this password, please choose another one.",
+};
+
 // hijack the submit button to display the json response in a neat modal
 var form = document.getElementById("submitForm");
 /*
@@ -118,7 +204,7 @@ function sendData() {
     XHR.send(FD);
 }
 */
-function sendData() {
+function sendData(captchaAnswer) {
     let XHR = new XMLHttpRequest();
 
     // 从 form 构造 FormData
@@ -129,8 +215,18 @@ function sendData() {
     for (const [key, value] of FD.entries()) {
         params.append(key, value);
     }
+    for (const widget of Object.values(CAPTCHA_WIDGETS)) {
+        if (params.has(widget.field)) {
+            captchaAnswer = captchaAnswer || params.get(widget.field);
+            params.delete(widget.field);
+        }
+    }
+    if (captchaAnswer) {
+        params.set("captcha", captchaAnswer);
+    }
 
     XHR.addEventListener("load", function (event) {
+        resetCaptcha();
         console.log(XHR.responseText);
         let response;
         try {
@@ -149,12 +245,33 @@ function sendData() {
             showError("Blocked!", "非法注册多次已被屏蔽");
         } else if ("INVALID_TOKEN" === response.registrationState) {
             showError("Wrong Token!", "The entered token is wrong.");
-        } else if ("INVALID_USER_OR_PASS" === response.registrationState) {
-            showError("Invalid username or password!", "You entered a username or password which contains not allowed characters! Usernames can exist of a-z and 0-9, passwords must be at least 3 chars long and not contain a whitespace!");
+        } else if ("TOKEN_EXPIRED" === response.registrationState) {
+            showError("Token expired!", "The entered token has expired.");
+        } else if ("INVALID_USERNAME" === response.registrationState) {
+            showError("Invalid username!", "Usernames can exist of a-z, 0-9 and . _ = - /, must not start with _ and must not be only digits.");
+        } else if ("USERNAME_TOO_LONG" === response.registrationState) {
+            showError("Invalid username!", "The username is too long.");
+        } else if ("USERNAME_RESERVED" === response.registrationState) {
+            showError("Username reserved!", "This username is reserved, please choose another one.");
+        } else if ("USERNAME_EXCLUSIVE" === response.registrationState) {
+            showError("Username reserved!", "This username belongs to a bridge or bot on this server, please choose another one.");
+        } else if (response.registrationState in PASSWORD_ERRORS) {
+            showError("Invalid password!", PASSWORD_ERRORS[response.registrationState]);
+        } else if ("INVALID_CAPTCHA" === response.registrationState) {
+            showError("Captcha failed!", "Please solve the captcha and try again.");
+        } else if ("INVALID_EMAIL" === response.registrationState) {
+            showError("Invalid email!", "Please enter a valid email address.");
         } else if ("REGISTERED" === response.registrationState) {
             document.getElementById("welcome").innerHTML = "Welcome " + response.username;
             document.getElementById("success").classList.remove("hidden");
-        } else if (response.status === 422) {
+        } else if ("VERIFICATION_SENT" === response.registrationState) {
+            document.getElementById("welcome").innerHTML = "Check your email, " + response.username;
+            document.getElementById("success").classList.remove("hidden");
+        } else if ("PENDING_APPROVAL" === response.registrationState) {
+            document.getElementById("welcome").innerHTML = "Waiting for an admin to approve " + response.username;
+            document.getElementById("success").classList.remove("hidden");
+            pollApproval(response.statusUrl);
+        } else if ("USER_EXISTS" === response.registrationState) {
             showError("User already exists!", "The entered user is already registered.");
         } else {
             showError("Invalid response!", "Please contact the server admin about this.");
@@ -162,6 +279,7 @@ function sendData() {
     });
 
     XHR.addEventListener("error", function (event) {
+        resetCaptcha();
         showError("Internal Server Error!", "Please contact the server admin about this.");
     });
 
@@ -174,10 +292,37 @@ function sendData() {
     XHR.send(params.toString());
 }
 
+// check every 30 seconds whether a queued registration was decided
+function pollApproval(statusUrl) {
+    setTimeout(function () {
+        fetch(statusUrl).then(response => response.json()).then(function (response) {
+            if ("PENDING_APPROVAL" === response.registrationState) {
+                pollApproval(statusUrl);
+            } else if ("REGISTERED" === response.registrationState) {
+                document.getElementById("welcome").innerHTML = "Welcome " + response.username;
+            } else if ("REJECTED" === response.registrationState) {
+                document.getElementById("success").classList.add("hidden");
+                showError("Registration declined!", "An admin declined your registration.");
+            } else {
+                document.getElementById("success").classList.add("hidden");
+                showError("Registration failed!", response.error ? response.error.message : "Please contact the server admin about this.");
+            }
+        }, function () {
+            pollApproval(statusUrl);
+        });
+    }, 30000);
+}
+
 // take over its submit event.
 form.addEventListener("submit", function (event) {
     event.preventDefault();
-    sendData();
+    if (captcha.dataset.provider === "pow") {
+        solveProofOfWork().then(sendData, function () {
+            showError("Internal Server Error!", "Please contact the server admin about this.");
+        });
+    } else {
+        sendData();
+    }
 });
 
 function cleanForMatrix(strInput) {